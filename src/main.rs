@@ -10,89 +10,462 @@
 //! some assumptions.
 //!
 //! Input of CSV data is handled in the [transaction_engine_util::csv_input] module.
+//! Transactions can also be read as JSON Lines, which is handled in the
+//! [transaction_engine_util::jsonl_input] module, or from a binary transaction log
+//! as described in the [transaction_engine_util::binary_log] module. The `convert`
+//! subcommand converts CSV or JSON Lines input into a binary transaction log.
 //!
 //! The transaction processing itself happens in the [transaction_engine] package.
 //!
 //! CSV output happens mainly here in the command-line utility, using a
 //! helper struct from [transaction_engine_util::csv_output] for formatting
-//! the output according to spec.
+//! the output according to spec. Account data can also be written as JSON Lines,
+//! using [transaction_engine_util::jsonl_output].
 //!
-//! CSV input and output errors, including errors during parsing of CSV input,
-//! are considered fatal and will result in termination of the program.
+//! CSV input and output errors are considered fatal and will result in termination
+//! of the program. By default, this includes errors during parsing of CSV input.
+//! With the `--on-parse-error` option, rows that cannot be parsed can instead be
+//! skipped, or written to a dead-letter file using [transaction_engine_util::dead_letter_output].
+//!
+//! Transactions are processed by a [transaction_engine::sharded::ShardedProcessor],
+//! on as many threads as are specified with the `--threads` option.
 //!
 //! When processing transactions, syntactically valid transactions
 //! that specify invalid operations are reported as errors but are
 //! not considered fatal and processing of the remaining transactions
 //! will continue. These types of errors are reported to `stderr`
 //! by the command-line utility.
+//!
+//! With the `--outcomes` option, the outcome of each transaction is additionally
+//! written to a CSV file, using [transaction_engine_util::outcome_output].
 
-use clap::Parser;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter};
+use std::path::Path;
+use std::str::FromStr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use transaction_engine_util::csv_input::{CSVInputParser, Transaction};
-use transaction_engine::{TransactionProcessor, Accounts};
+use clap::{ArgEnum, Parser, Subcommand};
+
+use transaction_engine_util::csv_input::{CSVInputParserBuilder, decompress};
+use transaction_engine_util::jsonl_input::JsonlInputParser;
+use transaction_engine_util::binary_log::{BinaryLogReader, BinaryLogWriter};
+use transaction_engine::{TransactionProcessor, ProcessorPolicy, Transaction, AccountOrder, Currency, DisputeWindow, ParseOptions, Precision, RoundingMode};
+use transaction_engine::sharded::ShardedProcessor;
+use transaction_engine::snapshot::ProcessorSnapshot;
+use transaction_engine::store::Storage;
+use transaction_engine::journal::{self, SyncPolicy};
 use transaction_engine_util::csv_output::AccountOutputCSVRecord;
+use transaction_engine_util::jsonl_output::JsonlWriter;
+use transaction_engine_util::outcome_output::OutcomeWriter;
+use transaction_engine_util::dead_letter_output::{DeadLetterWriter, RejectedInput};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-  csv_input_file: String,
+  #[clap(subcommand)]
+  command: Option<Command>,
+  /// Path to the file to read transactions from, or "-" to read from stdin.
+  #[clap(required = true)]
+  input_file: Option<String>,
+  /// Format of the input. By default, the format is detected from the extension of
+  /// the input file: ".jsonl" and ".ndjson" files are read as JSON Lines, ".txlog"
+  /// files as binary transaction logs, and anything else, including stdin, as CSV.
+  #[clap(long, arg_enum)]
+  input_format: Option<InputFormat>,
+  /// Format of the account data that is written to stdout.
+  #[clap(long, arg_enum, default_value = "csv")]
+  output_format: OutputFormat,
+  /// Order to write the account data in: "client", "total" or "available", in ascending order
+  /// with ties ordered by client and currency. By default, the order is unspecified and can differ between runs.
+  #[clap(long)]
+  sort: Option<AccountOrder>,
+  /// Number of places past the decimal point that amounts are precise to, from 0 to 18.
+  /// Digits past this are chopped off when amounts are read, and amounts are written with
+  /// this number of decimal places. State files and journals must have the same precision.
+  #[clap(long, default_value = "4")]
+  precision: Precision,
+  /// How input amounts with more decimal places than the precision are handled: "truncate"
+  /// chops off the excess digits, "half-up" and "half-even" round to the nearest amount, with
+  /// ties away from zero or to even respectively, and "reject" treats the row as unparseable.
+  #[clap(long, default_value = "truncate")]
+  rounding: RoundingMode,
+  /// A currency other than the default currency that is accepted in the optional currency
+  /// column of the input, as CODE=PRECISION, for example "BTC=8". Can be given more than once.
+  /// When given, the account data has a currency column with one row per client and currency.
+  #[clap(long = "currency", multiple_occurrences = true)]
+  currencies: Vec<CurrencyOption>,
+  /// How long deposits, withdrawals and transfers can be disputed for, after which they are
+  /// no longer kept in memory: a number followed by "tx" for the number of newer transactions
+  /// of the same client, for example "1000tx", or by "s", "m", "h" or "d" for the time since
+  /// the transaction, for example "90d", going by the optional timestamp column of the input.
+  /// When given, the number of transactions that are still retained is reported to stderr.
+  #[clap(long)]
+  dispute_window: Option<DisputeWindow>,
+  /// Number of threads to process transactions on. Transactions are spread across
+  /// the threads by client id, while the input is read on the main thread.
+  #[clap(long, default_value = "1")]
+  threads: NonZeroUsize,
+  /// Directory to keep retained transactions, open disputes and the IDs of all transactions
+  /// in, as temporary files, rather than in memory. This bounds memory use for inputs with
+  /// billions of transactions, at the cost of speed. The directory must exist.
+  /// This does not bound memory use while a state file is read with `--state-in`
+  /// or written with `--state-out`, which holds all of the state in memory.
+  #[clap(long)]
+  store_dir: Option<String>,
+  /// Path to a state file written by a previous run with `--state-out`.
+  /// Processing continues from the state in the file.
+  #[clap(long)]
+  state_in: Option<String>,
+  /// Path to write the complete state of the transaction processor to after processing,
+  /// so that a later run can continue from it with `--state-in`. The state is held in memory
+  /// in its entirety while the file is written, even with `--store-dir`.
+  #[clap(long)]
+  state_out: Option<String>,
+  /// Path to a write-ahead journal that accepted transactions are written to before
+  /// they are applied. If the journal already exists, for example after a crash,
+  /// the state is first recovered from the state given with `--state-in` (if any)
+  /// and the transactions in the journal. Requires `--threads 1`.
+  #[clap(long)]
+  journal: Option<String>,
+  /// How often the journal is synced to disk: "always", "never",
+  /// or after every given number of transactions.
+  #[clap(long, default_value = "always")]
+  journal_sync: SyncPolicy,
+  /// Path to write the outcome of each transaction to, as CSV. Each row has the client,
+  /// the transaction ID, the type of transaction, whether the transaction was applied
+  /// or rejected, the error code for rejected transactions, the resulting balances,
+  /// and the reason of locks and unlocks.
+  #[clap(long)]
+  outcomes: Option<String>,
+  /// What to do with rows of input that cannot be parsed: "fail" stops processing,
+  /// "skip" reports the row to stderr and continues, and "quarantine" additionally
+  /// writes the row to the file given with `--dead-letter` and continues.
+  #[clap(long, arg_enum, default_value = "fail")]
+  on_parse_error: OnParseError,
+  /// Path to write rows of input that cannot be parsed to, with `--on-parse-error=quarantine`.
+  #[clap(long, required_if_eq("on-parse-error", "quarantine"))]
+  dead_letter: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+  /// Converts transactions into a binary transaction log, so that they
+  /// can be processed repeatedly without parsing the text each time.
+  Convert {
+    /// Path to the file to read transactions from, or "-" to read from stdin.
+    input_file: String,
+    /// Path to write the binary transaction log to. By convention, the extension is ".txlog".
+    output_file: String,
+    /// Format of the input. By default, the format is detected from the extension of the input file.
+    #[clap(long, arg_enum)]
+    input_format: Option<InputFormat>,
+    /// Number of places past the decimal point that amounts are precise to, from 0 to 18.
+    /// Binary transaction logs are always read with the precision they were written with.
+    #[clap(long, default_value = "4")]
+    precision: Precision,
+    /// How input amounts with more decimal places than the precision are handled.
+    #[clap(long, default_value = "truncate")]
+    rounding: RoundingMode,
+    /// A currency other than the default currency that is accepted, as CODE=PRECISION.
+    #[clap(long = "currency", multiple_occurrences = true)]
+    currencies: Vec<CurrencyOption>,
+  },
+}
+
+/// A currency and the precision of its amounts, as given with `--currency`.
+#[derive(Copy, Clone)]
+struct CurrencyOption {
+  currency: Currency,
+  precision: Precision,
+}
+
+/// Parses `CODE=PRECISION`, where the code is not empty.
+impl FromStr for CurrencyOption {
+  type Err = String;
+  fn from_str (s: &str) -> Result<Self, Self::Err>
+  {
+    let (code, precision) = s.split_once('=').ok_or_else(|| format!("Invalid currency {:?}; expected CODE=PRECISION", s))?;
+    let currency: Currency = code.parse().map_err(|e| format!("{}", e))?;
+    if currency == Currency::DEFAULT {
+      return Err(format!("Invalid currency {:?}; the currency code cannot be empty", s));
+    }
+    let precision = precision.parse().map_err(|e| format!("{}", e))?;
+    Ok(Self { currency, precision })
+  }
+}
+
+/// Collects the currencies given with `--currency` into a map from currency to precision.
+fn currency_map (currencies: &[CurrencyOption]) -> HashMap<Currency, Precision>
+{
+  currencies.iter().map(|c| (c.currency, c.precision)).collect()
+}
+
+/// Format of input data.
+#[derive(ArgEnum, Copy, Clone, Eq, PartialEq)]
+enum InputFormat {
+  Csv,
+  Jsonl,
+  Binary,
+}
+
+impl InputFormat {
+  /// Detects the format of a file from the extension of its path,
+  /// ignoring the extension for compression, if any.
+  fn from_path (path: &str) -> Self
+  {
+    let path = Path::new(path);
+    let path = match path.extension().and_then(|ext| ext.to_str()) {
+      Some("gz" | "zst") => Path::new(path.file_stem().unwrap_or_default()),
+      _ => path,
+    };
+    match path.extension().and_then(|ext| ext.to_str()) {
+      Some("jsonl" | "ndjson") => Self::Jsonl,
+      Some("txlog") => Self::Binary,
+      _ => Self::Csv,
+    }
+  }
+}
+
+/// Format of output data.
+#[derive(ArgEnum, Copy, Clone, Eq, PartialEq)]
+enum OutputFormat {
+  Csv,
+  Jsonl,
+}
+
+/// How to handle rows of input that cannot be parsed.
+#[derive(ArgEnum, Copy, Clone, Eq, PartialEq)]
+enum OnParseError {
+  Fail,
+  Skip,
+  Quarantine,
 }
 
 fn main () -> anyhow::Result<()>
 {
   let args = Args::parse();
-  let csv_parser: CSVInputParser<_> = args.csv_input_file.try_into()?;
-  let mut transaction_processor = TransactionProcessor::new();
-  for tx_result in csv_parser {
-    // XXX: We consider failures in CSV parsing to be fatal.
-    let (client_id, transaction_id, tx) = tx_result?;
-    // XXX: Transactions themselves are allowed to error as per spec.
-    //      Errors in transactions themselves are logged to stderr
-    //      and processing continues.
-    match tx {
-      Transaction::Deposit(amount) => {
-        let tx_result = transaction_processor.deposit(client_id, transaction_id, amount);
-        if let Err(e) = tx_result {
-          eprintln!("Error during processing of deposit tx {} for client {}: {:?}", transaction_id, client_id, e);
-        }
-      },
-      Transaction::Withdrawal(amount) => {
-        let tx_result = transaction_processor.withdraw(client_id, transaction_id, amount);
-        if let Err(e) = tx_result {
-          eprintln!("Error during processing of withdrawal tx {} for client {}: {:?}", transaction_id, client_id, e);
-        }
-      },
-      Transaction::Dispute => {
-        let tx_result = transaction_processor.dispute(client_id, transaction_id);
-        if let Err(e) = tx_result {
-          eprintln!("Error during processing of dispute for tx {} for client {}: {:?}", transaction_id, client_id, e);
-        }
-      },
-      Transaction::Resolve => {
-        let tx_result = transaction_processor.resolve(client_id, transaction_id);
-        if let Err(e) = tx_result {
-          eprintln!("Error during processing of resolve for tx {} for client {}: {:?}", transaction_id, client_id, e);
-        }
-      },
-      Transaction::Chargeback => {
-        let tx_result = transaction_processor.chargeback(client_id, transaction_id);
-        if let Err(e) = tx_result {
-          eprintln!("Error during processing of chargeback for tx {} for client {}: {:?}", transaction_id, client_id, e);
+  if let Some(Command::Convert { input_file, output_file, input_format, precision, rounding, currencies }) = &args.command {
+    let parse_options = ParseOptions { precision: *precision, rounding: *rounding, ..Default::default() };
+    return convert(input_file, output_file, *input_format, parse_options, currency_map(currencies));
+  }
+  // XXX: The unwrap is fine because the input file is required when no subcommand is given.
+  let input_file = args.input_file.as_deref().unwrap();
+  let input = open_input(input_file)?;
+  let input_format = args.input_format.unwrap_or_else(|| InputFormat::from_path(input_file));
+  let currencies = currency_map(&args.currencies);
+  let storage = match &args.store_dir {
+    Some(dir) if !Path::new(dir).is_dir() => anyhow::bail!("Store directory {} does not exist", dir),
+    Some(dir) => Storage::Disk(dir.into()),
+    None => Storage::Memory,
+  };
+  let policy = ProcessorPolicy { precision: args.precision, currencies: currencies.clone(), dispute_window: args.dispute_window, storage, ..Default::default() };
+  let parse_options = ParseOptions { precision: args.precision, rounding: args.rounding, ..Default::default() };
+  // XXX: Transactions themselves are allowed to error as per spec.
+  //      Errors in transactions themselves are logged to stderr
+  //      and processing continues.
+  let snapshot = match &args.state_in {
+    Some(path) => Some(ProcessorSnapshot::read_from(BufReader::new(File::open(path)?))?),
+    None => None,
+  };
+  let initial_state = match &args.journal {
+    Some(path) => {
+      if args.threads.get() > 1 {
+        anyhow::bail!("Journaling requires --threads 1");
+      }
+      let (transaction_processor, report) = journal::recover(path, args.journal_sync, snapshot, policy)?;
+      if report.replayed > 0 || report.discarded_bytes > 0 {
+        eprintln!("Recovered {} transactions from journal, discarding {} bytes of incomplete data. Last transaction in journal: {:?}",
+          report.replayed, report.discarded_bytes, report.last_entry);
+      }
+      transaction_processor
+    },
+    None => match snapshot {
+      Some(snapshot) => TransactionProcessor::restore(snapshot, policy)?,
+      None => TransactionProcessor::with_policy(policy)?,
+    },
+  };
+  let outcomes = match &args.outcomes {
+    Some(path) => Some(OutcomeWriter::from_writer(BufWriter::new(File::create(path)?))),
+    None => None,
+  };
+  let outcomes = Arc::new(Mutex::new(outcomes));
+  let outcomes_for_workers = outcomes.clone();
+  let storage_failed = Arc::new(AtomicBool::new(false));
+  let storage_failed_for_workers = storage_failed.clone();
+  let mut transaction_processor = ShardedProcessor::from_processor(initial_state, args.threads, move |tx, res, account| {
+    if let Err(e) = res {
+      eprintln!("{} tx {} for client {}: {:?}", e, tx.tx, tx.client, e);
+      // XXX: Unlike the other errors, a storage error is not a rejection of the transaction
+      //      but a failure of the disk store, after which processing stops.
+      if e.code() == "storage_error" {
+        storage_failed_for_workers.store(true, Ordering::Relaxed);
+      }
+    }
+    // XXX: The unwrap is fine because the lock is only poisoned if another worker
+    //      panicked, in which case the panic is propagated anyways.
+    if let Some(outcomes) = outcomes_for_workers.lock().unwrap().as_mut() {
+      outcomes.write(tx, res, account);
+    }
+  })?;
+  let dead_letter_file = match (&args.dead_letter, args.on_parse_error) {
+    (Some(path), OnParseError::Quarantine) => Some(BufWriter::new(File::create(path)?)),
+    _ => None,
+  };
+  match input_format {
+    InputFormat::Csv => {
+      let csv_parser = CSVInputParserBuilder::new().parse_options(parse_options).currencies(currencies).from_reader(input)?;
+      let dead_letters = match dead_letter_file {
+        Some(file) => Some(DeadLetterWriter::from_writer(file, csv_parser.headers())?),
+        None => None,
+      };
+      process_input(csv_parser, &mut transaction_processor, args.on_parse_error, dead_letters, &storage_failed)?;
+    },
+    InputFormat::Jsonl => {
+      let jsonl_parser = JsonlInputParser::with_parse_options(input, parse_options).currencies(currencies);
+      let dead_letters = match dead_letter_file {
+        Some(file) => Some(DeadLetterWriter::from_writer(file, &csv::StringRecord::from(vec!["record"]))?),
+        None => None,
+      };
+      process_input(jsonl_parser, &mut transaction_processor, args.on_parse_error, dead_letters, &storage_failed)?;
+    },
+    InputFormat::Binary => {
+      let binary_reader = BinaryLogReader::from_reader(input)?;
+      let dead_letters = match dead_letter_file {
+        Some(file) => Some(DeadLetterWriter::from_writer(file, &csv::StringRecord::new())?),
+        None => None,
+      };
+      process_input(binary_reader, &mut transaction_processor, args.on_parse_error, dead_letters, &storage_failed)?;
+    },
+  }
+  let transaction_processor = transaction_processor.into_processor()?;
+  if storage_failed.load(Ordering::Relaxed) {
+    anyhow::bail!("Stopped processing after a storage error");
+  }
+  if let Some(outcomes) = outcomes.lock().unwrap().take() {
+    outcomes.finish()?;
+  }
+  if args.dispute_window.is_some() {
+    eprintln!("Retaining {} deposits, withdrawals and transfers for future disputes", transaction_processor.retained_transactions());
+  }
+  if let Some(path) = &args.state_out {
+    transaction_processor.snapshot()?.write_to(BufWriter::new(File::create(path)?))?;
+  }
+  let final_account_data: Vec<_> = match args.sort {
+    Some(order) => transaction_processor.sorted_balances(order),
+    None => transaction_processor.balances().collect(),
+  };
+  // XXX: Without other currencies, the output has no currency column, exactly as in the spec.
+  let with_currency = !args.currencies.is_empty();
+  let records = final_account_data.into_iter().map(|b| AccountOutputCSVRecord {
+    client: b.client.into(),
+    currency: with_currency.then_some(b.currency),
+    available: b.balance.get_available(),
+    held: b.balance.get_held(),
+    total: b.balance.get_total(),
+    locked: b.frozen,
+  });
+  match args.output_format {
+    OutputFormat::Csv => {
+      let mut wtr = csv::Writer::from_writer(std::io::stdout());
+      for record in records {
+        wtr.serialize(record)?;
+      }
+      wtr.flush()?;
+    },
+    OutputFormat::Jsonl => {
+      let mut wtr = JsonlWriter::from_writer(BufWriter::new(std::io::stdout()));
+      for record in records {
+        wtr.serialize(&record)?;
+      }
+      wtr.flush()?;
+    },
+  }
+  Ok(())
+}
+
+/// Sends the transactions from an input parser to the transaction processor,
+/// and handles rows that cannot be parsed according to `on_parse_error`.
+///
+/// Stops with an error once a transaction has failed with a storage error.
+fn process_input<I, E> (transactions: I, transaction_processor: &mut ShardedProcessor, on_parse_error: OnParseError, mut dead_letters: Option<DeadLetterWriter<BufWriter<File>>>, storage_failed: &AtomicBool) -> anyhow::Result<()>
+where
+  I: Iterator<Item = Result<Transaction, E>>,
+  E: RejectedInput + Send + Sync + 'static,
+{
+  for tx_result in transactions {
+    if storage_failed.load(Ordering::Relaxed) {
+      anyhow::bail!("Stopped processing after a storage error");
+    }
+    match tx_result {
+      Ok(transaction) => transaction_processor.process(transaction),
+      // XXX: By default, we consider failures in parsing of input to be fatal.
+      //      I/O errors are always fatal, as no further rows can be read after them.
+      Err(e) if on_parse_error == OnParseError::Fail || e.is_io_error() => return Err(parse_error(e)),
+      Err(e) => {
+        eprintln!("Skipping input row {:?}: {}: {:?}", e.fields(), e, e.reason());
+        if let Some(dead_letters) = dead_letters.as_mut() {
+          dead_letters.write(&e)?;
         }
       },
     }
   }
-  let final_account_data: Accounts = transaction_processor.into();
-  let mut wtr = csv::Writer::from_writer(std::io::stdout());
-  for (client_id, account) in final_account_data {
-    wtr.serialize(AccountOutputCSVRecord {
-      client: client_id.into(),
-      available: account.get_available().to_string(),
-      held: account.get_held().to_string(),
-      total: account.get_total().to_string(),
-      locked: account.is_frozen(),
-    })?;
+  if let Some(dead_letters) = dead_letters.as_mut() {
+    dead_letters.flush()?;
+  }
+  Ok(())
+}
+
+/// Converts transactions from the input file into a binary transaction log.
+fn convert (input_file: &str, output_file: &str, input_format: Option<InputFormat>, parse_options: ParseOptions, currencies: HashMap<Currency, Precision>) -> anyhow::Result<()>
+{
+  let input = open_input(input_file)?;
+  let mut wtr = BinaryLogWriter::from_writer(BufWriter::new(File::create(output_file)?))?;
+  match input_format.unwrap_or_else(|| InputFormat::from_path(input_file)) {
+    InputFormat::Csv => write_binary_log(CSVInputParserBuilder::new().parse_options(parse_options).currencies(currencies).from_reader(input)?, &mut wtr)?,
+    InputFormat::Jsonl => write_binary_log(JsonlInputParser::with_parse_options(input, parse_options).currencies(currencies), &mut wtr)?,
+    InputFormat::Binary => write_binary_log(BinaryLogReader::from_reader(input)?, &mut wtr)?,
   }
   wtr.flush()?;
   Ok(())
 }
+
+/// Writes the transactions from an input parser to a binary transaction log.
+/// Rows that cannot be parsed are considered fatal.
+fn write_binary_log<I, E> (transactions: I, wtr: &mut BinaryLogWriter<BufWriter<File>>) -> anyhow::Result<()>
+where
+  I: Iterator<Item = Result<Transaction, E>>,
+  E: RejectedInput + Send + Sync + 'static,
+{
+  for tx_result in transactions {
+    wtr.write(&tx_result.map_err(parse_error)?)?;
+  }
+  Ok(())
+}
+
+/// Opens the input file, or stdin if the path is "-". Compressed input is decompressed while reading.
+fn open_input (path: &str) -> std::io::Result<Box<dyn BufRead>>
+{
+  match path {
+    "-" => decompress(std::io::stdin().lock()),
+    path => decompress(File::open(path)?),
+  }
+}
+
+/// Error for a row of input that cannot be parsed, with the fields of the row, if any, as context.
+fn parse_error<E> (e: E) -> anyhow::Error
+where
+  E: RejectedInput + Send + Sync + 'static,
+{
+  let fields = e.fields();
+  let context = if fields.is_empty() {
+    "Failed to parse input".to_string()
+  } else {
+    format!("Failed to parse input row {:?}", fields)
+  };
+  anyhow::Error::new(e).context(context)
+}
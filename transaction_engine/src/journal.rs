@@ -0,0 +1,482 @@
+//! Write-ahead journal of accepted transactions, for recovery after a crash.
+//!
+//! All state of a [TransactionProcessor] is held in memory, and would be lost if the
+//! process were to die part way through processing its input. When a [Journal] is attached
+//! to a transaction processor, each transaction that the transaction processor accepts is
+//! written to the journal before the balances of any account are changed. The state of
+//! the transaction processor can then be rebuilt with [recover], by restoring the last
+//! [ProcessorSnapshot] and replaying the transactions in the journal that came after it.
+//!
+//! Entries in the journal are numbered with the sequence number of the transaction, which
+//! is the same as [TransactionProcessor::applied_transactions] after the transaction has been
+//! applied. Snapshots record the number of applied transactions as well, so that when
+//! recovering we know which entries of the journal are already reflected in the snapshot.
+//!
+//! Each entry has a checksum. An incomplete entry, or an entry with a checksum mismatch,
+//! at the end of the journal is the result of a write that was interrupted by the crash.
+//! Such an entry was never applied, and is discarded during recovery. A checksum mismatch
+//! anywhere else in the journal means that the journal is damaged, and recovery fails.
+//! Since entries for locks and unlocks are longer than other entries, a checksum mismatch
+//! within the length of the longest possible entry from the end of the journal is taken
+//! to be the result of an interrupted write as well, unless a complete entry follows it.
+//!
+//! How often the journal is synced to disk is decided by the [SyncPolicy] of the journal.
+//!
+//! Note that the journal contains exactly the transactions that were accepted. Transactions
+//! that were rejected are not recorded, and so the empty account that is created for a client
+//! whose only transaction was a rejected withdrawal is not recreated by recovery.
+//!
+//! A transfer between two clients is a single transaction, and is written to the journal as
+//! a single entry. Replaying the entry debits the sending client and credits the receiving
+//! client, so that recovery recreates both sides of the transfer, or neither.
+//!
+//! ## Examples
+//!
+//! ```
+//! use transaction_engine::{ClientId, TransactionId, Accounts};
+//! use transaction_engine::journal::{recover, SyncPolicy};
+//!
+//! let path = std::env::temp_dir().join(format!("transaction_engine_journal_doctest_{}", std::process::id()));
+//! # let _ = std::fs::remove_file(&path);
+//! let client_a = ClientId::from(1u16);
+//!
+//! // There is no journal yet, so recovery starts out with a new, empty journal.
+//! let (mut transaction_processor, report) = recover(&path, SyncPolicy::Always, None, Default::default()).unwrap();
+//! assert_eq!(report.replayed, 0);
+//! transaction_processor.deposit(client_a, TransactionId::from(1u32), "1.5".try_into().unwrap()).unwrap();
+//! transaction_processor.dispute(client_a, TransactionId::from(1u32)).unwrap();
+//!
+//! // The process dies before the results have been written anywhere.
+//! drop(transaction_processor);
+//!
+//! let (transaction_processor, report) = recover(&path, SyncPolicy::Always, None, Default::default()).unwrap();
+//! assert_eq!(report.replayed, 2);
+//! let accounts: Accounts = transaction_processor.into();
+//! assert_eq!(accounts[&client_a].get_held().to_string(), "1.5000");
+//! # std::fs::remove_file(&path).unwrap();
+//! ```
+//!
+//! Recovery recreates both sides of a transfer from its entry.
+//!
+//! ```
+//! use transaction_engine::{ClientId, TransactionId, Accounts};
+//! use transaction_engine::journal::{recover, SyncPolicy};
+//!
+//! let path = std::env::temp_dir().join(format!("transaction_engine_journal_transfer_doctest_{}", std::process::id()));
+//! # let _ = std::fs::remove_file(&path);
+//! let client_a = ClientId::from(1u16);
+//! let client_b = ClientId::from(2u16);
+//!
+//! let (mut transaction_processor, _) = recover(&path, SyncPolicy::Always, None, Default::default()).unwrap();
+//! transaction_processor.deposit(client_a, TransactionId::from(1u32), "1.5".try_into().unwrap()).unwrap();
+//! transaction_processor.transfer(client_a, TransactionId::from(2u32), client_b, "1".try_into().unwrap()).unwrap();
+//! assert_eq!(transaction_processor.applied_transactions(), 2);
+//! drop(transaction_processor);
+//!
+//! let (transaction_processor, report) = recover(&path, SyncPolicy::Always, None, Default::default()).unwrap();
+//! assert_eq!(report.replayed, 2);
+//! let accounts: Accounts = transaction_processor.into();
+//! assert_eq!(accounts[&client_a].get_available().to_string(), "0.5000");
+//! assert_eq!(accounts[&client_b].get_available().to_string(), "1.0000");
+//! # std::fs::remove_file(&path).unwrap();
+//! ```
+//!
+//! A damaged entry that is followed by other entries is not mistaken for an interrupted write.
+//!
+//! ```
+//! use transaction_engine::{ClientId, TransactionId};
+//! use transaction_engine::journal::{recover, JournalError, SyncPolicy};
+//!
+//! let path = std::env::temp_dir().join(format!("transaction_engine_journal_damaged_doctest_{}", std::process::id()));
+//! # let _ = std::fs::remove_file(&path);
+//! let (mut transaction_processor, _) = recover(&path, SyncPolicy::Always, None, Default::default()).unwrap();
+//! for tx in 1..=3u32 {
+//!   transaction_processor.deposit(ClientId::from(1u16), TransactionId::from(tx), "1.5".try_into().unwrap()).unwrap();
+//! }
+//! drop(transaction_processor);
+//!
+//! // Damage the second to last entry; entries of deposits are 48 bytes long.
+//! let mut bytes = std::fs::read(&path).unwrap();
+//! let offset = bytes.len() - 2 * 48;
+//! bytes[offset + 16] ^= 0xff;
+//! std::fs::write(&path, &bytes).unwrap();
+//!
+//! let res = recover(&path, SyncPolicy::Always, None, Default::default());
+//! assert!(matches!(res, Err(JournalError::ChecksumMismatch { offset: o }) if o == offset as u64));
+//! // Nothing was discarded.
+//! assert_eq!(std::fs::read(&path).unwrap().len(), bytes.len());
+//! # std::fs::remove_file(&path).unwrap();
+//! ```
+//!
+//! A journal whose header was only partly written is treated as a new, empty journal.
+//!
+//! ```
+//! use transaction_engine::journal::{recover, SyncPolicy};
+//!
+//! let path = std::env::temp_dir().join(format!("transaction_engine_journal_torn_header_doctest_{}", std::process::id()));
+//! std::fs::write(&path, b"TTEJ").unwrap();
+//!
+//! let (_, report) = recover(&path, SyncPolicy::Always, None, Default::default()).unwrap();
+//! assert_eq!(report.replayed, 0);
+//! assert_eq!(report.discarded_bytes, 4);
+//! assert_eq!(&std::fs::read(&path).unwrap()[..8], b"TTEJRNL\0");
+//! # std::fs::remove_file(&path).unwrap();
+//! ```
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::{ClientId, TransactionId, Currency, FractionalAmount, Precision, ProcessorPolicy, Reason, TransactionProcessor, Transaction, TransactionKind};
+use crate::snapshot::{ProcessorSnapshot, SnapshotError};
+
+/// Magic number at the start of journal files.
+const JOURNAL_MAGIC: &[u8; 8] = b"TTEJRNL\0";
+
+/// Version of the journal format.
+pub const JOURNAL_VERSION: u32 = 6;
+
+/// Length of the header of a journal file; the magic number followed by the version.
+const HEADER_LEN: u64 = 12;
+
+/// Length of the fixed-length part of an entry in a journal file.
+///
+/// The fixed-length part consists of the sequence number (u64), the type of transaction (u8),
+/// the client id (u16), the transaction id (u32), the amount (i64, zero for types
+/// of transactions that have no amount), the number of decimal places of the amount (u8),
+/// the currency code (8 bytes, padded with zeros), the receiving client id (u16, zero for
+/// types of transactions other than transfers), the time of the transaction processor when
+/// the transaction was applied (u64, see [TransactionProcessor::advance_time]), the length of
+/// the reason (u16, zero for types of transactions that have no reason) and a CRC-32 checksum
+/// of the preceding fields (u32), all in little-endian byte order.
+///
+/// For locks and unlocks, the fixed-length part is followed by the reason, as UTF-8,
+/// and a CRC-32 checksum of the reason (u32).
+const ENTRY_LEN: usize = 48;
+
+/// Length of the longest possible entry, which is that of a lock or unlock with the longest possible reason.
+const MAX_ENTRY_LEN: usize = ENTRY_LEN + Reason::MAX_LEN + 4;
+
+/// How often a [Journal] is synced to disk.
+///
+/// Every entry is written to the journal file as soon as its transaction has been applied,
+/// so every applied transaction can be recovered after a crash of the process, regardless
+/// of the sync policy. The sync policy only determines when the journal file is synced to disk,
+/// which is what protects against a crash of the host system.
+///
+/// Syncing after every entry ensures that every transaction that has been applied
+/// can be recovered after a crash of the host system, at a considerable cost to throughput.
+/// With less frequent syncing, the most recent transactions may be lost if the host system
+/// crashes, though recovery still results in a consistent state as of an earlier transaction.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum SyncPolicy {
+  /// Sync after every entry.
+  #[default]
+  Always,
+  /// Sync after every given number of entries.
+  Every(NonZeroU32),
+  /// Never sync explicitly. It is left to the operating system to decide when
+  /// to write the journal file to disk.
+  Never,
+}
+
+/// Parses `"always"`, `"never"`, or a number of entries to sync after.
+impl FromStr for SyncPolicy {
+  type Err = JournalError;
+  fn from_str (s: &str) -> Result<Self, Self::Err>
+  {
+    match s {
+      "always" => Ok(SyncPolicy::Always),
+      "never" => Ok(SyncPolicy::Never),
+      _ => s.parse().map(SyncPolicy::Every).map_err(|_| JournalError::InvalidSyncPolicy(s.to_owned())),
+    }
+  }
+}
+
+/// Append-only journal file that a [TransactionProcessor] writes accepted transactions to.
+///
+/// See [TransactionProcessor::attach_journal].
+pub struct Journal {
+  file: File,
+  sync_policy: SyncPolicy,
+  unsynced_entries: u32,
+}
+
+impl Journal {
+  /// Creates a new, empty journal file, replacing the file if it already exists.
+  pub fn create<P: AsRef<Path>> (path: P, sync_policy: SyncPolicy) -> Result<Self, JournalError>
+  {
+    let mut file = File::create(path)?;
+    file.write_all(JOURNAL_MAGIC)?;
+    file.write_all(&JOURNAL_VERSION.to_le_bytes())?;
+    file.sync_data()?;
+    Ok(Self::from_file(file, sync_policy))
+  }
+  /// Syncs the journal file to disk.
+  pub fn sync (&mut self) -> Result<(), JournalError>
+  {
+    self.file.sync_data()?;
+    self.unsynced_entries = 0;
+    Ok(())
+  }
+  /// Appends an entry to the journal, syncing it according to the [SyncPolicy].
+  pub(crate) fn append (&mut self, sequence: u64, transaction: &Transaction, time: u64) -> Result<(), JournalError>
+  {
+    // XXX: Entries are written with a single unbuffered write, so that an entry reaches the journal file
+    //      before its transaction is reported as applied, even if the process crashes right after.
+    self.file.write_all(&encode_entry(sequence, transaction, time))?;
+    self.unsynced_entries += 1;
+    match self.sync_policy {
+      SyncPolicy::Always => self.sync(),
+      SyncPolicy::Every(n) if self.unsynced_entries >= n.get() => self.sync(),
+      _ => Ok(()),
+    }
+  }
+  fn from_file (file: File, sync_policy: SyncPolicy) -> Self
+  {
+    Self {
+      file,
+      sync_policy,
+      unsynced_entries: 0,
+    }
+  }
+}
+
+/// Outcome of a successful [recover].
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+  /// Number of transactions that were replayed from the journal.
+  pub replayed: u64,
+  /// The last transaction in the journal, which is the last transaction
+  /// that was applied before the crash. `None` if the journal is empty.
+  pub last_entry: Option<Transaction>,
+  /// Number of bytes at the end of the journal that were discarded
+  /// because they were left behind by an interrupted write.
+  pub discarded_bytes: u64,
+}
+
+/// Rebuilds the state of a transaction processor from a snapshot, if any, and the journal
+/// at the given path, and attaches the journal to the transaction processor so that
+/// processing can continue.
+///
+/// If the journal file does not exist, or was left behind with an incomplete header
+/// by an interrupted [Journal::create], a new journal is created at the given path.
+/// Entries left behind by an interrupted write at the end of the journal are removed
+/// from the file.
+pub fn recover<P: AsRef<Path>> (path: P, sync_policy: SyncPolicy, snapshot: Option<ProcessorSnapshot>, policy: ProcessorPolicy) -> Result<(TransactionProcessor, RecoveryReport), JournalError>
+{
+  let mut transaction_processor = match snapshot {
+    Some(snapshot) => TransactionProcessor::restore(snapshot, policy)?,
+    None => TransactionProcessor::with_policy(policy).map_err(JournalError::Storage)?,
+  };
+  let mut report = RecoveryReport::default();
+  let mut file = match OpenOptions::new().read(true).write(true).open(&path) {
+    Ok(file) => file,
+    Err(e) if e.kind() == ErrorKind::NotFound => {
+      transaction_processor.attach_journal(Journal::create(path, sync_policy)?);
+      return Ok((transaction_processor, report));
+    },
+    Err(e) => return Err(e.into()),
+  };
+  let file_len = file.metadata()?.len();
+  if file_len < HEADER_LEN {
+    // XXX: An incomplete header is the result of an interrupted [Journal::create],
+    //      so the journal never had any entries.
+    let mut header = Vec::with_capacity(HEADER_LEN as usize);
+    file.read_to_end(&mut header)?;
+    if !JOURNAL_MAGIC.iter().chain(&JOURNAL_VERSION.to_le_bytes()).take(header.len()).eq(&header) {
+      return Err(JournalError::NotAJournal);
+    }
+    drop(file);
+    transaction_processor.attach_journal(Journal::create(path, sync_policy)?);
+    report.discarded_bytes = file_len;
+    return Ok((transaction_processor, report));
+  }
+  let mut reader = BufReader::new(&mut file);
+  let mut magic = [0u8; 8];
+  let mut version = [0u8; 4];
+  reader.read_exact(&mut magic)?;
+  reader.read_exact(&mut version)?;
+  if &magic != JOURNAL_MAGIC {
+    return Err(JournalError::NotAJournal);
+  }
+  let version = u32::from_le_bytes(version);
+  if version != JOURNAL_VERSION {
+    return Err(JournalError::UnsupportedVersion(version));
+  }
+  let mut valid_len = HEADER_LEN;
+  let mut buf = [0u8; ENTRY_LEN];
+  let mut reason = Vec::with_capacity(Reason::MAX_LEN + 4);
+  while file_len - valid_len >= ENTRY_LEN as u64 {
+    let remaining = file_len - valid_len;
+    reader.read_exact(&mut buf)?;
+    let entry_len = match reason_len(&buf) {
+      Some(reason_len) => ENTRY_LEN + reason_len,
+      // XXX: A checksum mismatch in what may be the very last entry is the result of an interrupted write,
+      //      unless a complete entry follows it.
+      None if remaining <= MAX_ENTRY_LEN as u64 => {
+        let mut rest = buf.to_vec();
+        reader.read_to_end(&mut rest)?;
+        if contains_entry(&rest[1..]) {
+          return Err(JournalError::ChecksumMismatch { offset: valid_len });
+        }
+        break;
+      },
+      None => return Err(JournalError::ChecksumMismatch { offset: valid_len }),
+    };
+    if remaining < entry_len as u64 {
+      // The very last entry is incomplete.
+      break;
+    }
+    reason.resize(entry_len - ENTRY_LEN, 0);
+    reader.read_exact(&mut reason)?;
+    let (sequence, transaction) = match decode_entry(&buf, &reason) {
+      Some(decoded) => decoded,
+      // XXX: A checksum mismatch in the very last entry is the result of an interrupted write.
+      None if remaining == entry_len as u64 => break,
+      None => return Err(JournalError::ChecksumMismatch { offset: valid_len }),
+    };
+    valid_len += entry_len as u64;
+    report.last_entry = Some(transaction.clone());
+    let expected = transaction_processor.applied_transactions + 1;
+    if sequence < expected {
+      // Already reflected in the snapshot.
+      continue;
+    } else if sequence > expected {
+      return Err(JournalError::SequenceGap { expected, found: sequence });
+    }
+    transaction_processor.process(transaction).map_err(|_| JournalError::ReplayFailed(sequence))?;
+    report.replayed += 1;
+  }
+  drop(reader);
+  report.discarded_bytes = file_len - valid_len;
+  file.set_len(valid_len)?;
+  file.seek(SeekFrom::Start(valid_len))?;
+  file.sync_data()?;
+  transaction_processor.attach_journal(Journal::from_file(file, sync_policy));
+  Ok((transaction_processor, report))
+}
+
+fn encode_entry (sequence: u64, transaction: &Transaction, time: u64) -> Vec<u8>
+{
+  let (kind, amount, to, reason) = match &transaction.kind {
+    TransactionKind::Deposit(amount) => (0u8, *amount, ClientId(0), None),
+    TransactionKind::Withdrawal(amount) => (1, *amount, ClientId(0), None),
+    TransactionKind::Dispute => (2, FractionalAmount::ZERO, ClientId(0), None),
+    TransactionKind::Resolve => (3, FractionalAmount::ZERO, ClientId(0), None),
+    TransactionKind::Chargeback => (4, FractionalAmount::ZERO, ClientId(0), None),
+    TransactionKind::Lock(reason) => (5, FractionalAmount::ZERO, ClientId(0), Some(reason.as_str().as_bytes())),
+    TransactionKind::Unlock(reason) => (6, FractionalAmount::ZERO, ClientId(0), Some(reason.as_str().as_bytes())),
+    TransactionKind::Transfer { to, amount } => (7, *amount, *to, None),
+  };
+  let mut buf = vec![0u8; ENTRY_LEN];
+  buf[0..8].copy_from_slice(&sequence.to_le_bytes());
+  buf[8] = kind;
+  buf[9..11].copy_from_slice(&transaction.client.0.to_le_bytes());
+  buf[11..15].copy_from_slice(&transaction.tx.0.to_le_bytes());
+  buf[15..23].copy_from_slice(&amount.units().to_le_bytes());
+  buf[23] = amount.precision().decimal_places();
+  buf[24..32].copy_from_slice(&transaction.currency.to_bytes());
+  buf[32..34].copy_from_slice(&to.0.to_le_bytes());
+  buf[34..42].copy_from_slice(&time.to_le_bytes());
+  // XXX: The cast is fine because reasons are at most Reason::MAX_LEN bytes long.
+  buf[42..44].copy_from_slice(&(reason.map_or(0, <[u8]>::len) as u16).to_le_bytes());
+  let checksum = crc32fast::hash(&buf[0..44]);
+  buf[44..48].copy_from_slice(&checksum.to_le_bytes());
+  if let Some(reason) = reason {
+    buf.extend_from_slice(reason);
+    buf.extend_from_slice(&crc32fast::hash(reason).to_le_bytes());
+  }
+  buf
+}
+
+/// Checks the checksum of the fixed-length part of an entry, and returns the length of
+/// the rest of the entry, which holds the reason and its checksum, if any. Returns `None`
+/// if the checksum does not match.
+fn reason_len (buf: &[u8; ENTRY_LEN]) -> Option<usize>
+{
+  // XXX: The unwraps are fine because the slices are of the exact lengths of the arrays.
+  let checksum = u32::from_le_bytes(buf[44..48].try_into().unwrap());
+  if crc32fast::hash(&buf[0..44]) != checksum {
+    return None;
+  }
+  match usize::from(u16::from_le_bytes(buf[42..44].try_into().unwrap())) {
+    0 => Some(0),
+    len => Some(len + 4),
+  }
+}
+
+/// Returns whether a complete entry with matching checksums starts anywhere in the given bytes.
+fn contains_entry (bytes: &[u8]) -> bool
+{
+  (0..bytes.len().saturating_sub(ENTRY_LEN - 1)).any(|start| {
+    // XXX: The unwrap is fine because the range leaves room for the fixed-length part of an entry.
+    let buf: &[u8; ENTRY_LEN] = bytes[start..start + ENTRY_LEN].try_into().unwrap();
+    reason_len(buf)
+      .and_then(|reason_len| bytes.get(start + ENTRY_LEN..start + ENTRY_LEN + reason_len))
+      .is_some_and(|rest| decode_entry(buf, rest).is_some())
+  })
+}
+
+/// Decodes an entry from its fixed-length part, the checksum of which has been checked
+/// by [reason_len], and the rest of the entry. Returns `None` if the checksum of the reason
+/// does not match or the entry is otherwise invalid.
+fn decode_entry (buf: &[u8; ENTRY_LEN], rest: &[u8]) -> Option<(u64, Transaction)>
+{
+  // XXX: The unwraps are fine because the slices are of the exact lengths of the arrays.
+  let sequence = u64::from_le_bytes(buf[0..8].try_into().unwrap());
+  let client = ClientId(u16::from_le_bytes(buf[9..11].try_into().unwrap()));
+  let tx = TransactionId(u32::from_le_bytes(buf[11..15].try_into().unwrap()));
+  let amount = FractionalAmount::new(i64::from_le_bytes(buf[15..23].try_into().unwrap()), Precision::new(buf[23])?);
+  let currency = Currency::from_bytes(buf[24..32].try_into().unwrap())?;
+  let to = ClientId(u16::from_le_bytes(buf[32..34].try_into().unwrap()));
+  let time = u64::from_le_bytes(buf[34..42].try_into().unwrap());
+  let reason = || -> Option<Reason> {
+    let (reason, checksum) = rest.split_at(rest.len().checked_sub(4)?);
+    if crc32fast::hash(reason) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+      return None;
+    }
+    std::str::from_utf8(reason).ok()?.parse().ok()
+  };
+  let kind = match buf[8] {
+    0 => TransactionKind::Deposit(amount),
+    1 => TransactionKind::Withdrawal(amount),
+    2 => TransactionKind::Dispute,
+    3 => TransactionKind::Resolve,
+    4 => TransactionKind::Chargeback,
+    5 => TransactionKind::Lock(reason()?),
+    6 => TransactionKind::Unlock(reason()?),
+    7 => TransactionKind::Transfer { to, amount },
+    _ => return None,
+  };
+  // XXX: Replaying the entry with the time as its timestamp advances the time of the
+  //      transaction processor the same way as when the transaction was first applied.
+  Some((sequence, Transaction { client, tx, currency, kind, timestamp: (time != 0).then_some(time) }))
+}
+
+/// Errors which can occur when writing to or recovering from a [Journal].
+#[derive(Error, Debug)]
+pub enum JournalError {
+  #[error("I/O error")]
+  Io(#[from] std::io::Error),
+  #[error("Failed to restore snapshot")]
+  Snapshot(#[from] SnapshotError),
+  #[error("Failed to create the stores of retained transactions")]
+  Storage(#[source] std::io::Error),
+  #[error("File is not a journal")]
+  NotAJournal,
+  #[error("Unsupported journal format version {0}")]
+  UnsupportedVersion(u32),
+  #[error("Checksum mismatch in journal entry at byte offset {offset}")]
+  ChecksumMismatch { offset: u64 },
+  #[error("Journal does not continue from snapshot; expected entry {expected} but found entry {found}")]
+  SequenceGap { expected: u64, found: u64 },
+  #[error("Failed to replay journal entry {0}")]
+  ReplayFailed(u64),
+  #[error("Invalid sync policy {0:?}; expected \"always\", \"never\" or a number of entries")]
+  InvalidSyncPolicy(String),
+}
@@ -10,6 +10,12 @@
 //! then, if our toy code was to be deployed to a fleet of servers, we could
 //! shard on client id.
 //!
+//! The same idea is used within a single server by the [sharded] module, which
+//! spreads the processing of transactions across a number of threads by client id.
+//! Going beyond the toy spec, transfers between clients are the one kind of transaction
+//! that involves two users, and the [sharded] module coordinates the threads of both
+//! users for those.
+//!
 //! Beyond the toy spec though, something that would become a concern over time
 //! is balancing users across the fleet of servers. Certainly there is a lot
 //! that would need to be considered.
@@ -43,7 +49,8 @@
 //! let tx_1 = TransactionId::from(1u32);
 //! let tx_2 = TransactionId::from(2u32);
 //! // XXX: Precision of fractional part is four digits as per spec.
-//! //      Fifth digit and onwards will be "chopped off", without rounding.
+//! //      Fifth digit and onwards will be "chopped off", without rounding,
+//! //      unless a different RoundingMode is given in the ParseOptions.
 //! //
 //! //      In other words, the following are all equivalent:
 //! //
@@ -142,244 +149,2518 @@
 //! assert_eq!(acc_a.get_total().to_string(), "-0.2500");
 //! assert!(acc_a.is_frozen());
 //! ```
+//!
+//! ### Disputed withdrawals
+//!
+//! Withdrawals can only be disputed when this is enabled in the [ProcessorPolicy].
+//!
+//! ```
+//! use transaction_engine::{TransactionProcessor, ProcessorPolicy, ClientId, TransactionId, Accounts};
+//!
+//! let policy = ProcessorPolicy {
+//!   withdrawals_can_be_disputed: true,
+//!   ..Default::default()
+//! };
+//! let mut transaction_processor = TransactionProcessor::with_policy(policy).unwrap();
+//!
+//! let client_a = ClientId::from(1u16);
+//! let amount_1 = "1.5".try_into().unwrap();
+//! let tx_1 = TransactionId::from(1u32);
+//! let tx_2 = TransactionId::from(2u32);
+//! let tx_3 = TransactionId::from(3u32);
+//! let amount_2 = "0.25".try_into().unwrap();
+//! let amount_3 = "0.5".try_into().unwrap();
+//!
+//! transaction_processor.deposit(client_a, tx_1, amount_1).unwrap();
+//! transaction_processor.withdraw(client_a, tx_2, amount_2).unwrap();
+//! transaction_processor.withdraw(client_a, tx_3, amount_3).unwrap();
+//!
+//! // The withdrawn amount is held while the withdrawal is under dispute.
+//! transaction_processor.dispute(client_a, tx_2).unwrap();
+//! transaction_processor.dispute(client_a, tx_3).unwrap();
+//!
+//! // Resolving the dispute means that the withdrawal stands.
+//! transaction_processor.resolve(client_a, tx_2).unwrap();
+//!
+//! // Charging back the dispute credits the withdrawn amount back to the client.
+//! transaction_processor.chargeback(client_a, tx_3).unwrap();
+//!
+//! let accounts: Accounts = transaction_processor.into();
+//! let (_, acc_a) = accounts.into_iter().next().unwrap();
+//! assert_eq!(acc_a.get_available().to_string(), "1.2500");
+//! assert_eq!(acc_a.get_held().to_string(), "0.0000");
+//! assert_eq!(acc_a.get_total().to_string(), "1.2500");
+//! assert!(acc_a.is_frozen());
+//! ```
+//!
+//! With the default policy, withdrawals are not retained and cannot be disputed.
+//!
+//! ```
+//! use transaction_engine::{TransactionProcessor, ClientId, TransactionId};
+//! use transaction_engine::TransactionDisputeError;
+//!
+//! let mut transaction_processor = TransactionProcessor::new();
+//!
+//! let client_a = ClientId::from(1u16);
+//! let tx_1 = TransactionId::from(1u32);
+//! let tx_2 = TransactionId::from(2u32);
+//!
+//! transaction_processor.deposit(client_a, tx_1, "1.5".try_into().unwrap()).unwrap();
+//! transaction_processor.withdraw(client_a, tx_2, "0.25".try_into().unwrap()).unwrap();
+//! let res = transaction_processor.dispute(client_a, tx_2);
+//! assert!(matches!(res, Err(TransactionDisputeError::ReferencedTransactionNotFoundForSpecifiedClient)));
+//! ```
+//!
+//! ### Dispute window
+//!
+//! By default, deposits are retained forever, in case they get disputed. With a [DisputeWindow]
+//! in the [ProcessorPolicy], they are evicted once they fall outside of the window, which bounds
+//! the memory used for them. Disputes of evicted deposits are rejected with a distinct error.
+//!
+//! ```
+//! use std::num::NonZeroUsize;
+//! use transaction_engine::{TransactionProcessor, ProcessorPolicy, DisputeWindow, ClientId, TransactionId};
+//! use transaction_engine::TransactionDisputeError;
+//!
+//! let policy = ProcessorPolicy {
+//!   dispute_window: Some(DisputeWindow::Transactions(NonZeroUsize::new(2).unwrap())),
+//!   ..Default::default()
+//! };
+//! let mut transaction_processor = TransactionProcessor::with_policy(policy).unwrap();
+//!
+//! let client_a = ClientId::from(1u16);
+//! let amount = "1.5".try_into().unwrap();
+//! for tx in 1..=3u32 {
+//!   transaction_processor.deposit(client_a, TransactionId::from(tx), amount).unwrap();
+//! }
+//! assert_eq!(transaction_processor.retained_transactions(), 2);
+//!
+//! // Only the two most recent deposits of the client can be disputed.
+//! let res = transaction_processor.dispute(client_a, TransactionId::from(1u32));
+//! assert!(matches!(res, Err(TransactionDisputeError::DisputeWindowExpired)));
+//! transaction_processor.dispute(client_a, TransactionId::from(2u32)).unwrap();
+//!
+//! // Deposits that never happened are still told apart from those that have been evicted.
+//! let res = transaction_processor.dispute(client_a, TransactionId::from(4u32));
+//! assert!(matches!(res, Err(TransactionDisputeError::ReferencedTransactionNotFoundForSpecifiedClient)));
+//!
+//! // A deposit that falls outside of the window while under dispute can still be resolved,
+//! // but it cannot be disputed again afterwards.
+//! transaction_processor.deposit(client_a, TransactionId::from(5u32), amount).unwrap();
+//! transaction_processor.resolve(client_a, TransactionId::from(2u32)).unwrap();
+//! let res = transaction_processor.dispute(client_a, TransactionId::from(2u32));
+//! assert!(matches!(res, Err(TransactionDisputeError::DisputeWindowExpired)));
+//!
+//! // A deposit that has been charged back is gone for good.
+//! transaction_processor.dispute(client_a, TransactionId::from(3u32)).unwrap();
+//! transaction_processor.chargeback(client_a, TransactionId::from(3u32)).unwrap();
+//! let res = transaction_processor.dispute(client_a, TransactionId::from(3u32));
+//! assert!(matches!(res, Err(TransactionDisputeError::ReferencedTransactionNotFoundForSpecifiedClient)));
+//! ```
+//!
+//! ### Amount overflow
+//!
+//! Transactions that would make the balance of an account overflow are rejected,
+//! and leave the account unchanged.
+//!
+//! ```
+//! use transaction_engine::{TransactionProcessor, ClientId, TransactionId, FractionalAmount, Accounts};
+//! use transaction_engine::TransactionDepositError;
+//!
+//! let mut transaction_processor = TransactionProcessor::new();
+//!
+//! let client_a = ClientId::from(1u16);
+//! let tx_1 = TransactionId::from(1u32);
+//! let tx_2 = TransactionId::from(2u32);
+//!
+//! transaction_processor.deposit(client_a, tx_1, FractionalAmount::MAX).unwrap();
+//! let res = transaction_processor.deposit(client_a, tx_2, "0.0001".try_into().unwrap());
+//! assert!(matches!(res, Err(TransactionDepositError::AmountOverflow)));
+//!
+//! let accounts: Accounts = transaction_processor.into();
+//! let (_, acc_a) = accounts.into_iter().next().unwrap();
+//! assert_eq!(acc_a.get_available(), FractionalAmount::MAX);
+//! ```
+//!
+//! ### Precision
+//!
+//! Amounts are precise to four places past the decimal point by default. A transaction
+//! processor can use a different [Precision] instead, given in its [ProcessorPolicy],
+//! in which case amounts must be parsed with the same precision.
+//!
+//! ```
+//! use transaction_engine::{TransactionProcessor, ProcessorPolicy, ClientId, TransactionId, FractionalAmount, ParseOptions, Precision};
+//! use transaction_engine::TransactionDepositError;
+//!
+//! let precision = Precision::new(8).unwrap();
+//! let mut transaction_processor = TransactionProcessor::with_policy(ProcessorPolicy { precision, ..Default::default() }).unwrap();
+//!
+//! let client_a = ClientId::from(1u16);
+//! let options = ParseOptions { precision, ..Default::default() };
+//! let amount = FractionalAmount::parse("0.00000001", &options).unwrap();
+//!
+//! transaction_processor.deposit(client_a, TransactionId::from(1u32), amount).unwrap();
+//! assert_eq!(transaction_processor.account(client_a).unwrap().get_available().to_string(), "0.00000001");
+//!
+//! // Amounts of another precision are rejected.
+//! let res = transaction_processor.deposit(client_a, TransactionId::from(2u32), "1.5".try_into().unwrap());
+//! assert!(matches!(res, Err(TransactionDepositError::PrecisionMismatch)));
+//! ```
+//!
+//! ### Currencies
+//!
+//! Besides the default currency, an account can hold balances in the other currencies
+//! that are accepted by the [ProcessorPolicy], each with its own [Precision]. Disputes,
+//! resolves and chargebacks apply to the currency of the transaction that they reference.
+//!
+//! ```
+//! use transaction_engine::{TransactionProcessor, ProcessorPolicy, ClientId, TransactionId, Currency, FractionalAmount, Precision};
+//! use transaction_engine::TransactionDepositError;
+//!
+//! let btc: Currency = "BTC".parse().unwrap();
+//! let mut policy = ProcessorPolicy::default();
+//! policy.currencies.insert(btc, Precision::new(8).unwrap());
+//! let mut transaction_processor = TransactionProcessor::with_policy(policy).unwrap();
+//!
+//! let client_a = ClientId::from(1u16);
+//! let tx_1 = TransactionId::from(1u32);
+//! let tx_2 = TransactionId::from(2u32);
+//!
+//! transaction_processor.deposit(client_a, tx_1, "1.5".try_into().unwrap()).unwrap();
+//! transaction_processor.deposit_in(client_a, tx_2, btc, FractionalAmount::new(25_000_000, Precision::new(8).unwrap())).unwrap();
+//! transaction_processor.dispute(client_a, tx_2).unwrap();
+//!
+//! let account = transaction_processor.account(client_a).unwrap();
+//! assert_eq!(account.get_available().to_string(), "1.5000");
+//! assert_eq!(account.balance(btc).unwrap().get_held().to_string(), "0.25000000");
+//!
+//! // Currencies that the policy does not accept are rejected.
+//! let res = transaction_processor.deposit_in(client_a, TransactionId::from(3u32), "ETH".parse().unwrap(), "1".try_into().unwrap());
+//! assert!(matches!(res, Err(TransactionDepositError::UnknownCurrency)));
+//! ```
+//!
+//! ### Duplicate transaction IDs
+//!
+//! ```
+//! use transaction_engine::{TransactionProcessor, ClientId, TransactionId, Accounts};
+//! use transaction_engine::{TransactionDepositError, TransactionWithdrawError};
+//!
+//! let mut transaction_processor = TransactionProcessor::new();
+//!
+//! let client_a = ClientId::from(1u16);
+//! let client_b = ClientId::from(2u16);
+//! let amount_1 = "1.5".try_into().unwrap();
+//! let tx_1 = TransactionId::from(1u32);
+//! let amount_2 = "0.25".try_into().unwrap();
+//!
+//! transaction_processor.deposit(client_a, tx_1, amount_1).unwrap();
+//!
+//! // A replayed deposit is rejected and does not credit the account a second time.
+//! let res = transaction_processor.deposit(client_a, tx_1, amount_1);
+//! assert!(matches!(res, Err(TransactionDepositError::DuplicateTransactionId)));
+//!
+//! // Transaction IDs are global, so they cannot be reused by another client either,
+//! // nor by a different type of transaction.
+//! let res = transaction_processor.deposit(client_b, tx_1, amount_2);
+//! assert!(matches!(res, Err(TransactionDepositError::DuplicateTransactionId)));
+//! let res = transaction_processor.withdraw(client_a, tx_1, amount_2);
+//! assert!(matches!(res, Err(TransactionWithdrawError::DuplicateTransactionId)));
+//!
+//! let accounts: Accounts = transaction_processor.into();
+//! assert_eq!(accounts.len(), 1);
+//! let (_, acc_a) = accounts.into_iter().next().unwrap();
+//! assert_eq!(acc_a.get_available().to_string(), "1.5000");
+//! ```
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fmt::Formatter;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::str::FromStr;
 
-use derive_more::{Add, Display, From, Sub, Into};
-use serde::Deserialize;
+use derive_more::{Display, From, Into};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, Visitor};
+use serde::ser::SerializeStruct;
 use thiserror::Error;
 
+pub mod journal;
+pub mod serde_units;
+pub mod sharded;
+pub mod snapshot;
+pub mod store;
+
+use journal::{Journal, JournalError};
+use store::{Storage, TransactionStore};
+
 /// Client ID is represented by u16 integer as per spec.
-#[derive(Deserialize, Debug, Display, From, Copy, Clone, Hash, Eq, PartialEq, Into)]
+#[derive(Deserialize, Debug, Display, From, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd, Into)]
 pub struct ClientId(u16);
 
 /// Transaction ID is represented by u32 integer as per spec.
-#[derive(Deserialize, Debug, Display, From, Copy, Clone, Hash, Eq, PartialEq)]
+#[derive(Deserialize, Debug, Display, From, Copy, Clone, Hash, Eq, PartialEq, Into)]
 pub struct TransactionId(u32);
 
-/// Transaction amount is precise to four places past the decimal point in inputs
-/// and outputs. Therefore, we represent the amount internally as integer fractional
-/// amounts of 1/10,000ths (one ten thousands) of the i/o amount unit.
-///
-/// We use signed integers because even though deposits and withdrawals themselves
-/// are not allowed to be negative, the available amount and the total amount on
-/// an account can become negative, as explained in the main readme file.
-#[derive(Debug, Add, From, Default, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Sub)]
-pub struct FractionalAmount(i64);
-
-impl std::fmt::Display for FractionalAmount {
-  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result
-  {
-    write!(f, "{}{}.{:04}", if self.0 >= 0 {""} else {"-"}, (self.0 / 10_000).abs(), (self.0 % 10_000).abs())
-  }
-}
-
-/// Turns a string like `"321.54689498498549"` into a [FractionalAmount]
-/// with 4 digits of precision for fractional portion as per spec.
+/// Code of a currency or other asset that balances are held in, such as `USD` or `BTC`.
 ///
-/// In the case of a string like `"321.54689498498549"`, the value of
-/// the FractionalAmount will be `3215468`, which if converted into
-/// a string will be equal to `"321.5468"`.
+/// Codes are made up of one to eight ASCII letters and digits, and are case-insensitive.
+/// They are stored in upper case, padded with zero bytes, so that currencies can be
+/// copied and compared cheaply.
 ///
-/// The full string is read in order to ensure that no non-digits
-/// are present in the input.
+/// Transactions that do not specify a currency are in the default currency, whose code is empty.
 ///
 /// ## Examples
 ///
 /// ```
-/// use transaction_engine::FractionalAmount;
-/// let amount: FractionalAmount = "321.54689498498549".try_into().unwrap();
-/// assert_eq!(amount.to_string(), "321.5468");
-/// ```
-///
-/// ```
-/// # use transaction_engine::FractionalAmount;
-/// let amount: FractionalAmount = "25".try_into().unwrap();
-/// assert_eq!(amount.to_string(), "25.0000");
+/// use transaction_engine::Currency;
+/// let btc: Currency = "btc".parse().unwrap();
+/// assert_eq!(btc.to_string(), "BTC");
+/// assert_eq!("".parse::<Currency>().unwrap(), Currency::DEFAULT);
+/// assert!("BTC-USD".parse::<Currency>().is_err());
+/// assert!("TOOLONGCODE".parse::<Currency>().is_err());
 /// ```
-impl TryInto<FractionalAmount> for &str {
-  type Error = FractionalAmountParseError;
-  fn try_into (self) -> Result<FractionalAmount, Self::Error>
+#[derive(Debug, Default, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Currency([u8; 8]);
+
+impl Currency {
+  /// The currency of transactions that do not specify a currency.
+  pub const DEFAULT: Currency = Currency([0; 8]);
+  /// Returns the currency with the given code, as stored by [Currency::to_bytes],
+  /// or `None` if the bytes are not a valid code.
+  pub fn from_bytes (bytes: [u8; 8]) -> Option<Currency>
   {
-    let mut splitter = self.splitn(2, ".");
-    // XXX: The unwrap below is fine because even with an empty string,
-    //      the first call to next() will return Some(&str).
-    let decimal_portion = splitter.next().unwrap();
-    let decimal_portion_amount = decimal_portion.parse::<i64>()
-      .map_err(|e| FractionalAmountParseError::DecimalPortionParseIntError(e))?;
-    let mut fractional_portion_amount = 0;
-    if let Some(fractional_portion) = splitter.next() {
-      let mut magnitude = 1_000;
-      for digit in fractional_portion.chars() {
-        let digit = digit.to_digit(10).ok_or(FractionalAmountParseError::NonDigitInFractionalPortion)? as i64;
-        if magnitude > 1 {
-          fractional_portion_amount += digit * magnitude;
-          magnitude /= 10;
-        } else if magnitude == 1 {
-          fractional_portion_amount += digit;
-          magnitude = 0;
-        }
-        // XXX: After we finish reading the up to 4 digits that we care about
-        //      in terms of precision, we do not break out of the loop. The reason for this
-        //      is that we still want to ensure that all remaining characters are digits.
-      }
-    };
-    Ok(FractionalAmount(decimal_portion_amount * 10_000 + fractional_portion_amount))
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    let valid = bytes[..len].iter().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+      && bytes[len..].iter().all(|&b| b == 0);
+    valid.then_some(Currency(bytes))
+  }
+  /// The code of the currency in upper case, padded with zero bytes, for binary encodings of currencies.
+  pub fn to_bytes (self) -> [u8; 8]
+  {
+    self.0
   }
 }
 
-/// Errors returned for [TryInto::try_into]::<[FractionalAmount]> on &[str].
-#[derive(Error, Debug)]
-pub enum FractionalAmountParseError {
-  #[error("Failed to parse decimal portion of amount")]
-  DecimalPortionParseIntError(#[from] std::num::ParseIntError),
-  #[error("Non-digit in fractional portion of amount")]
-  NonDigitInFractionalPortion,
+impl std::fmt::Display for Currency {
+  fn fmt (&self, f: &mut Formatter<'_>) -> std::fmt::Result
+  {
+    let len = self.0.iter().position(|&b| b == 0).unwrap_or(self.0.len());
+    // XXX: The unwrap is fine because codes only ever consist of ASCII letters and digits.
+    f.write_str(std::str::from_utf8(&self.0[..len]).unwrap())
+  }
 }
 
-/// Contains the account data for a single user.
-#[derive(Debug, Default)]
-pub struct Account {
-  available_amount: FractionalAmount,
-  held_amount: FractionalAmount,
-  frozen: bool,
+/// Parses a currency code. The empty string is the default currency.
+impl FromStr for Currency {
+  type Err = ParseCurrencyError;
+  fn from_str (s: &str) -> Result<Self, Self::Err>
+  {
+    let mut bytes = [0u8; 8];
+    if s.len() > bytes.len() || !s.bytes().all(|b| b.is_ascii_alphanumeric()) {
+      return Err(ParseCurrencyError(s.to_owned()));
+    }
+    bytes[..s.len()].copy_from_slice(s.to_ascii_uppercase().as_bytes());
+    Ok(Currency(bytes))
+  }
 }
 
-impl Account {
-  pub fn is_frozen (&self) -> bool {
-    self.frozen
-  }
-  pub fn get_available (&self) -> FractionalAmount {
-    self.available_amount
-  }
-  pub fn get_held (&self) -> FractionalAmount {
-    self.held_amount
-  }
-  pub fn get_total (&self) -> FractionalAmount {
-    self.available_amount + self.held_amount
+/// Currencies are serialized as their code.
+impl Serialize for Currency {
+  fn serialize<S: Serializer> (&self, serializer: S) -> Result<S::Ok, S::Error>
+  {
+    serializer.collect_str(self)
   }
 }
 
-/// Contains the accounts of all users for which we have processed valid transactions.
-pub type Accounts = HashMap<ClientId, Account>;
+/// Error returned when parsing a [Currency] from a string fails.
+#[derive(Error, Debug)]
+#[error("Invalid currency {0:?}; expected up to eight ASCII letters and digits")]
+pub struct ParseCurrencyError(String);
 
-/// Processes transactions and provides final balances for accounts for which
-/// transactions have been processed.
-pub struct TransactionProcessor {
-  accounts: Accounts,
-  /// Contains deposit transactions we have seen and which we are holding onto until,
-  /// if ever, they get disputed.
-  ///
-  /// When a transaction is disputed by the user, we remove the transaction from the
-  /// collection of deposit transactions and put an entry for the transaction
-  /// in the collection of disputed transactions instead.
-  ///
-  /// A transaction is put back into the collection of deposit transactions
-  /// if the transaction is subsequently resolved after having been disputed.
-  /// This is done in case there are any future disputes about the transaction.
-  ///
-  /// Meanwhile, if the transaction changes state from disputed to charged back,
-  /// then the transaction will not be put back into the deposit transactions
-  /// collection, as
-  deposit_transactions: HashMap<(ClientId, TransactionId), FractionalAmount>,
-  /// Contains dispute transactions we have seen and which we are holding onto until,
-  /// if ever, they either get resolved or charged back.
-  ///
-  /// See also the description on the deposit transactions field for details about
-  /// what happens to a transaction after it has been disputed and then it has
-  /// either been resolved or charged back.
-  dispute_transactions: HashMap<(ClientId, TransactionId), FractionalAmount>,
+/// Reason given by support staff for locking or unlocking an account.
+///
+/// A reason is mandatory for [TransactionKind::Lock] and [TransactionKind::Unlock], so it cannot
+/// be empty. It is at most [Reason::MAX_LEN] bytes long, so that it fits in a single record
+/// of the binary encodings of transactions.
+///
+/// ## Examples
+///
+/// ```
+/// use transaction_engine::Reason;
+/// let reason: Reason = "Identity verified by phone".parse().unwrap();
+/// assert_eq!(reason.as_str(), "Identity verified by phone");
+/// assert!("".parse::<Reason>().is_err());
+/// assert!("   ".parse::<Reason>().is_err());
+/// ```
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct Reason(Box<str>);
+
+impl Reason {
+  /// The maximum length of a reason in bytes.
+  pub const MAX_LEN: usize = 200;
+  pub fn as_str (&self) -> &str
+  {
+    &self.0
+  }
 }
 
-/// Processes deposit, withdraw, dispute, resolve and chargeback transactions.
-impl TransactionProcessor {
-  pub fn new () -> Self
+impl std::fmt::Display for Reason {
+  fn fmt (&self, f: &mut Formatter<'_>) -> std::fmt::Result
   {
-    Self {
-      accounts: Default::default(),
-      deposit_transactions: Default::default(),
-      dispute_transactions: Default::default()
-    }
+    f.write_str(&self.0)
   }
-  /// Credit to client's account.
-  pub fn deposit (&mut self, client_id: ClientId, transaction_id: TransactionId, amount: FractionalAmount) -> Result<(), TransactionDepositError>
+}
+
+/// Parses a reason, which must contain something other than whitespace.
+impl FromStr for Reason {
+  type Err = ParseReasonError;
+  fn from_str (s: &str) -> Result<Self, Self::Err>
   {
-    if amount.0 < 0 {
-      return Err(TransactionDepositError::CannotDepositANegativeAmount);
+    if s.trim().is_empty() || s.len() > Reason::MAX_LEN {
+      return Err(ParseReasonError(s.to_owned()));
     }
-    let account = self.accounts.entry(client_id).or_insert_with(|| Default::default());
-    account.available_amount = account.available_amount + amount;
-    self.deposit_transactions.insert((client_id, transaction_id), amount);
-    Ok(())
+    Ok(Reason(s.into()))
   }
-  /// Debit to client's account.
-  pub fn withdraw (&mut self, client_id: ClientId, _transaction_id: TransactionId, amount: FractionalAmount) -> Result<(), TransactionWithdrawError>
+}
+
+/// Error returned when parsing a [Reason] from a string fails.
+#[derive(Error, Debug)]
+#[error("Invalid reason {0:?}; expected between 1 and 200 bytes of text")]
+pub struct ParseReasonError(String);
+
+/// Number of places past the decimal point that amounts are precise to.
+///
+/// The spec calls for four places, which is the default. Other currencies and assets
+/// need a different number of places, from none at all up to eight or more.
+/// At most 18 places are supported, so that one whole unit still fits in an `i64`.
+///
+/// ## Examples
+///
+/// ```
+/// use transaction_engine::Precision;
+/// assert_eq!(Precision::default().decimal_places(), 4);
+/// assert_eq!(Precision::new(8).unwrap().scale(), 100_000_000);
+/// assert!(Precision::new(19).is_none());
+/// assert_eq!("2".parse::<Precision>().unwrap(), Precision::new(2).unwrap());
+/// ```
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Precision(u8);
+
+impl Precision {
+  /// The precision of four places past the decimal point, as per spec.
+  pub const DEFAULT: Precision = Precision(4);
+  /// The largest supported number of places past the decimal point.
+  pub const MAX_DECIMAL_PLACES: u8 = 18;
+  /// Returns the precision with the given number of places past the decimal point,
+  /// or `None` if more places are given than are supported.
+  pub fn new (decimal_places: u8) -> Option<Precision>
   {
-    if amount.0 < 0 {
-      return Err(TransactionWithdrawError::CannotWithdrawANegativeAmount);
-    }
-    let account = self.accounts.entry(client_id).or_insert_with(|| Default::default());
-    if account.frozen {
-      return Err(TransactionWithdrawError::CannotWithdrawFromFrozenAccount);
-    } else if account.available_amount < amount {
-      return Err(TransactionWithdrawError::InsufficientAmountAvailableForWithdrawal);
-    }
-    account.available_amount = account.available_amount - amount;
-    Ok(())
+    (decimal_places <= Self::MAX_DECIMAL_PLACES).then_some(Precision(decimal_places))
   }
-  /// Claim that referenced transaction was erroneous and should be reversed.
-  pub fn dispute (&mut self, client_id: ClientId, transaction_id: TransactionId) -> Result<(), TransactionDisputeError>
+  /// Number of places past the decimal point.
+  pub fn decimal_places (self) -> u8
   {
-    let k = (client_id, transaction_id);
-    let disputed_amount = self.deposit_transactions.remove(&k).ok_or(TransactionDisputeError::ReferencedTransactionNotFoundForSpecifiedClient)?;
-    // XXX: The unwrap for the account is fine because we have found the deposit transaction,
-    //      and because we create accounts when we process deposits that means that
-    //      an account for the client exists for sure :)
-    let acc = self.accounts.get_mut(&client_id).unwrap();
-    acc.available_amount = acc.available_amount - disputed_amount;
-    acc.held_amount = acc.held_amount + disputed_amount;
-    self.dispute_transactions.insert(k, disputed_amount);
-    Ok(())
+    self.0
   }
-  /// A resolution to a dispute.
-  pub fn resolve (&mut self, client_id: ClientId, transaction_id: TransactionId) -> Result<(), TransactionResolveError>
+  /// Number of the smallest units of an amount that make up one whole unit.
+  pub fn scale (self) -> i64
   {
-    let k = (client_id, transaction_id);
-    let resolved_amount = self.dispute_transactions.remove(&k).ok_or(TransactionResolveError::ReferencedTransactionNotUnderDisputeForSpecifiedClient)?;
-    // XXX: Unwrap for the account is fine for same reason as in Self::dispute.
-    let acc = self.accounts.get_mut(&client_id).unwrap();
-    acc.available_amount = acc.available_amount + resolved_amount;
-    acc.held_amount = acc.held_amount - resolved_amount;
-    self.deposit_transactions.insert(k, resolved_amount);
-    Ok(())
+    10i64.pow(self.0.into())
   }
-  /// Final state of a dispute.
-  pub fn chargeback (&mut self, client_id: ClientId, transaction_id: TransactionId) -> Result<(), TransactionChargebackError>
+}
+
+impl Default for Precision {
+  fn default () -> Self
   {
-    let k = (client_id, transaction_id);
-    let chargeback_amount = self.dispute_transactions.remove(&k).ok_or(TransactionChargebackError::ReferencedTransactionNotUnderDisputeForSpecifiedClient)?;
-    // XXX: Unwrap for the account is fine for same reason as in Self::dispute.
-    let acc = self.accounts.get_mut(&client_id).unwrap();
-    acc.held_amount = acc.held_amount - chargeback_amount;
-    acc.frozen = true;
-    Ok(())
+    Self::DEFAULT
   }
 }
 
-impl Into<Accounts> for TransactionProcessor {
-  /// Consumes self and returns final account data for all accounts
-  /// for which valid transactions have been processed.
-  fn into (self) -> Accounts {
-    self.accounts
+impl FromStr for Precision {
+  type Err = ParsePrecisionError;
+  fn from_str (s: &str) -> Result<Self, Self::Err>
+  {
+    s.parse().ok().and_then(Precision::new).ok_or_else(|| ParsePrecisionError(s.to_owned()))
   }
 }
 
-/// Errors returned by [TransactionProcessor::withdraw].
+/// Error returned when parsing a [Precision] from a string fails.
 #[derive(Error, Debug)]
-pub enum TransactionDepositError {
+#[error("Invalid precision {0:?}; expected a number of decimal places from 0 to 18")]
+pub struct ParsePrecisionError(String);
+
+/// Transaction amount is precise to a fixed number of places past the decimal point
+/// in inputs and outputs, which is four places as per spec unless a different [Precision]
+/// is used. Therefore, we represent the amount internally as an integer number of
+/// the smallest units at that precision, such as 1/10,000ths (one ten thousands)
+/// of the i/o amount unit for the default precision.
+///
+/// Each amount carries its precision along with it, so that it is formatted with
+/// the right number of decimal places wherever it ends up.
+///
+/// We use signed integers because even though deposits and withdrawals themselves
+/// are not allowed to be negative, the available amount and the total amount on
+/// an account can become negative, as explained in the main readme file.
+///
+/// Converting an `i64` to an amount gives an amount of that many 1/10,000ths, at the
+/// default precision. See [FractionalAmount::new] and [FractionalAmount::units] for
+/// amounts of any precision, which is useful for binary encodings of amounts.
+///
+/// Amounts are compared by value, so amounts with different precisions are equal
+/// when they represent the same number.
+///
+/// Arithmetic on amounts is only available in checked form, so that amounts
+/// that are out of range result in an error rather than a panic or silent wraparound.
+///
+/// ## Examples
+///
+/// ```
+/// use transaction_engine::{FractionalAmount, Precision};
+/// let amount: FractionalAmount = "1.5".try_into().unwrap();
+/// assert_eq!(amount.checked_add(amount).unwrap().to_string(), "3.0000");
+/// assert_eq!(amount.checked_sub(amount).unwrap().to_string(), "0.0000");
+/// assert_eq!(amount.checked_mul(3).unwrap().to_string(), "4.5000");
+/// assert!(FractionalAmount::MAX.checked_add(amount).is_none());
+///
+/// let cents = FractionalAmount::new(150, Precision::new(2).unwrap());
+/// assert_eq!(cents.to_string(), "1.50");
+/// assert_eq!(cents, amount);
+/// ```
+#[derive(Debug, Copy, Clone)]
+pub struct FractionalAmount {
+  units: i64,
+  precision: Precision,
+}
+
+impl FractionalAmount {
+  /// An amount of zero, at the default precision.
+  pub const ZERO: FractionalAmount = FractionalAmount { units: 0, precision: Precision::DEFAULT };
+  /// The smallest amount that can be represented at the default precision.
+  pub const MIN: FractionalAmount = FractionalAmount { units: i64::MIN, precision: Precision::DEFAULT };
+  /// The largest amount that can be represented at the default precision.
+  pub const MAX: FractionalAmount = FractionalAmount { units: i64::MAX, precision: Precision::DEFAULT };
+  /// Creates an amount of the given number of the smallest units at the given precision.
+  pub fn new (units: i64, precision: Precision) -> FractionalAmount
+  {
+    FractionalAmount { units, precision }
+  }
+  /// The amount of zero at the given precision.
+  pub fn zero (precision: Precision) -> FractionalAmount
+  {
+    Self::new(0, precision)
+  }
+  /// The integer number of the smallest units of the amount, at the precision of the amount.
+  pub fn units (self) -> i64
+  {
+    self.units
+  }
+  /// The precision of the amount.
+  pub fn precision (self) -> Precision
+  {
+    self.precision
+  }
+  /// Converts the amount to a different precision. Returns `None` if the amount cannot
+  /// be represented exactly at the other precision, or if it would overflow.
+  ///
+  /// ## Examples
+  ///
+  /// ```
+  /// use transaction_engine::{FractionalAmount, Precision};
+  /// let amount: FractionalAmount = "1.5".try_into().unwrap();
+  /// assert_eq!(amount.to_precision(Precision::new(8).unwrap()).unwrap().to_string(), "1.50000000");
+  /// assert_eq!(amount.to_precision(Precision::new(1).unwrap()).unwrap().to_string(), "1.5");
+  /// assert!(amount.to_precision(Precision::new(0).unwrap()).is_none());
+  /// ```
+  pub fn to_precision (self, precision: Precision) -> Option<FractionalAmount>
+  {
+    let units = if precision >= self.precision {
+      self.units.checked_mul(10i64.pow((precision.0 - self.precision.0).into()))?
+    } else {
+      let divisor = 10i64.pow((self.precision.0 - precision.0).into());
+      if self.units % divisor != 0 {
+        return None;
+      }
+      self.units / divisor
+    };
+    Some(Self::new(units, precision))
+  }
+  /// Adds two amounts, returning `None` if the result would overflow.
+  ///
+  /// The result has the precision of `self`. If `rhs` has a different precision, it is
+  /// converted first, and `None` is returned if it cannot be represented exactly.
+  pub fn checked_add (self, rhs: FractionalAmount) -> Option<FractionalAmount>
+  {
+    let rhs = rhs.to_precision(self.precision)?;
+    self.units.checked_add(rhs.units).map(|units| Self::new(units, self.precision))
+  }
+  /// Subtracts an amount from another, returning `None` if the result would overflow.
+  ///
+  /// Amounts of different precisions are handled the same way as in [FractionalAmount::checked_add].
+  pub fn checked_sub (self, rhs: FractionalAmount) -> Option<FractionalAmount>
+  {
+    let rhs = rhs.to_precision(self.precision)?;
+    self.units.checked_sub(rhs.units).map(|units| Self::new(units, self.precision))
+  }
+  /// Multiplies an amount by an integer factor, returning `None` if the result would overflow.
+  pub fn checked_mul (self, rhs: i64) -> Option<FractionalAmount>
+  {
+    self.units.checked_mul(rhs).map(|units| Self::new(units, self.precision))
+  }
+  /// Negates an amount which is known to not be negative.
+  ///
+  /// This is used for turning the amount of a transaction into a change to the
+  /// balance of an account. The amounts of transactions are never negative,
+  /// so the negation cannot overflow.
+  fn negated (self) -> FractionalAmount
+  {
+    debug_assert!(self.units >= 0);
+    Self::new(-self.units, self.precision)
+  }
+  /// The amount as a number of the smallest units at the given precision, which must be
+  /// at least the precision of the amount. This is used for comparing amounts by value.
+  fn widened_units (self, precision: Precision) -> i128
+  {
+    // XXX: This cannot overflow, as the magnitude of an i64 multiplied by 10^18 fits in an i128.
+    i128::from(self.units) * i128::from(10i64.pow((precision.0 - self.precision.0).into()))
+  }
+}
+
+impl Default for FractionalAmount {
+  fn default () -> Self
+  {
+    Self::ZERO
+  }
+}
+
+/// Converts an integer number of 1/10,000ths into an amount at the default precision.
+impl From<i64> for FractionalAmount {
+  fn from (units: i64) -> Self
+  {
+    Self::new(units, Precision::DEFAULT)
+  }
+}
+
+impl Ord for FractionalAmount {
+  fn cmp (&self, other: &Self) -> std::cmp::Ordering
+  {
+    let precision = self.precision.max(other.precision);
+    self.widened_units(precision).cmp(&other.widened_units(precision))
+  }
+}
+
+impl PartialOrd for FractionalAmount {
+  fn partial_cmp (&self, other: &Self) -> Option<std::cmp::Ordering>
+  {
+    Some(self.cmp(other))
+  }
+}
+
+impl PartialEq for FractionalAmount {
+  fn eq (&self, other: &Self) -> bool
+  {
+    self.cmp(other).is_eq()
+  }
+}
+
+impl Eq for FractionalAmount {}
+
+/// Formats the amount with as many decimal places as its precision has.
+impl std::fmt::Display for FractionalAmount {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result
+  {
+    let scale = self.precision.scale();
+    let sign = if self.units >= 0 {""} else {"-"};
+    let (whole, fraction) = ((self.units / scale).unsigned_abs(), (self.units % scale).unsigned_abs());
+    match usize::from(self.precision.0) {
+      0 => write!(f, "{}{}", sign, whole),
+      decimal_places => write!(f, "{}{}.{:0width$}", sign, whole, fraction, width = decimal_places),
+    }
+  }
+}
+
+/// Turns a string like `"321.54689498498549"` into a [FractionalAmount]
+/// with 4 digits of precision for fractional portion as per spec.
+///
+/// In the case of a string like `"321.54689498498549"`, the value of
+/// the FractionalAmount will be `3215468`, which if converted into
+/// a string will be equal to `"321.5468"`.
+///
+/// The full string is read in order to ensure that no non-digits
+/// are present in the input.
+///
+/// This uses the default [ParseOptions]. See [FractionalAmount::parse]
+/// for details about the accepted format.
+///
+/// ## Examples
+///
+/// ```
+/// use transaction_engine::FractionalAmount;
+/// let amount: FractionalAmount = "321.54689498498549".try_into().unwrap();
+/// assert_eq!(amount.to_string(), "321.5468");
+/// ```
+///
+/// ```
+/// # use transaction_engine::FractionalAmount;
+/// let amount: FractionalAmount = "25".try_into().unwrap();
+/// assert_eq!(amount.to_string(), "25.0000");
+/// ```
+///
+/// Amounts that cannot be represented are rejected.
+///
+/// ```
+/// # use transaction_engine::{FractionalAmount, FractionalAmountParseError};
+/// let res: Result<FractionalAmount, _> = "922337203685477.5808".try_into();
+/// assert!(matches!(res, Err(FractionalAmountParseError::AmountOutOfRange)));
+/// ```
+impl TryInto<FractionalAmount> for &str {
+  type Error = FractionalAmountParseError;
+  fn try_into (self) -> Result<FractionalAmount, Self::Error>
+  {
+    FractionalAmount::parse(self, &Default::default())
+  }
+}
+
+/// Amounts are serialized as strings in the same form as their [std::fmt::Display] output, with
+/// as many decimal places as their precision has, so that no precision is lost in formats
+/// that would otherwise use floating point.
+///
+/// To serialize the integer number of the smallest units instead, see the [serde_units] module.
+///
+/// ## Examples
+///
+/// ```
+/// use transaction_engine::FractionalAmount;
+/// let amount: FractionalAmount = "1.5".try_into().unwrap();
+/// assert_eq!(serde_json::to_string(&amount).unwrap(), r#""1.5000""#);
+/// ```
+impl Serialize for FractionalAmount {
+  fn serialize<S: Serializer> (&self, serializer: S) -> Result<S::Ok, S::Error>
+  {
+    serializer.collect_str(self)
+  }
+}
+
+/// Amounts are deserialized from strings, at the precision of as many decimal places as the string has,
+/// so that amounts are read back at the precision that they were serialized with.
+///
+/// ## Examples
+///
+/// ```
+/// use transaction_engine::{FractionalAmount, Precision};
+/// let amount: FractionalAmount = serde_json::from_str(r#""1.5000""#).unwrap();
+/// assert_eq!(amount.to_string(), "1.5000");
+/// assert_eq!(serde_json::from_str::<FractionalAmount>(&serde_json::to_string(&amount).unwrap()).unwrap(), amount);
+/// assert!(serde_json::from_str::<FractionalAmount>(r#""1.5x""#).is_err());
+///
+/// // Amounts keep their precision.
+/// let amount = FractionalAmount::new(-12_345_678, Precision::new(8).unwrap());
+/// let json = serde_json::to_string(&amount).unwrap();
+/// assert_eq!(json, r#""-0.12345678""#);
+/// assert_eq!(serde_json::from_str::<FractionalAmount>(&json).unwrap(), amount);
+/// let amount = FractionalAmount::new(42, Precision::new(0).unwrap());
+/// assert_eq!(serde_json::from_str::<FractionalAmount>(&serde_json::to_string(&amount).unwrap()).unwrap(), amount);
+///
+/// // There are at most 18 decimal places.
+/// assert!(serde_json::from_str::<FractionalAmount>(r#""0.1234567890123456789""#).is_err());
+/// ```
+impl<'de> Deserialize<'de> for FractionalAmount {
+  fn deserialize<D: Deserializer<'de>> (deserializer: D) -> Result<Self, D::Error>
+  {
+    deserializer.deserialize_str(FractionalAmountVisitor)
+  }
+}
+
+/// Visitor for deserializing a [FractionalAmount] from a string.
+struct FractionalAmountVisitor;
+
+impl Visitor<'_> for FractionalAmountVisitor {
+  type Value = FractionalAmount;
+  fn expecting (&self, f: &mut Formatter<'_>) -> std::fmt::Result
+  {
+    f.write_str("a decimal amount as a string")
+  }
+  fn visit_str<E: de::Error> (self, v: &str) -> Result<Self::Value, E>
+  {
+    let decimal_places = v.split_once('.').map_or(0, |(_, fraction)| fraction.len());
+    let precision = u8::try_from(decimal_places).ok().and_then(Precision::new)
+      .ok_or_else(|| E::invalid_value(de::Unexpected::Str(v), &"an amount with at most 18 decimal places"))?;
+    FractionalAmount::parse(v, &ParseOptions { precision, ..Default::default() }).map_err(E::custom)
+  }
+}
+
+/// Options for [FractionalAmount::parse].
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct ParseOptions {
+  /// Reject amounts that have a decimal point but no digits after it, such as `"1."`.
+  pub strict: bool,
+  /// The precision of the parsed amount.
+  pub precision: Precision,
+  /// How amounts with more decimal places than the precision has are handled.
+  pub rounding: RoundingMode,
+}
+
+/// How [FractionalAmount::parse] handles amounts that have more decimal places than
+/// the [Precision] that they are parsed with.
+///
+/// Rounding applies to the magnitude of the amount, before the sign is applied,
+/// so that rounding a negative amount gives the negation of rounding the positive amount.
+///
+/// ## Examples
+///
+/// ```
+/// use transaction_engine::{FractionalAmount, ParseOptions, RoundingMode};
+///
+/// let parse = |s, rounding| FractionalAmount::parse(s, &ParseOptions { rounding, ..Default::default() }).map(|a| a.to_string());
+/// assert_eq!(parse("0.25009", RoundingMode::Truncate).unwrap(), "0.2500");
+/// assert_eq!(parse("0.25005", RoundingMode::HalfUp).unwrap(), "0.2501");
+/// assert_eq!(parse("-0.25005", RoundingMode::HalfUp).unwrap(), "-0.2501");
+/// assert_eq!(parse("0.25005", RoundingMode::HalfEven).unwrap(), "0.2500");
+/// assert_eq!(parse("0.25015", RoundingMode::HalfEven).unwrap(), "0.2502");
+/// assert_eq!(parse("0.250050001", RoundingMode::HalfEven).unwrap(), "0.2501");
+/// assert_eq!(parse("0.25000000", RoundingMode::Reject).unwrap(), "0.2500");
+/// assert!(parse("0.25009", RoundingMode::Reject).is_err());
+/// assert_eq!("half-even".parse::<RoundingMode>().unwrap(), RoundingMode::HalfEven);
+/// ```
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum RoundingMode {
+  /// Excess digits are "chopped off", without rounding.
+  #[default]
+  Truncate,
+  /// Round to the nearest amount, with amounts exactly half way rounded away from zero.
+  HalfUp,
+  /// Round to the nearest amount, with amounts exactly half way rounded to the amount
+  /// whose last digit is even. This is also known as banker's rounding.
+  HalfEven,
+  /// Amounts with excess non-zero digits are rejected with [FractionalAmountParseError::ExcessPrecision].
+  Reject,
+}
+
+impl FromStr for RoundingMode {
+  type Err = ParseRoundingModeError;
+  fn from_str (s: &str) -> Result<Self, Self::Err>
+  {
+    match s {
+      "truncate" => Ok(RoundingMode::Truncate),
+      "half-up" => Ok(RoundingMode::HalfUp),
+      "half-even" => Ok(RoundingMode::HalfEven),
+      "reject" => Ok(RoundingMode::Reject),
+      _ => Err(ParseRoundingModeError(s.to_owned())),
+    }
+  }
+}
+
+/// Error returned when parsing a [RoundingMode] from a string fails.
+#[derive(Error, Debug)]
+#[error("Invalid rounding mode {0:?}; expected \"truncate\", \"half-up\", \"half-even\" or \"reject\"")]
+pub struct ParseRoundingModeError(String);
+
+impl FractionalAmount {
+  /// Parses a string like `"-321.5468"` into a [FractionalAmount].
+  ///
+  /// The string consists of an optional sign, followed by one or more digits,
+  /// optionally followed by a decimal point and a fractional portion. The sign
+  /// applies to the number as a whole, including the fractional portion.
+  ///
+  /// Digits of the fractional portion past the number of decimal places of the
+  /// [Precision] in the options are handled according to the [RoundingMode]
+  /// in the options. By default, they are "chopped off", without rounding.
+  ///
+  /// ## Examples
+  ///
+  /// ```
+  /// use transaction_engine::{FractionalAmount, ParseOptions, FractionalAmountParseError};
+  ///
+  /// let options = ParseOptions::default();
+  /// assert_eq!(FractionalAmount::parse("-1.5", &options).unwrap().to_string(), "-1.5000");
+  /// assert_eq!(FractionalAmount::parse("-0.5", &options).unwrap().to_string(), "-0.5000");
+  /// assert_eq!(FractionalAmount::parse("+2", &options).unwrap().to_string(), "2.0000");
+  /// assert_eq!(FractionalAmount::parse("1.", &options).unwrap().to_string(), "1.0000");
+  ///
+  /// let res = FractionalAmount::parse("-", &options);
+  /// assert!(matches!(res, Err(FractionalAmountParseError::MissingDigits)));
+  /// let res = FractionalAmount::parse("1.", &ParseOptions { strict: true, ..Default::default() });
+  /// assert!(matches!(res, Err(FractionalAmountParseError::EmptyFractionalPortion)));
+  /// ```
+  ///
+  /// With a different [Precision], the amount has that number of decimal places.
+  ///
+  /// ```
+  /// use transaction_engine::{FractionalAmount, ParseOptions, Precision};
+  ///
+  /// let options = ParseOptions { precision: Precision::new(8).unwrap(), ..Default::default() };
+  /// assert_eq!(FractionalAmount::parse("0.123456789", &options).unwrap().to_string(), "0.12345678");
+  /// let options = ParseOptions { precision: Precision::new(0).unwrap(), ..Default::default() };
+  /// assert_eq!(FractionalAmount::parse("1500.99", &options).unwrap().to_string(), "1500");
+  /// ```
+  ///
+  /// Formatting an amount and parsing the result gives back the same amount,
+  /// at every precision and up to the edges of the range of the underlying integer,
+  /// both for the edge cases and for pseudo-random amounts of every magnitude.
+  ///
+  /// ```
+  /// use transaction_engine::{FractionalAmount, ParseOptions, Precision, RoundingMode};
+  ///
+  /// for decimal_places in 0..=Precision::MAX_DECIMAL_PLACES {
+  ///   let precision = Precision::new(decimal_places).unwrap();
+  ///   let options = ParseOptions { strict: true, precision, rounding: RoundingMode::Reject };
+  ///   let scale = precision.scale();
+  ///   for value in [i64::MIN, i64::MIN + 1, -scale - 1, -scale, -scale + 1, -1, 0, 1, scale - 1, scale, scale + 1, i64::MAX - 1, i64::MAX] {
+  ///     let amount = FractionalAmount::new(value, precision);
+  ///     let parsed = FractionalAmount::parse(&amount.to_string(), &options).unwrap();
+  ///     assert_eq!((parsed.units(), parsed.precision()), (value, precision));
+  ///   }
+  ///   // Negative zero is zero.
+  ///   assert_eq!(FractionalAmount::parse(&format!("-{}", FractionalAmount::zero(precision)), &options).unwrap().units(), 0);
+  /// }
+  ///
+  /// // SplitMix64, with a fixed seed so that failures can be reproduced.
+  /// let mut state = 0x5eed_u64;
+  /// let mut next = || {
+  ///   state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+  ///   let mut z = state;
+  ///   z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+  ///   z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+  ///   z ^ (z >> 31)
+  /// };
+  /// for _ in 0..20_000 {
+  ///   let precision = Precision::new((next() % (Precision::MAX_DECIMAL_PLACES as u64 + 1)) as u8).unwrap();
+  ///   let options = ParseOptions { strict: true, precision, rounding: RoundingMode::Reject };
+  ///   // Shift by a random number of bits, so that small amounts are as likely as large ones.
+  ///   let value = (next() as i64) >> (next() % 64);
+  ///   let amount = FractionalAmount::new(value, precision);
+  ///   let parsed = FractionalAmount::parse(&amount.to_string(), &options).unwrap();
+  ///   assert_eq!((parsed.units(), parsed.precision()), (value, precision), "{}", amount);
+  /// }
+  /// ```
+  pub fn parse (s: &str, options: &ParseOptions) -> Result<FractionalAmount, FractionalAmountParseError>
+  {
+    let (negative, unsigned) = match s.strip_prefix('-') {
+      Some(unsigned) => (true, unsigned),
+      None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let (decimal_portion, fractional_portion) = match unsigned.split_once('.') {
+      Some((decimal_portion, fractional_portion)) => (decimal_portion, Some(fractional_portion)),
+      None => (unsigned, None),
+    };
+    if decimal_portion.is_empty() {
+      return Err(FractionalAmountParseError::MissingDigits);
+    }
+    // XXX: We accumulate the magnitude of the amount in an i128, so that the magnitude
+    //      of the smallest i64 can be represented before the sign is applied.
+    let mut units: i128 = 0;
+    for digit in decimal_portion.chars() {
+      let digit = digit.to_digit(10).ok_or(FractionalAmountParseError::NonDigitInDecimalPortion)?;
+      units = units.checked_mul(10)
+        .and_then(|units| units.checked_add(digit.into()))
+        .ok_or(FractionalAmountParseError::AmountOutOfRange)?;
+    }
+    let scale = options.precision.scale();
+    units = units.checked_mul(scale.into()).ok_or(FractionalAmountParseError::AmountOutOfRange)?;
+    if let Some(fractional_portion) = fractional_portion {
+      if fractional_portion.is_empty() && options.strict {
+        return Err(FractionalAmountParseError::EmptyFractionalPortion);
+      }
+      let decimal_places = usize::from(options.precision.decimal_places());
+      let mut magnitude = scale / 10;
+      // The first digit past the precision, and whether any of the digits after it are non-zero.
+      let mut first_excess_digit = 0;
+      let mut more_excess_digits = false;
+      for (i, digit) in fractional_portion.chars().enumerate() {
+        let digit = digit.to_digit(10).ok_or(FractionalAmountParseError::NonDigitInFractionalPortion)?;
+        if i < decimal_places {
+          units += i128::from(i64::from(digit) * magnitude);
+          magnitude /= 10;
+        } else if i == decimal_places {
+          first_excess_digit = digit;
+        } else {
+          more_excess_digits |= digit != 0;
+        }
+        // XXX: After we finish reading the digits that we care about
+        //      in terms of precision, we do not break out of the loop. The reason for this
+        //      is that we still want to ensure that all remaining characters are digits.
+      }
+      let round_up = match options.rounding {
+        RoundingMode::Truncate => false,
+        RoundingMode::HalfUp => first_excess_digit >= 5,
+        RoundingMode::HalfEven => first_excess_digit > 5 || (first_excess_digit == 5 && (more_excess_digits || units % 2 == 1)),
+        RoundingMode::Reject if first_excess_digit != 0 || more_excess_digits => {
+          return Err(FractionalAmountParseError::ExcessPrecision);
+        },
+        RoundingMode::Reject => false,
+      };
+      if round_up {
+        units += 1;
+      }
+    }
+    if negative {
+      units = -units;
+    }
+    i64::try_from(units)
+      .map(|units| FractionalAmount::new(units, options.precision))
+      .map_err(|_| FractionalAmountParseError::AmountOutOfRange)
+  }
+}
+
+/// Errors returned by [FractionalAmount::parse], and for [TryInto::try_into]::<[FractionalAmount]> on &[str].
+#[derive(Error, Debug)]
+pub enum FractionalAmountParseError {
+  #[error("Amount has no digits before the decimal point")]
+  MissingDigits,
+  #[error("Non-digit in decimal portion of amount")]
+  NonDigitInDecimalPortion,
+  #[error("Non-digit in fractional portion of amount")]
+  NonDigitInFractionalPortion,
+  #[error("Amount has a decimal point but no fractional portion")]
+  EmptyFractionalPortion,
+  #[error("Amount is out of range")]
+  AmountOutOfRange,
+  #[error("Amount has more decimal places than the precision allows")]
+  ExcessPrecision,
+}
+
+/// Balance of an account in a single currency.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Balance {
+  available_amount: FractionalAmount,
+  held_amount: FractionalAmount,
+}
+
+impl Balance {
+  /// Creates an empty balance, with amounts of zero at the given precision.
+  fn new (precision: Precision) -> Balance
+  {
+    Balance {
+      available_amount: FractionalAmount::zero(precision),
+      held_amount: FractionalAmount::zero(precision),
+    }
+  }
+  pub fn get_available (&self) -> FractionalAmount {
+    self.available_amount
+  }
+  pub fn get_held (&self) -> FractionalAmount {
+    self.held_amount
+  }
+  pub fn get_total (&self) -> FractionalAmount {
+    // XXX: The unwrap is fine because the amounts of a balance are only ever
+    //      updated through Self::checked_apply, which ensures that the total does not overflow.
+    self.available_amount.checked_add(self.held_amount).unwrap()
+  }
+  /// Returns the balance that results from adding the given changes to the available
+  /// and held amounts of the balance.
+  ///
+  /// Returns `None` if the available, held or total amount would overflow.
+  fn checked_apply (mut self, available_change: FractionalAmount, held_change: FractionalAmount) -> Option<Balance>
+  {
+    self.available_amount = self.available_amount.checked_add(available_change)?;
+    self.held_amount = self.held_amount.checked_add(held_change)?;
+    self.available_amount.checked_add(self.held_amount)?;
+    Some(self)
+  }
+}
+
+/// Balances are serialized with the same fields as the amounts in the CSV output specified in the spec.
+impl Serialize for Balance {
+  fn serialize<S: Serializer> (&self, serializer: S) -> Result<S::Ok, S::Error>
+  {
+    let mut state = serializer.serialize_struct("Balance", 3)?;
+    state.serialize_field("available", &self.available_amount)?;
+    state.serialize_field("held", &self.held_amount)?;
+    state.serialize_field("total", &self.get_total())?;
+    state.end()
+  }
+}
+
+/// Contains the account data for a single user.
+///
+/// An account has a separate [Balance] for each currency that the user has made
+/// transactions in. Being frozen on the other hand applies to the account as a whole.
+#[derive(Debug, Clone)]
+pub struct Account {
+  balances: BTreeMap<Currency, Balance>,
+  frozen: bool,
+  /// The precision of amounts in the default currency, as given by the [ProcessorPolicy],
+  /// for when the user has not made any transactions in the default currency.
+  default_precision: Precision,
+}
+
+impl Account {
+  /// Creates an account without any balances, with the given precision for the default currency.
+  fn new (default_precision: Precision) -> Account
+  {
+    Account {
+      balances: BTreeMap::new(),
+      frozen: false,
+      default_precision,
+    }
+  }
+  pub fn is_frozen (&self) -> bool {
+    self.frozen
+  }
+  /// The available amount in the default currency.
+  pub fn get_available (&self) -> FractionalAmount {
+    self.default_balance().get_available()
+  }
+  /// The held amount in the default currency.
+  pub fn get_held (&self) -> FractionalAmount {
+    self.default_balance().get_held()
+  }
+  /// The total amount in the default currency.
+  pub fn get_total (&self) -> FractionalAmount {
+    self.default_balance().get_total()
+  }
+  /// The balance in the given currency, if the user has made transactions in it.
+  pub fn balance (&self, currency: Currency) -> Option<&Balance>
+  {
+    self.balances.get(&currency)
+  }
+  /// The balances in all currencies that the user has made transactions in, ordered by currency.
+  pub fn balances (&self) -> impl Iterator<Item = (Currency, &Balance)>
+  {
+    self.balances.iter().map(|(&currency, balance)| (currency, balance))
+  }
+  /// The balance in the default currency, which is empty if the user
+  /// has not made any transactions in the default currency.
+  fn default_balance (&self) -> Balance
+  {
+    self.balances.get(&Currency::DEFAULT).copied().unwrap_or_else(|| Balance::new(self.default_precision))
+  }
+}
+
+/// Accounts are serialized with the same fields as the account data in the CSV output
+/// specified in the spec, except for the client ID, which is not part of the account.
+/// The amounts are those of the default currency. Balances in other currencies, if any,
+/// are serialized in an additional `currencies` field, keyed by currency.
+///
+/// ## Examples
+///
+/// ```
+/// use transaction_engine::{TransactionProcessor, ProcessorPolicy, ClientId, TransactionId, Precision};
+/// let mut transaction_processor = TransactionProcessor::new();
+/// let client_a = ClientId::from(1u16);
+/// transaction_processor.deposit(client_a, TransactionId::from(1u32), "1.5".try_into().unwrap()).unwrap();
+/// let account = transaction_processor.account(client_a).unwrap();
+/// assert_eq!(serde_json::to_string(account).unwrap(),
+///   r#"{"available":"1.5000","held":"0.0000","total":"1.5000","locked":false}"#);
+///
+/// let mut policy = ProcessorPolicy::default();
+/// policy.currencies.insert("BTC".parse().unwrap(), Precision::new(8).unwrap());
+/// let mut transaction_processor = TransactionProcessor::with_policy(policy).unwrap();
+/// let amount = transaction_engine::FractionalAmount::new(1, Precision::new(8).unwrap());
+/// transaction_processor.deposit_in(client_a, TransactionId::from(1u32), "BTC".parse().unwrap(), amount).unwrap();
+/// let account = transaction_processor.account(client_a).unwrap();
+/// assert_eq!(serde_json::to_string(account).unwrap(),
+///   r#"{"available":"0.0000","held":"0.0000","total":"0.0000","locked":false,"currencies":{"BTC":{"available":"0.00000001","held":"0.00000000","total":"0.00000001"}}}"#);
+///
+/// // Without any transactions in the default currency, the amounts of the default currency
+/// // are still at the precision of the policy.
+/// let mut policy = ProcessorPolicy { precision: Precision::new(2).unwrap(), ..Default::default() };
+/// policy.currencies.insert("BTC".parse().unwrap(), Precision::new(8).unwrap());
+/// let mut transaction_processor = TransactionProcessor::with_policy(policy).unwrap();
+/// transaction_processor.deposit_in(client_a, TransactionId::from(1u32), "BTC".parse().unwrap(), amount).unwrap();
+/// let account = transaction_processor.account(client_a).unwrap();
+/// assert_eq!(account.get_available().to_string(), "0.00");
+/// ```
+impl Serialize for Account {
+  fn serialize<S: Serializer> (&self, serializer: S) -> Result<S::Ok, S::Error>
+  {
+    let default_balance = self.default_balance();
+    let mut state = serializer.serialize_struct("Account", 5)?;
+    state.serialize_field("available", &default_balance.available_amount)?;
+    state.serialize_field("held", &default_balance.held_amount)?;
+    state.serialize_field("total", &default_balance.get_total())?;
+    state.serialize_field("locked", &self.frozen)?;
+    if self.balances.keys().any(|&currency| currency != Currency::DEFAULT) {
+      state.serialize_field("currencies", &OtherBalances(&self.balances))?;
+    } else {
+      state.skip_field("currencies")?;
+    }
+    state.end()
+  }
+}
+
+/// Helper for serializing the balances of an [Account] in currencies other than the default currency.
+struct OtherBalances<'a>(&'a BTreeMap<Currency, Balance>);
+
+impl Serialize for OtherBalances<'_> {
+  fn serialize<S: Serializer> (&self, serializer: S) -> Result<S::Ok, S::Error>
+  {
+    serializer.collect_map(self.0.iter().filter(|(&currency, _)| currency != Currency::DEFAULT))
+  }
+}
+
+/// Contains the accounts of all users for which we have processed valid transactions.
+pub type Accounts = HashMap<ClientId, Account>;
+
+/// Order of the balances returned by [TransactionProcessor::sorted_balances].
+///
+/// Balances are in ascending order. Balances with the same amount are ordered by client ID
+/// and then by currency, so that the same balances always end up in the same order.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AccountOrder {
+  /// By client ID.
+  Client,
+  /// By total amount.
+  Total,
+  /// By available amount.
+  Available,
+}
+
+impl FromStr for AccountOrder {
+  type Err = ParseAccountOrderError;
+  fn from_str (s: &str) -> Result<Self, Self::Err>
+  {
+    match s {
+      "client" => Ok(AccountOrder::Client),
+      "total" => Ok(AccountOrder::Total),
+      "available" => Ok(AccountOrder::Available),
+      _ => Err(ParseAccountOrderError(s.to_owned())),
+    }
+  }
+}
+
+/// Error returned when parsing an [AccountOrder] from a string fails.
+#[derive(Error, Debug)]
+#[error("Invalid account order {0:?}; expected \"client\", \"total\" or \"available\"")]
+pub struct ParseAccountOrderError(String);
+
+/// The balance of a client in a single currency, as returned by [TransactionProcessor::balances].
+#[derive(Debug, Copy, Clone)]
+pub struct ClientBalance {
+  pub client: ClientId,
+  pub currency: Currency,
+  pub balance: Balance,
+  /// Whether the account of the client is frozen.
+  pub frozen: bool,
+}
+
+/// A transaction, as submitted by a client.
+///
+/// This is the common representation of transactions for all input formats,
+/// and it is processed with [TransactionProcessor::process].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Transaction {
+  /// The client that submitted the transaction.
+  pub client: ClientId,
+  /// For deposits, withdrawals and transfers, the ID of the transaction itself. For disputes,
+  /// resolves and chargebacks, the ID of the transaction that is referenced.
+  /// For locks and unlocks, an ID that identifies the action in the outcome log,
+  /// which is not checked for uniqueness.
+  pub tx: TransactionId,
+  /// For deposits, withdrawals and transfers, the currency of the amount. Disputes, resolves and
+  /// chargebacks always apply to the currency of the transaction that is referenced,
+  /// and locks and unlocks apply to all currencies, so for them the currency is
+  /// the default currency.
+  pub currency: Currency,
+  /// The kind of transaction.
+  pub kind: TransactionKind,
+  /// When the transaction was submitted, as a number of seconds since the Unix epoch, if known.
+  ///
+  /// Timestamps are only used for a [DisputeWindow::Seconds], and they are expected to be
+  /// non-decreasing in the order in which transactions are processed. See [TransactionProcessor::advance_time].
+  pub timestamp: Option<u64>,
+}
+
+impl Transaction {
+  /// Creates a transaction in the default currency.
+  pub fn new (client: ClientId, tx: TransactionId, kind: TransactionKind) -> Self
+  {
+    Self::with_currency(client, tx, Currency::DEFAULT, kind)
+  }
+  /// Creates a transaction in the given currency.
+  pub fn with_currency (client: ClientId, tx: TransactionId, currency: Currency, kind: TransactionKind) -> Self
+  {
+    Self { client, tx, currency, kind, timestamp: None }
+  }
+  /// Returns the transaction with the given timestamp.
+  pub fn at (self, timestamp: u64) -> Self
+  {
+    Self { timestamp: Some(timestamp), ..self }
+  }
+}
+
+/// Transaction type and, in the case of deposits, withdrawals and transfers, the amount for the transaction.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum TransactionKind {
+  Deposit(FractionalAmount),
+  Withdrawal(FractionalAmount),
+  /// Transfer of the amount from the account of the client to the account of another client.
+  Transfer { to: ClientId, amount: FractionalAmount },
+  Dispute,
+  Resolve,
+  Chargeback,
+  /// Manual freeze of an account by support staff, for the given reason.
+  Lock(Reason),
+  /// Manual unfreeze of an account by support staff, for the given reason,
+  /// such as after a chargeback has been investigated.
+  Unlock(Reason),
+}
+
+/// Result of a transaction that was successfully processed by [TransactionProcessor::process].
+#[derive(Debug, Clone)]
+pub struct Outcome {
+  /// The currency of the balance that the transaction was applied to. For disputes, resolves
+  /// and chargebacks, this is the currency of the transaction that is referenced.
+  pub currency: Currency,
+  /// The account of the client, after the transaction was applied.
+  pub account: Account,
+}
+
+/// Rules that decide which transactions [TransactionProcessor] accepts.
+///
+/// The default policy corresponds to the assumptions listed in the main readme file:
+///
+/// * Users can dispute deposits, but they cannot dispute withdrawals.
+/// * A frozen account cannot withdraw money.
+/// * A frozen account is still able to deposit money.
+/// * A frozen account is also still able to dispute, resolve and chargeback.
+///
+/// Individual rules can be switched by overriding the corresponding fields.
+///
+/// ## Examples
+///
+/// ```
+/// use transaction_engine::{TransactionProcessor, ProcessorPolicy, ClientId, TransactionId};
+/// use transaction_engine::TransactionDepositError;
+///
+/// let policy = ProcessorPolicy {
+///   frozen_accounts_can_deposit: false,
+///   ..Default::default()
+/// };
+/// let mut transaction_processor = TransactionProcessor::with_policy(policy).unwrap();
+///
+/// let client_a = ClientId::from(1u16);
+/// let amount = "1.5".try_into().unwrap();
+///
+/// transaction_processor.deposit(client_a, TransactionId::from(1u32), amount).unwrap();
+/// transaction_processor.dispute(client_a, TransactionId::from(1u32)).unwrap();
+/// transaction_processor.chargeback(client_a, TransactionId::from(1u32)).unwrap();
+///
+/// let res = transaction_processor.deposit(client_a, TransactionId::from(2u32), amount);
+/// assert!(matches!(res, Err(TransactionDepositError::CannotDepositToFrozenAccount)));
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ProcessorPolicy {
+  /// Whether deposits into frozen accounts are accepted.
+  pub frozen_accounts_can_deposit: bool,
+  /// Whether withdrawals from frozen accounts are accepted.
+  pub frozen_accounts_can_withdraw: bool,
+  /// Whether transactions of frozen accounts can be disputed.
+  pub frozen_accounts_can_dispute: bool,
+  /// Whether disputes on frozen accounts can be resolved.
+  pub frozen_accounts_can_resolve: bool,
+  /// Whether disputes on frozen accounts can be charged back.
+  pub frozen_accounts_can_chargeback: bool,
+  /// Whether withdrawals can be disputed, in addition to deposits.
+  ///
+  /// When enabled, withdrawals are retained by the transaction processor
+  /// the same way that deposits are. See [TransactionProcessor::dispute]
+  /// for how a disputed withdrawal affects the account of the client.
+  pub withdrawals_can_be_disputed: bool,
+  /// The precision of the amounts of deposits and withdrawals in the default currency,
+  /// and of the balances of accounts in the default currency.
+  ///
+  /// Deposits and withdrawals with amounts of a different precision are rejected,
+  /// rather than rounded to this precision.
+  pub precision: Precision,
+  /// The currencies other than the default currency that are accepted, along with
+  /// the precision of amounts in each currency. Deposits and withdrawals in other
+  /// currencies are rejected.
+  pub currencies: HashMap<Currency, Precision>,
+  /// How long deposits, withdrawals and transfers can be disputed for, if not forever.
+  ///
+  /// Once a transaction is outside of the dispute window, it is no longer retained by the
+  /// transaction processor, and disputes that reference it are rejected with
+  /// [TransactionDisputeError::DisputeWindowExpired]. A transaction that is under dispute
+  /// when it falls outside of the dispute window can still be resolved or charged back,
+  /// but it cannot be disputed again after it has been resolved.
+  pub dispute_window: Option<DisputeWindow>,
+  /// Where retained transactions, disputes and the IDs of transactions are kept.
+  ///
+  /// This does not change which transactions are accepted, but it decides how much memory
+  /// the transaction processor needs for inputs with many transactions. See [store].
+  pub storage: Storage,
+}
+
+impl ProcessorPolicy {
+  /// The precision of amounts in the given currency, or `None` if the currency is not accepted.
+  pub fn precision_of (&self, currency: Currency) -> Option<Precision>
+  {
+    match currency {
+      Currency::DEFAULT => Some(self.precision),
+      currency => self.currencies.get(&currency).copied(),
+    }
+  }
+}
+
+impl Default for ProcessorPolicy {
+  fn default () -> Self
+  {
+    Self {
+      frozen_accounts_can_deposit: true,
+      frozen_accounts_can_withdraw: false,
+      frozen_accounts_can_dispute: true,
+      frozen_accounts_can_resolve: true,
+      frozen_accounts_can_chargeback: true,
+      withdrawals_can_be_disputed: false,
+      precision: Precision::DEFAULT,
+      currencies: Default::default(),
+      dispute_window: None,
+      storage: Storage::Memory,
+    }
+  }
+}
+
+/// How long deposits, withdrawals and transfers can be disputed for. See [ProcessorPolicy::dispute_window].
+///
+/// ## Examples
+///
+/// ```
+/// use std::num::{NonZeroU64, NonZeroUsize};
+/// use transaction_engine::DisputeWindow;
+///
+/// assert_eq!("1000tx".parse::<DisputeWindow>().unwrap(), DisputeWindow::Transactions(NonZeroUsize::new(1000).unwrap()));
+/// assert_eq!("90d".parse::<DisputeWindow>().unwrap(), DisputeWindow::Seconds(NonZeroU64::new(90 * 24 * 60 * 60).unwrap()));
+/// assert!("90".parse::<DisputeWindow>().is_err());
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DisputeWindow {
+  /// A transaction can be disputed until the given number of newer deposits, withdrawals
+  /// and transfers have been retained for the same client. In other words, each client
+  /// can only dispute their most recent transactions.
+  Transactions(NonZeroUsize),
+  /// A transaction can be disputed until the given number of seconds have passed since
+  /// it was applied, going by the timestamps of the transactions that are processed.
+  /// See [TransactionProcessor::advance_time].
+  Seconds(NonZeroU64),
+}
+
+/// Parses a number followed by `tx` for a number of transactions, or by `s`, `m`, `h` or `d`
+/// for a number of seconds, minutes, hours or days.
+impl FromStr for DisputeWindow {
+  type Err = ParseDisputeWindowError;
+  fn from_str (s: &str) -> Result<Self, Self::Err>
+  {
+    let err = || ParseDisputeWindowError(s.to_owned());
+    let split = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(err)?;
+    let (number, unit) = s.split_at(split);
+    if unit == "tx" {
+      return number.parse().map(DisputeWindow::Transactions).map_err(|_| err());
+    }
+    let seconds = match unit {
+      "s" => 1,
+      "m" => 60,
+      "h" => 60 * 60,
+      "d" => 24 * 60 * 60,
+      _ => return Err(err()),
+    };
+    number.parse::<u64>().ok()
+      .and_then(|number| number.checked_mul(seconds))
+      .and_then(NonZeroU64::new)
+      .map(DisputeWindow::Seconds)
+      .ok_or_else(err)
+  }
+}
+
+/// Error returned when parsing a [DisputeWindow] from a string fails.
+#[derive(Error, Debug)]
+#[error("Invalid dispute window {0:?}; expected a number followed by \"tx\" for transactions, or by \"s\", \"m\", \"h\" or \"d\" for time")]
+pub struct ParseDisputeWindowError(String);
+
+/// A transaction that is currently under dispute, along with the currency and the amount of the transaction.
+/// For transfers, the client that the amount was transferred from comes first.
+#[derive(Debug, Copy, Clone)]
+enum DisputedTransaction {
+  Deposit(Currency, FractionalAmount),
+  Withdrawal(Currency, FractionalAmount),
+  Transfer(ClientId, Currency, FractionalAmount),
+}
+
+impl DisputedTransaction {
+  fn currency (self) -> Currency
+  {
+    match self {
+      Self::Deposit(currency, _) | Self::Withdrawal(currency, _) | Self::Transfer(_, currency, _) => currency,
+    }
+  }
+}
+
+/// Retained transactions of a kind, or their disputes, keyed by the client that can dispute them.
+type RetainedTransactions<V> = Box<dyn TransactionStore<(ClientId, TransactionId), V>>;
+
+/// A change to the stores of a [TransactionProcessor] that is made by an accepted transaction.
+///
+/// The changes of a transaction are made before the transaction is written to the [journal]
+/// and applied to the balances, and are undone if any of them fails or the journal fails,
+/// so that a transaction that fails leaves the transaction processor as it was.
+#[derive(Debug, Copy, Clone)]
+enum StoreChange {
+  /// Retains a deposit, withdrawal or transfer in case it gets disputed.
+  Retain((ClientId, TransactionId), DisputedTransaction),
+  /// Stops retaining a deposit, withdrawal or transfer, because it gets disputed.
+  Release((ClientId, TransactionId), DisputedTransaction),
+  /// Opens the dispute of a transaction.
+  OpenDispute((ClientId, TransactionId), DisputedTransaction),
+  /// Closes the dispute of a transaction, because it gets resolved or charged back.
+  CloseDispute((ClientId, TransactionId), DisputedTransaction),
+  /// Forgets that a transaction fell outside of the dispute window while under dispute,
+  /// because the dispute gets resolved or charged back.
+  ForgetExpired((ClientId, TransactionId)),
+  /// Records that a transaction can no longer be disputed, because it gets charged back.
+  Finalize((ClientId, TransactionId)),
+  /// Uses up the ID of a deposit, withdrawal or transfer, along with the client that can dispute it, if any.
+  UseTransactionId(TransactionId, Option<ClientId>),
+}
+
+/// Processes transactions and provides final balances for accounts for which
+/// transactions have been processed.
+pub struct TransactionProcessor {
+  policy: ProcessorPolicy,
+  accounts: Accounts,
+  /// Contains deposit transactions we have seen and which we are holding onto until,
+  /// if ever, they get disputed.
+  ///
+  /// When a transaction is disputed by the user, we remove the transaction from the
+  /// collection of deposit transactions and put an entry for the transaction
+  /// in the collection of disputed transactions instead.
+  ///
+  /// A transaction is put back into the collection of deposit transactions
+  /// if the transaction is subsequently resolved after having been disputed.
+  /// This is done in case there are any future disputes about the transaction.
+  ///
+  /// Meanwhile, if the transaction changes state from disputed to charged back,
+  /// then the transaction will not be put back into the deposit transactions
+  /// collection, as a transaction that has been charged back cannot be disputed again.
+  deposit_transactions: RetainedTransactions<(Currency, FractionalAmount)>,
+  /// Contains withdrawal transactions we have seen and which we are holding onto until,
+  /// if ever, they get disputed.
+  ///
+  /// Withdrawals are only retained when [ProcessorPolicy::withdrawals_can_be_disputed]
+  /// is enabled. They otherwise move between this collection and the collection of
+  /// disputed transactions in the same way as deposits do.
+  withdrawal_transactions: RetainedTransactions<(Currency, FractionalAmount)>,
+  /// Contains transfers we have seen and which we are holding onto until, if ever,
+  /// they get disputed, along with the client that the amount was transferred from.
+  ///
+  /// Transfers are disputed by the client that the amount was transferred to, the same
+  /// way as deposits are, so they are keyed by that client. They move between this
+  /// collection and the collection of disputed transactions in the same way as deposits do.
+  transfer_transactions: RetainedTransactions<(ClientId, Currency, FractionalAmount)>,
+  /// Contains dispute transactions we have seen and which we are holding onto until,
+  /// if ever, they either get resolved or charged back.
+  ///
+  /// See also the description on the deposit transactions field for details about
+  /// what happens to a transaction after it has been disputed and then it has
+  /// either been resolved or charged back.
+  dispute_transactions: RetainedTransactions<DisputedTransaction>,
+  /// Contains the IDs of all deposit, withdrawal and transfer transactions that we have accepted,
+  /// along with the client that can dispute the transaction. The client is `None` for withdrawals
+  /// that are not retained and for transactions that have been charged back.
+  ///
+  /// Transaction IDs are globally unique as per spec, so we track them across all clients
+  /// and not just per client. This allows us to detect transactions that are delivered
+  /// to us more than once, which would otherwise credit or debit the account again.
+  ///
+  /// A transaction that can be disputed by a client, but that is neither retained nor under dispute,
+  /// has been evicted because it fell outside of the dispute window. This allows us to tell disputes
+  /// that reference such transactions apart from disputes that reference transactions we have never seen.
+  transaction_ids: Box<dyn TransactionStore<TransactionId, Option<ClientId>>>,
+  /// The order in which deposits, withdrawals and transfers were retained for each client,
+  /// oldest first, so that they can be evicted once they are outside of a
+  /// [DisputeWindow::Transactions]. Empty for other dispute windows.
+  ///
+  /// Transactions stay in here while they are under dispute and after they have been
+  /// charged back, until they are the oldest of the client.
+  retained_per_client: HashMap<ClientId, VecDeque<TransactionId>>,
+  /// The order in which deposits, withdrawals and transfers were retained, oldest first,
+  /// along with the time they were retained at, so that they can be evicted once they are
+  /// outside of a [DisputeWindow::Seconds]. Empty for other dispute windows.
+  retained_by_time: VecDeque<(u64, ClientId, TransactionId)>,
+  /// Transactions that have fallen outside of the dispute window, in the same form as in
+  /// [Self::retained_by_time], which have not been evicted yet because evicting them failed.
+  /// See [Self::evict_pending].
+  pending_evictions: VecDeque<(u64, ClientId, TransactionId)>,
+  /// Contains the transactions under dispute that have fallen outside of the dispute window,
+  /// so that they are not retained again if the dispute is resolved.
+  ///
+  /// A transaction is removed from here once its dispute is resolved or charged back,
+  /// so there are never more of these than there are open disputes.
+  expired_transactions: RetainedTransactions<()>,
+  /// The latest timestamp of the transactions that have been processed. See [Self::advance_time].
+  time: u64,
+  /// Number of transactions that have been accepted and applied.
+  ///
+  /// This is used as the sequence number of transactions in the [journal],
+  /// so that it is known which entries of a journal are already reflected in a snapshot.
+  applied_transactions: u64,
+  /// Journal that accepted transactions are written to before they are applied, if any.
+  journal: Option<Journal>,
+  /// Transfers that can no longer be charged back because they fell outside of the dispute window,
+  /// along with their sending client, if these are kept track of. Only the workers of the [sharded]
+  /// module keep track of them, so that they can let go of transfers between clients of different workers.
+  forgotten_transfers: Option<Vec<((ClientId, TransactionId), ClientId)>>,
+}
+
+/// Processes deposit, withdraw, transfer, dispute, resolve and chargeback transactions.
+impl TransactionProcessor {
+  /// Creates a transaction processor that uses the default [ProcessorPolicy].
+  pub fn new () -> Self
+  {
+    // XXX: The unwrap is fine because the default policy keeps transactions in memory,
+    //      and stores in memory cannot fail to be created.
+    Self::with_policy(Default::default()).unwrap()
+  }
+  /// Creates a transaction processor that uses the given [ProcessorPolicy].
+  ///
+  /// Fails if the stores for retained transactions cannot be created, which can only
+  /// happen for [store::Storage::Disk].
+  pub fn with_policy (policy: ProcessorPolicy) -> std::io::Result<Self>
+  {
+    Ok(Self {
+      accounts: Default::default(),
+      deposit_transactions: policy.storage.create()?,
+      withdrawal_transactions: policy.storage.create()?,
+      transfer_transactions: policy.storage.create()?,
+      dispute_transactions: policy.storage.create()?,
+      transaction_ids: policy.storage.create()?,
+      retained_per_client: Default::default(),
+      retained_by_time: Default::default(),
+      pending_evictions: Default::default(),
+      expired_transactions: policy.storage.create()?,
+      policy,
+      time: 0,
+      applied_transactions: 0,
+      journal: None,
+      forgotten_transfers: None,
+    })
+  }
+  /// Processes a transaction of any kind, by dispatching it to the method
+  /// for the kind of transaction.
+  ///
+  /// On success, returns the account of the client as it is after the transaction.
+  ///
+  /// ## Examples
+  ///
+  /// ```
+  /// use transaction_engine::{TransactionProcessor, Transaction, TransactionKind, TransactionError, ClientId, TransactionId};
+  ///
+  /// let mut transaction_processor = TransactionProcessor::new();
+  ///
+  /// let client_a = ClientId::from(1u16);
+  /// let tx_1 = TransactionId::from(1u32);
+  /// let deposit = Transaction::new(client_a, tx_1, TransactionKind::Deposit("1.5".try_into().unwrap()));
+  /// let dispute = Transaction::new(client_a, tx_1, TransactionKind::Dispute);
+  ///
+  /// let outcome = transaction_processor.process(deposit.clone()).unwrap();
+  /// assert_eq!(outcome.account.get_available().to_string(), "1.5000");
+  /// let outcome = transaction_processor.process(dispute).unwrap();
+  /// assert_eq!(outcome.account.get_held().to_string(), "1.5000");
+  ///
+  /// let res = transaction_processor.process(deposit);
+  /// assert!(matches!(res, Err(TransactionError::Deposit(_))));
+  /// ```
+  pub fn process (&mut self, transaction: Transaction) -> Result<Outcome, TransactionError>
+  {
+    let Transaction { client, tx, currency, kind, timestamp } = transaction;
+    if let Some(timestamp) = timestamp {
+      self.advance_time(timestamp).map_err(|e| TransactionError::storage(&kind, e))?;
+    }
+    // XXX: The currency of the referenced transaction is looked up before the transaction
+    //      is applied, because a chargeback removes the referenced transaction.
+    let currency = match kind {
+      TransactionKind::Deposit(_) | TransactionKind::Withdrawal(_) | TransactionKind::Transfer { .. } => currency,
+      TransactionKind::Dispute | TransactionKind::Resolve | TransactionKind::Chargeback =>
+        self.referenced_currency(client, tx).map_err(|e| TransactionError::storage(&kind, e))?.unwrap_or(Currency::DEFAULT),
+      TransactionKind::Lock(_) | TransactionKind::Unlock(_) => Currency::DEFAULT,
+    };
+    match kind {
+      TransactionKind::Deposit(amount) => self.deposit_in(client, tx, currency, amount)?,
+      TransactionKind::Withdrawal(amount) => self.withdraw_in(client, tx, currency, amount)?,
+      TransactionKind::Transfer { to, amount } => self.transfer_in(client, tx, to, currency, amount)?,
+      TransactionKind::Dispute => self.dispute(client, tx)?,
+      TransactionKind::Resolve => self.resolve(client, tx)?,
+      TransactionKind::Chargeback => self.chargeback(client, tx)?,
+      TransactionKind::Lock(reason) => self.lock(client, tx, reason)?,
+      TransactionKind::Unlock(reason) => self.unlock(client, tx, reason)?,
+    }
+    Ok(self.outcome(client, currency))
+  }
+  /// Returns the account of the client, if the client has one.
+  pub fn account (&self, client_id: ClientId) -> Option<&Account>
+  {
+    self.accounts.get(&client_id)
+  }
+  /// Returns the balances of all clients in all currencies, with one entry for each
+  /// combination of client and currency that the client has made transactions in.
+  ///
+  /// Like [Accounts], the balances are in no particular order.
+  pub fn balances (&self) -> impl Iterator<Item = ClientBalance> + '_
+  {
+    self.accounts.iter().flat_map(|(&client, acc)| acc.balances().map(move |(currency, &balance)| ClientBalance {
+      client,
+      currency,
+      balance,
+      frozen: acc.frozen,
+    }))
+  }
+  /// Returns the balances of all clients in all currencies in a stable order,
+  /// unlike [Self::balances]. See [AccountOrder].
+  ///
+  /// ## Examples
+  ///
+  /// ```
+  /// use transaction_engine::{TransactionProcessor, AccountOrder, ClientId, TransactionId};
+  ///
+  /// let mut transaction_processor = TransactionProcessor::new();
+  /// transaction_processor.deposit(ClientId::from(2u16), TransactionId::from(1u32), "1.5".try_into().unwrap()).unwrap();
+  /// transaction_processor.deposit(ClientId::from(3u16), TransactionId::from(2u32), "0.5".try_into().unwrap()).unwrap();
+  /// transaction_processor.deposit(ClientId::from(1u16), TransactionId::from(3u32), "1.5".try_into().unwrap()).unwrap();
+  ///
+  /// let client_ids = |order| transaction_processor.sorted_balances(order).into_iter()
+  ///   .map(|balance| u16::from(balance.client))
+  ///   .collect::<Vec<_>>();
+  /// assert_eq!(client_ids(AccountOrder::Client), vec![1, 2, 3]);
+  /// assert_eq!(client_ids(AccountOrder::Total), vec![3, 1, 2]);
+  /// ```
+  pub fn sorted_balances (&self, order: AccountOrder) -> Vec<ClientBalance>
+  {
+    let mut balances: Vec<_> = self.balances().collect();
+    match order {
+      AccountOrder::Client => balances.sort_unstable_by_key(|b| (b.client, b.currency)),
+      AccountOrder::Total => balances.sort_unstable_by_key(|b| (b.balance.get_total(), b.client, b.currency)),
+      AccountOrder::Available => balances.sort_unstable_by_key(|b| (b.balance.get_available(), b.client, b.currency)),
+    }
+    balances
+  }
+  /// Credit to client's account, in the default currency.
+  pub fn deposit (&mut self, client_id: ClientId, transaction_id: TransactionId, amount: FractionalAmount) -> Result<(), TransactionDepositError>
+  {
+    self.deposit_in(client_id, transaction_id, Currency::DEFAULT, amount)
+  }
+  /// Credit to client's account, in the given currency.
+  ///
+  /// The currency must be accepted by the [ProcessorPolicy], and the amount must have
+  /// the precision of the currency.
+  pub fn deposit_in (&mut self, client_id: ClientId, transaction_id: TransactionId, currency: Currency, amount: FractionalAmount) -> Result<(), TransactionDepositError>
+  {
+    if amount.units < 0 {
+      return Err(TransactionDepositError::CannotDepositANegativeAmount);
+    }
+    let precision = self.policy.precision_of(currency).ok_or(TransactionDepositError::UnknownCurrency)?;
+    if amount.precision != precision {
+      return Err(TransactionDepositError::PrecisionMismatch);
+    }
+    self.evict_pending()?;
+    if self.transaction_ids.contains_key(&transaction_id)? {
+      return Err(TransactionDepositError::DuplicateTransactionId);
+    }
+    if !self.policy.frozen_accounts_can_deposit && self.is_frozen(client_id) {
+      return Err(TransactionDepositError::CannotDepositToFrozenAccount);
+    }
+    let balance = self.balance_or_new(client_id, currency, precision)
+      .checked_apply(amount, FractionalAmount::ZERO).ok_or(TransactionDepositError::AmountOverflow)?;
+    let k = (client_id, transaction_id);
+    self.commit::<TransactionDepositError>(&Transaction::with_currency(client_id, transaction_id, currency, TransactionKind::Deposit(amount)), &[
+      StoreChange::UseTransactionId(transaction_id, Some(client_id)),
+      StoreChange::Retain(k, DisputedTransaction::Deposit(currency, amount)),
+    ])?;
+    self.set_balance(client_id, currency, balance);
+    self.retain(k);
+    Ok(())
+  }
+  /// Debit to client's account, in the default currency.
+  pub fn withdraw (&mut self, client_id: ClientId, transaction_id: TransactionId, amount: FractionalAmount) -> Result<(), TransactionWithdrawError>
+  {
+    self.withdraw_in(client_id, transaction_id, Currency::DEFAULT, amount)
+  }
+  /// Debit to client's account, in the given currency.
+  ///
+  /// The currency must be accepted by the [ProcessorPolicy], and the amount must have
+  /// the precision of the currency. Only the available amount in the given currency
+  /// can be withdrawn.
+  pub fn withdraw_in (&mut self, client_id: ClientId, transaction_id: TransactionId, currency: Currency, amount: FractionalAmount) -> Result<(), TransactionWithdrawError>
+  {
+    if amount.units < 0 {
+      return Err(TransactionWithdrawError::CannotWithdrawANegativeAmount);
+    }
+    let precision = self.policy.precision_of(currency).ok_or(TransactionWithdrawError::UnknownCurrency)?;
+    if amount.precision != precision {
+      return Err(TransactionWithdrawError::PrecisionMismatch);
+    }
+    self.evict_pending()?;
+    if self.transaction_ids.contains_key(&transaction_id)? {
+      return Err(TransactionWithdrawError::DuplicateTransactionId);
+    }
+    let balance = self.balance_or_new(client_id, currency, precision);
+    self.set_balance(client_id, currency, balance);
+    if self.is_frozen(client_id) && !self.policy.frozen_accounts_can_withdraw {
+      return Err(TransactionWithdrawError::CannotWithdrawFromFrozenAccount);
+    } else if balance.available_amount < amount {
+      return Err(TransactionWithdrawError::InsufficientAmountAvailableForWithdrawal);
+    }
+    let balance = balance.checked_apply(amount.negated(), FractionalAmount::ZERO).ok_or(TransactionWithdrawError::AmountOverflow)?;
+    let k = (client_id, transaction_id);
+    let retained = self.policy.withdrawals_can_be_disputed;
+    let changes = [StoreChange::UseTransactionId(transaction_id, retained.then_some(client_id)), StoreChange::Retain(k, DisputedTransaction::Withdrawal(currency, amount))];
+    let changes = if retained { &changes[..] } else { &changes[..1] };
+    self.commit::<TransactionWithdrawError>(&Transaction::with_currency(client_id, transaction_id, currency, TransactionKind::Withdrawal(amount)), changes)?;
+    self.set_balance(client_id, currency, balance);
+    if retained {
+      self.retain(k);
+    }
+    Ok(())
+  }
+  /// Transfer from one client's account to another client's account, in the default currency.
+  pub fn transfer (&mut self, from: ClientId, transaction_id: TransactionId, to: ClientId, amount: FractionalAmount) -> Result<(), TransactionTransferError>
+  {
+    self.transfer_in(from, transaction_id, to, Currency::DEFAULT, amount)
+  }
+  /// Transfer from one client's account to another client's account, in the given currency.
+  ///
+  /// The amount is debited from the available amount of the sending client and credited to
+  /// the available amount of the receiving client in a single step. If either side of the
+  /// transfer cannot be applied, the transfer is rejected and neither account is changed.
+  /// Like with withdrawals, only the available amount can be transferred, and transfers from
+  /// frozen accounts are rejected unless [ProcessorPolicy::frozen_accounts_can_withdraw] is
+  /// enabled. Like with deposits, transfers to frozen accounts are rejected unless
+  /// [ProcessorPolicy::frozen_accounts_can_deposit] is enabled.
+  ///
+  /// For the receiving client, a transfer is disputed, resolved and charged back the same way
+  /// as a deposit, by referencing the transfer with the ID of the receiving client. When the
+  /// transfer is charged back, the amount is credited back to the sending client.
+  ///
+  /// ## Examples
+  ///
+  /// ```
+  /// use transaction_engine::{TransactionProcessor, ClientId, TransactionId};
+  /// use transaction_engine::TransactionTransferError;
+  ///
+  /// let mut transaction_processor = TransactionProcessor::new();
+  /// let client_a = ClientId::from(1u16);
+  /// let client_b = ClientId::from(2u16);
+  /// let tx_2 = TransactionId::from(2u32);
+  ///
+  /// transaction_processor.deposit(client_a, TransactionId::from(1u32), "1.5".try_into().unwrap()).unwrap();
+  /// transaction_processor.transfer(client_a, tx_2, client_b, "1".try_into().unwrap()).unwrap();
+  ///
+  /// // Transfers of more than is available are rejected, without any effect on either account.
+  /// let res = transaction_processor.transfer(client_a, TransactionId::from(3u32), client_b, "1".try_into().unwrap());
+  /// assert!(matches!(res, Err(TransactionTransferError::InsufficientAmountAvailableForTransfer)));
+  ///
+  /// // The receiving client disputes the transfer, which holds the amount in their account.
+  /// transaction_processor.dispute(client_b, tx_2).unwrap();
+  /// assert_eq!(transaction_processor.account(client_b).unwrap().get_held().to_string(), "1.0000");
+  ///
+  /// // Charging back the transfer credits the amount back to the sending client.
+  /// transaction_processor.chargeback(client_b, tx_2).unwrap();
+  /// assert_eq!(transaction_processor.account(client_a).unwrap().get_available().to_string(), "1.5000");
+  /// assert_eq!(transaction_processor.account(client_b).unwrap().get_total().to_string(), "0.0000");
+  /// assert!(transaction_processor.account(client_b).unwrap().is_frozen());
+  /// ```
+  pub fn transfer_in (&mut self, from: ClientId, transaction_id: TransactionId, to: ClientId, currency: Currency, amount: FractionalAmount) -> Result<(), TransactionTransferError>
+  {
+    let credited_balance = self.check_transfer_credit(to, currency, amount)?;
+    let debited_balance = self.check_transfer_debit(from, transaction_id, to, currency, amount)?;
+    // XXX: The transfer is written to the journal as a single entry, which is replayed
+    //      with this method as well, so that replaying it applies both sides of the transfer.
+    let k = (to, transaction_id);
+    self.commit::<TransactionTransferError>(&Transaction::with_currency(from, transaction_id, currency, TransactionKind::Transfer { to, amount }), &[
+      StoreChange::UseTransactionId(transaction_id, Some(to)),
+      StoreChange::Retain(k, DisputedTransaction::Transfer(from, currency, amount)),
+    ])?;
+    self.set_balance(from, currency, debited_balance);
+    self.set_balance(to, currency, credited_balance);
+    self.retain(k);
+    Ok(())
+  }
+  /// Checks the amount of a transfer, and returns the precision of its currency.
+  fn transfer_precision (&self, currency: Currency, amount: FractionalAmount) -> Result<Precision, TransactionTransferError>
+  {
+    if amount.units < 0 {
+      return Err(TransactionTransferError::CannotTransferANegativeAmount);
+    }
+    let precision = self.policy.precision_of(currency).ok_or(TransactionTransferError::UnknownCurrency)?;
+    if amount.precision != precision {
+      return Err(TransactionTransferError::PrecisionMismatch);
+    }
+    Ok(precision)
+  }
+  /// Checks whether the receiving client of a transfer can be credited,
+  /// and returns the balance of the receiving client after the credit.
+  fn check_transfer_credit (&self, to: ClientId, currency: Currency, amount: FractionalAmount) -> Result<Balance, TransactionTransferError>
+  {
+    let precision = self.transfer_precision(currency, amount)?;
+    if !self.policy.frozen_accounts_can_deposit && self.is_frozen(to) {
+      return Err(TransactionTransferError::CannotTransferToFrozenAccount);
+    }
+    self.balance_or_new(to, currency, precision)
+      .checked_apply(amount, FractionalAmount::ZERO).ok_or(TransactionTransferError::AmountOverflow)
+  }
+  /// Checks whether the sending client of a transfer can be debited,
+  /// and returns the balance of the sending client after the debit.
+  fn check_transfer_debit (&mut self, from: ClientId, transaction_id: TransactionId, to: ClientId, currency: Currency, amount: FractionalAmount) -> Result<Balance, TransactionTransferError>
+  {
+    let precision = self.transfer_precision(currency, amount)?;
+    if from == to {
+      return Err(TransactionTransferError::CannotTransferToSameClient);
+    }
+    self.evict_pending()?;
+    if self.transaction_ids.contains_key(&transaction_id)? {
+      return Err(TransactionTransferError::DuplicateTransactionId);
+    }
+    if self.is_frozen(from) && !self.policy.frozen_accounts_can_withdraw {
+      return Err(TransactionTransferError::CannotTransferFromFrozenAccount);
+    }
+    // XXX: Unlike for withdrawals, we do not create the account of the sending client
+    //      when the transfer is rejected, so that a rejected transfer has no effect at all.
+    let balance = self.balance_or_new(from, currency, precision);
+    if balance.available_amount < amount {
+      return Err(TransactionTransferError::InsufficientAmountAvailableForTransfer);
+    }
+    balance.checked_apply(amount.negated(), FractionalAmount::ZERO).ok_or(TransactionTransferError::AmountOverflow)
+  }
+  /// Debits the sending client of a transfer whose receiving client belongs to another worker
+  /// of the [sharded] module, and counts the transfer as an applied transaction.
+  ///
+  /// The receiving client must have been checked with [Self::check_transfer_credit] first, by
+  /// its own worker, which then credits it with [Self::credit_transfer]. Workers do not have
+  /// a journal, since there is only ever a journal when there is a single worker, which
+  /// applies both sides of each transfer with [Self::transfer_in] instead.
+  fn debit_transfer (&mut self, from: ClientId, transaction_id: TransactionId, to: ClientId, currency: Currency, amount: FractionalAmount) -> Result<(), TransactionTransferError>
+  {
+    let balance = self.check_transfer_debit(from, transaction_id, to, currency, amount)?;
+    self.commit::<TransactionTransferError>(&Transaction::with_currency(from, transaction_id, currency, TransactionKind::Transfer { to, amount }), &[
+      StoreChange::UseTransactionId(transaction_id, None),
+    ])?;
+    self.set_balance(from, currency, balance);
+    Ok(())
+  }
+  /// Credits the receiving client of a transfer whose sending client has been debited by another
+  /// worker of the [sharded] module, and retains the transfer in case it gets disputed.
+  ///
+  /// The ID of the transfer is recorded along with the receiving client here, while the worker
+  /// of the sending client records it without a client, since it cannot be disputed there.
+  fn credit_transfer (&mut self, from: ClientId, transaction_id: TransactionId, to: ClientId, currency: Currency, amount: FractionalAmount) -> std::io::Result<()>
+  {
+    // XXX: The expect is fine because the credit has been checked before the sending client
+    //      was debited, and the account of the receiving client has not changed since.
+    let balance = self.check_transfer_credit(to, currency, amount).expect("transfer credit was checked");
+    let k = (to, transaction_id);
+    self.evict_pending()?;
+    self.change_stores(&[
+      StoreChange::UseTransactionId(transaction_id, Some(to)),
+      StoreChange::Retain(k, DisputedTransaction::Transfer(from, currency, amount)),
+    ])?;
+    self.set_balance(to, currency, balance);
+    self.retain(k);
+    Ok(())
+  }
+  /// Claim that referenced transaction was erroneous and should be reversed.
+  ///
+  /// For a disputed deposit or transfer, the disputed amount is moved from available to held.
+  ///
+  /// For a disputed withdrawal, the disputed amount is added to held while available
+  /// stays the same. In other words, the funds that were debited are provisionally
+  /// credited back to the account, but the client cannot use them until the dispute
+  /// has been resolved or charged back.
+  ///
+  /// Either way, the amounts change in the currency of the disputed transaction.
+  pub fn dispute (&mut self, client_id: ClientId, transaction_id: TransactionId) -> Result<(), TransactionDisputeError>
+  {
+    if !self.policy.frozen_accounts_can_dispute && self.is_frozen(client_id) {
+      return Err(TransactionDisputeError::CannotDisputeOnFrozenAccount);
+    }
+    self.evict_pending()?;
+    let k = (client_id, transaction_id);
+    let disputed_transaction = if let Some((currency, amount)) = self.deposit_transactions.get(&k)? {
+      DisputedTransaction::Deposit(currency, amount)
+    } else if let Some((currency, amount)) = self.withdrawal_transactions.get(&k)? {
+      DisputedTransaction::Withdrawal(currency, amount)
+    } else if let Some((from, currency, amount)) = self.transfer_transactions.get(&k)? {
+      DisputedTransaction::Transfer(from, currency, amount)
+    } else if self.has_expired(k)? {
+      return Err(TransactionDisputeError::DisputeWindowExpired);
+    } else {
+      return Err(TransactionDisputeError::ReferencedTransactionNotFoundForSpecifiedClient);
+    };
+    let (currency, balance) = match disputed_transaction {
+      DisputedTransaction::Deposit(currency, amount) | DisputedTransaction::Transfer(_, currency, amount) =>
+        (currency, self.balance(client_id, currency).checked_apply(amount.negated(), amount)),
+      DisputedTransaction::Withdrawal(currency, amount) => (currency, self.balance(client_id, currency).checked_apply(FractionalAmount::ZERO, amount)),
+    };
+    let balance = balance.ok_or(TransactionDisputeError::AmountOverflow)?;
+    self.commit::<TransactionDisputeError>(&Transaction::new(client_id, transaction_id, TransactionKind::Dispute), &[
+      StoreChange::Release(k, disputed_transaction),
+      StoreChange::OpenDispute(k, disputed_transaction),
+    ])?;
+    self.set_balance(client_id, currency, balance);
+    Ok(())
+  }
+  /// A resolution to a dispute.
+  ///
+  /// The disputed transaction stands, and the account returns to the state
+  /// it was in before the dispute.
+  pub fn resolve (&mut self, client_id: ClientId, transaction_id: TransactionId) -> Result<(), TransactionResolveError>
+  {
+    if !self.policy.frozen_accounts_can_resolve && self.is_frozen(client_id) {
+      return Err(TransactionResolveError::CannotResolveOnFrozenAccount);
+    }
+    self.evict_pending()?;
+    let k = (client_id, transaction_id);
+    let resolved_transaction = self.dispute_transactions.get(&k)?.ok_or(TransactionResolveError::ReferencedTransactionNotUnderDisputeForSpecifiedClient)?;
+    let (currency, balance) = match resolved_transaction {
+      DisputedTransaction::Deposit(currency, amount) | DisputedTransaction::Transfer(_, currency, amount) =>
+        (currency, self.balance(client_id, currency).checked_apply(amount, amount.negated())),
+      DisputedTransaction::Withdrawal(currency, amount) => (currency, self.balance(client_id, currency).checked_apply(FractionalAmount::ZERO, amount.negated())),
+    };
+    let balance = balance.ok_or(TransactionResolveError::AmountOverflow)?;
+    // XXX: A transaction that fell outside of the dispute window while it was under dispute
+    //      has been evicted already, and it is not retained again.
+    let expired = self.expired_transactions.contains_key(&k)?;
+    let changes = if expired {
+      [StoreChange::CloseDispute(k, resolved_transaction), StoreChange::ForgetExpired(k)]
+    } else {
+      [StoreChange::CloseDispute(k, resolved_transaction), StoreChange::Retain(k, resolved_transaction)]
+    };
+    self.commit::<TransactionResolveError>(&Transaction::new(client_id, transaction_id, TransactionKind::Resolve), &changes)?;
+    self.set_balance(client_id, currency, balance);
+    if let (true, DisputedTransaction::Transfer(from, ..)) = (expired, resolved_transaction) {
+      self.forget_transfer(k, from);
+    }
+    Ok(())
+  }
+  /// Final state of a dispute.
+  ///
+  /// The disputed transaction is reversed and the account is frozen. For a deposit,
+  /// the held funds are withdrawn from the account. For a withdrawal, the held funds
+  /// are credited back to the available funds of the account. For a transfer, the held
+  /// funds are withdrawn from the account and credited back to the available funds of
+  /// the client that they were transferred from.
+  pub fn chargeback (&mut self, client_id: ClientId, transaction_id: TransactionId) -> Result<(), TransactionChargebackError>
+  {
+    self.chargeback_with_refund(client_id, transaction_id, true)
+  }
+  /// Charges back a dispute. When `refund` is false, the amount of a transfer that is charged
+  /// back is not credited back to the sending client, which is then left to the caller.
+  /// The [sharded] module does this when the sending client belongs to a different worker.
+  fn chargeback_with_refund (&mut self, client_id: ClientId, transaction_id: TransactionId, refund: bool) -> Result<(), TransactionChargebackError>
+  {
+    if !self.policy.frozen_accounts_can_chargeback && self.is_frozen(client_id) {
+      return Err(TransactionChargebackError::CannotChargebackOnFrozenAccount);
+    }
+    self.evict_pending()?;
+    let k = (client_id, transaction_id);
+    let chargeback_transaction = self.dispute_transactions.get(&k)?.ok_or(TransactionChargebackError::ReferencedTransactionNotUnderDisputeForSpecifiedClient)?;
+    let (currency, balance) = match chargeback_transaction {
+      DisputedTransaction::Deposit(currency, amount) | DisputedTransaction::Transfer(_, currency, amount) =>
+        (currency, self.balance(client_id, currency).checked_apply(FractionalAmount::ZERO, amount.negated())),
+      DisputedTransaction::Withdrawal(currency, amount) => (currency, self.balance(client_id, currency).checked_apply(amount, amount.negated())),
+    };
+    let balance = balance.ok_or(TransactionChargebackError::AmountOverflow)?;
+    let refunded = match chargeback_transaction {
+      DisputedTransaction::Transfer(from, currency, amount) if refund =>
+        Some((from, self.refunded_balance(from, currency, amount).ok_or(TransactionChargebackError::AmountOverflow)?)),
+      _ => None,
+    };
+    let changes = [StoreChange::CloseDispute(k, chargeback_transaction), StoreChange::Finalize(k), StoreChange::ForgetExpired(k)];
+    let changes = if self.expired_transactions.contains_key(&k)? { &changes[..] } else { &changes[..2] };
+    self.commit::<TransactionChargebackError>(&Transaction::new(client_id, transaction_id, TransactionKind::Chargeback), changes)?;
+    self.set_balance(client_id, currency, balance);
+    // XXX: Indexing the accounts is fine because the balance we have just set is part of the account.
+    self.accounts.get_mut(&client_id).unwrap().frozen = true;
+    if let Some((from, refunded_balance)) = refunded {
+      self.set_balance(from, currency, refunded_balance);
+    }
+    Ok(())
+  }
+  /// The balance of the sending client of a transfer after the amount of the transfer has been
+  /// credited back to them, or `None` if that would overflow. The sending client is not subject
+  /// to the rules for frozen accounts here, as the funds are returned to them involuntarily.
+  fn refunded_balance (&self, from: ClientId, currency: Currency, amount: FractionalAmount) -> Option<Balance>
+  {
+    // XXX: Using Self::balance is fine because the sending client was debited in the currency of the transfer.
+    self.balance(from, currency).checked_apply(amount, FractionalAmount::ZERO)
+  }
+  /// Manually freezes the account of the client, such as when support staff suspect fraud.
+  ///
+  /// The account is frozen in the same way as by a chargeback, so withdrawals from the account
+  /// are rejected unless [ProcessorPolicy::frozen_accounts_can_withdraw] is enabled. The reason
+  /// is not kept by the transaction processor, but it is written to the [Journal], if any,
+  /// and it is part of the transaction as it appears in the outcome log.
+  ///
+  /// ## Examples
+  ///
+  /// ```
+  /// use transaction_engine::{TransactionProcessor, ClientId, TransactionId};
+  /// use transaction_engine::{TransactionLockError, TransactionUnlockError, TransactionWithdrawError};
+  ///
+  /// let mut transaction_processor = TransactionProcessor::new();
+  /// let client_a = ClientId::from(1u16);
+  /// transaction_processor.deposit(client_a, TransactionId::from(1u32), "1.5".try_into().unwrap()).unwrap();
+  ///
+  /// transaction_processor.lock(client_a, TransactionId::from(2u32), "Suspected fraud".parse().unwrap()).unwrap();
+  /// let res = transaction_processor.withdraw(client_a, TransactionId::from(3u32), "0.5".try_into().unwrap());
+  /// assert!(matches!(res, Err(TransactionWithdrawError::CannotWithdrawFromFrozenAccount)));
+  /// let res = transaction_processor.lock(client_a, TransactionId::from(4u32), "Suspected fraud".parse().unwrap());
+  /// assert!(matches!(res, Err(TransactionLockError::AccountAlreadyFrozen)));
+  ///
+  /// transaction_processor.unlock(client_a, TransactionId::from(5u32), "Identity verified".parse().unwrap()).unwrap();
+  /// transaction_processor.withdraw(client_a, TransactionId::from(6u32), "0.5".try_into().unwrap()).unwrap();
+  /// let res = transaction_processor.unlock(client_a, TransactionId::from(7u32), "Identity verified".parse().unwrap());
+  /// assert!(matches!(res, Err(TransactionUnlockError::AccountNotFrozen)));
+  /// ```
+  pub fn lock (&mut self, client_id: ClientId, transaction_id: TransactionId, reason: Reason) -> Result<(), TransactionLockError>
+  {
+    let account = self.accounts.get(&client_id).ok_or(TransactionLockError::AccountNotFound)?;
+    if account.frozen {
+      return Err(TransactionLockError::AccountAlreadyFrozen);
+    }
+    self.record(&Transaction::new(client_id, transaction_id, TransactionKind::Lock(reason)))?;
+    // XXX: Indexing the accounts is fine because we have just found the account.
+    self.accounts.get_mut(&client_id).unwrap().frozen = true;
+    Ok(())
+  }
+  /// Manually unfreezes the account of the client, whether it was frozen by a chargeback
+  /// or by [Self::lock], such as after support staff have investigated a chargeback.
+  ///
+  /// Balances are not changed by unfreezing the account. See [Self::lock] for what happens to the reason.
+  pub fn unlock (&mut self, client_id: ClientId, transaction_id: TransactionId, reason: Reason) -> Result<(), TransactionUnlockError>
+  {
+    let account = self.accounts.get(&client_id).ok_or(TransactionUnlockError::AccountNotFound)?;
+    if !account.frozen {
+      return Err(TransactionUnlockError::AccountNotFrozen);
+    }
+    self.record(&Transaction::new(client_id, transaction_id, TransactionKind::Unlock(reason)))?;
+    // XXX: Indexing the accounts is fine because we have just found the account.
+    self.accounts.get_mut(&client_id).unwrap().frozen = false;
+    Ok(())
+  }
+  /// Attaches a [Journal] that all transactions accepted from now on are written to,
+  /// before they are applied. Returns the journal that was previously attached, if any.
+  ///
+  /// See the [journal] module for how to recover the state of a transaction processor
+  /// from a journal.
+  pub fn attach_journal (&mut self, journal: Journal) -> Option<Journal>
+  {
+    self.journal.replace(journal)
+  }
+  /// Detaches the [Journal] from the transaction processor, if one is attached.
+  pub fn detach_journal (&mut self) -> Option<Journal>
+  {
+    self.journal.take()
+  }
+  /// Number of transactions that have been accepted and applied.
+  pub fn applied_transactions (&self) -> u64
+  {
+    self.applied_transactions
+  }
+  /// Number of deposits, withdrawals and transfers that are currently retained in case they
+  /// get disputed, not counting those that are under dispute.
+  ///
+  /// Without a [DisputeWindow], this only ever grows. With a dispute window, it is bounded by
+  /// the number of transactions that fall within the dispute window.
+  pub fn retained_transactions (&self) -> usize
+  {
+    self.deposit_transactions.len() + self.withdrawal_transactions.len() + self.transfer_transactions.len()
+  }
+  /// Advances the time of the transaction processor to the given timestamp, in seconds since
+  /// the Unix epoch, and evicts the retained transactions that are now outside of a
+  /// [DisputeWindow::Seconds], if that is the dispute window of the [ProcessorPolicy].
+  ///
+  /// This is done by [Self::process] for each transaction that has a timestamp. Transactions
+  /// are retained at the time of the transaction processor when they are applied. The time
+  /// never goes backwards, so a timestamp that is earlier than the current time has no effect.
+  ///
+  /// ## Examples
+  ///
+  /// ```
+  /// use std::num::NonZeroU64;
+  /// use transaction_engine::{TransactionProcessor, ProcessorPolicy, DisputeWindow, Transaction, TransactionKind, ClientId, TransactionId};
+  /// use transaction_engine::{TransactionError, TransactionDisputeError};
+  ///
+  /// let policy = ProcessorPolicy {
+  ///   dispute_window: Some(DisputeWindow::Seconds(NonZeroU64::new(60 * 60).unwrap())),
+  ///   ..Default::default()
+  /// };
+  /// let mut transaction_processor = TransactionProcessor::with_policy(policy).unwrap();
+  /// let client_a = ClientId::from(1u16);
+  /// let deposit = TransactionKind::Deposit("1.5".try_into().unwrap());
+  ///
+  /// transaction_processor.process(Transaction::new(client_a, TransactionId::from(1u32), deposit.clone()).at(1_700_000_000)).unwrap();
+  /// transaction_processor.process(Transaction::new(client_a, TransactionId::from(2u32), deposit).at(1_700_003_000)).unwrap();
+  /// assert_eq!(transaction_processor.retained_transactions(), 2);
+  ///
+  /// // An hour after the first deposit, it can no longer be disputed.
+  /// let res = transaction_processor.process(Transaction::new(client_a, TransactionId::from(1u32), TransactionKind::Dispute).at(1_700_003_600));
+  /// assert!(matches!(res, Err(TransactionError::Dispute(TransactionDisputeError::DisputeWindowExpired))));
+  /// assert_eq!(transaction_processor.retained_transactions(), 1);
+  /// transaction_processor.process(Transaction::new(client_a, TransactionId::from(2u32), TransactionKind::Dispute).at(1_700_003_600)).unwrap();
+  /// ```
+  ///
+  /// Fails if evicting a transaction fails with an I/O error of the [store] that it is retained in.
+  pub fn advance_time (&mut self, timestamp: u64) -> std::io::Result<()>
+  {
+    if timestamp > self.time {
+      self.time = timestamp;
+      self.evict_expired()?;
+    }
+    Ok(())
+  }
+  /// Evicts the retained transactions that are outside of a [DisputeWindow::Seconds] at the current time,
+  /// along with those whose eviction failed before.
+  fn evict_expired (&mut self) -> std::io::Result<()>
+  {
+    if let Some(DisputeWindow::Seconds(window)) = self.policy.dispute_window {
+      while let Some(&(time, ..)) = self.retained_by_time.front() {
+        if self.time.saturating_sub(time) < window.get() {
+          break;
+        }
+        // XXX: The unwrap is fine because we have just looked at the front.
+        self.pending_evictions.push_back(self.retained_by_time.pop_front().unwrap());
+      }
+    }
+    self.evict_pending()
+  }
+  /// Evicts the transactions that have fallen outside of the dispute window and have not been evicted yet.
+  ///
+  /// Transactions that are pushed out of a [DisputeWindow::Transactions] by a transaction that is
+  /// applied are evicted right away, but if that fails, the transaction that pushed them out stands.
+  /// Evicting them is then retried before the next transaction changes anything, which fails if
+  /// evicting them fails again.
+  fn evict_pending (&mut self) -> std::io::Result<()>
+  {
+    while let Some(&(_, client_id, transaction_id)) = self.pending_evictions.front() {
+      self.evict((client_id, transaction_id))?;
+      self.pending_evictions.pop_front();
+    }
+    Ok(())
+  }
+  /// Keeps track of the order of a deposit, withdrawal or transfer that has just been retained,
+  /// and evicts the oldest transaction of the client if it is now outside of a [DisputeWindow::Transactions].
+  fn retain (&mut self, k: (ClientId, TransactionId))
+  {
+    self.retain_at(self.time, k);
+    // XXX: The transaction that has been retained stands regardless of whether evicting succeeds.
+    //      Evicting is retried before the next transaction, which reports the error if it fails again.
+    let _ = self.evict_pending();
+  }
+  /// Keeps track of the order of a retained transaction, as retained at the given time, without
+  /// evicting the transactions that are pushed out of the dispute window yet. See [Self::retain].
+  fn retain_at (&mut self, time: u64, k: (ClientId, TransactionId))
+  {
+    match self.policy.dispute_window {
+      None => {},
+      Some(DisputeWindow::Transactions(window)) => {
+        let retained = self.retained_per_client.entry(k.0).or_default();
+        retained.push_back(k.1);
+        if retained.len() > window.get() {
+          // XXX: The unwrap is fine because we have just pushed a transaction.
+          let oldest = retained.pop_front().unwrap();
+          self.pending_evictions.push_back((0, k.0, oldest));
+        }
+      },
+      Some(DisputeWindow::Seconds(_)) => self.retained_by_time.push_back((time, k.0, k.1)),
+    }
+  }
+  /// Evicts a transaction that is outside of the dispute window. A transaction that is under
+  /// dispute stays under dispute, and is not retained again if the dispute is resolved.
+  fn evict (&mut self, k: (ClientId, TransactionId)) -> std::io::Result<()>
+  {
+    if let Some((from, _, _)) = self.transfer_transactions.remove(&k)? {
+      self.forget_transfer(k, from);
+    } else if self.deposit_transactions.remove(&k)?.is_none() && self.withdrawal_transactions.remove(&k)?.is_none()
+      && self.dispute_transactions.contains_key(&k)? {
+      self.expired_transactions.insert(k, ())?;
+    }
+    Ok(())
+  }
+  /// Whether a transaction that is not retained has fallen outside of the dispute window,
+  /// as opposed to never having been accepted for the client or having been charged back.
+  fn has_expired (&self, k: (ClientId, TransactionId)) -> std::io::Result<bool>
+  {
+    if self.expired_transactions.contains_key(&k)? {
+      return Ok(true);
+    }
+    Ok(self.transaction_ids.get(&k.1)? == Some(Some(k.0)) && !self.dispute_transactions.contains_key(&k)?)
+  }
+  /// Makes the changes to the stores and writes the transaction to the journal, undoing the changes
+  /// if either fails, so that a transaction that fails leaves the stores as they were.
+  fn commit<E> (&mut self, transaction: &Transaction, changes: &[StoreChange]) -> Result<(), E>
+  where
+    E: From<std::io::Error> + From<JournalError>,
+  {
+    self.change_stores(changes)?;
+    if let Err(e) = self.record(transaction) {
+      self.undo_changes(changes);
+      return Err(e.into());
+    }
+    Ok(())
+  }
+  /// Makes the changes to the stores, undoing those that have been made already if one of them fails.
+  fn change_stores (&mut self, changes: &[StoreChange]) -> std::io::Result<()>
+  {
+    for (i, &change) in changes.iter().enumerate() {
+      if let Err(e) = self.change_store(change, false) {
+        self.undo_changes(&changes[..i]);
+        return Err(e);
+      }
+    }
+    Ok(())
+  }
+  /// Undoes changes to the stores, the most recent one first.
+  fn undo_changes (&mut self, changes: &[StoreChange])
+  {
+    for &change in changes.iter().rev() {
+      // XXX: If undoing a change fails as well, the store has failed twice in a row, and its
+      //      contents can no longer be relied upon anyways. See store::DiskStore.
+      let _ = self.change_store(change, true);
+    }
+  }
+  /// Makes a change to the stores, or undoes it.
+  fn change_store (&mut self, change: StoreChange, undo: bool) -> std::io::Result<()>
+  {
+    match (change, undo) {
+      (StoreChange::Retain(k, t), false) | (StoreChange::Release(k, t), true) => match t {
+        DisputedTransaction::Deposit(currency, amount) => self.deposit_transactions.insert(k, (currency, amount)).map(drop),
+        DisputedTransaction::Withdrawal(currency, amount) => self.withdrawal_transactions.insert(k, (currency, amount)).map(drop),
+        DisputedTransaction::Transfer(from, currency, amount) => self.transfer_transactions.insert(k, (from, currency, amount)).map(drop),
+      },
+      (StoreChange::Retain(k, t), true) | (StoreChange::Release(k, t), false) => match t {
+        DisputedTransaction::Deposit(..) => self.deposit_transactions.remove(&k).map(drop),
+        DisputedTransaction::Withdrawal(..) => self.withdrawal_transactions.remove(&k).map(drop),
+        DisputedTransaction::Transfer(..) => self.transfer_transactions.remove(&k).map(drop),
+      },
+      (StoreChange::OpenDispute(k, t), false) | (StoreChange::CloseDispute(k, t), true) => self.dispute_transactions.insert(k, t).map(drop),
+      (StoreChange::OpenDispute(k, _), true) | (StoreChange::CloseDispute(k, _), false) => self.dispute_transactions.remove(&k).map(drop),
+      (StoreChange::ForgetExpired(k), false) => self.expired_transactions.remove(&k).map(drop),
+      (StoreChange::ForgetExpired(k), true) => self.expired_transactions.insert(k, ()).map(drop),
+      (StoreChange::Finalize((_, transaction_id)), false) => self.transaction_ids.insert(transaction_id, None).map(drop),
+      (StoreChange::Finalize((client_id, transaction_id)), true) => self.transaction_ids.insert(transaction_id, Some(client_id)).map(drop),
+      (StoreChange::UseTransactionId(transaction_id, client_id), false) => self.transaction_ids.insert(transaction_id, client_id).map(drop),
+      (StoreChange::UseTransactionId(transaction_id, _), true) => self.transaction_ids.remove(&transaction_id).map(drop),
+    }
+  }
+  /// Keeps track of a transfer that can no longer be charged back, if forgotten transfers are kept track of.
+  fn forget_transfer (&mut self, k: (ClientId, TransactionId), from: ClientId)
+  {
+    if let Some(forgotten_transfers) = self.forgotten_transfers.as_mut() {
+      forgotten_transfers.push((k, from));
+    }
+  }
+  /// Counts an accepted transaction and writes it to the journal, if any.
+  ///
+  /// This must be called after the transaction has been validated and before any state
+  /// is changed, so that the journal contains exactly the transactions that were applied.
+  /// Use [Self::commit], which also makes the changes of the transaction to the stores.
+  fn record (&mut self, transaction: &Transaction) -> Result<(), JournalError>
+  {
+    let sequence = self.applied_transactions + 1;
+    if let Some(journal) = self.journal.as_mut() {
+      journal.append(sequence, transaction, self.time)?;
+    }
+    self.applied_transactions = sequence;
+    Ok(())
+  }
+  /// Returns the [ProcessorPolicy] used by this transaction processor.
+  pub fn policy (&self) -> &ProcessorPolicy
+  {
+    &self.policy
+  }
+  /// The outcome of a transaction that has been applied to the balance of the client in the given currency.
+  fn outcome (&self, client_id: ClientId, currency: Currency) -> Outcome
+  {
+    // XXX: Indexing the accounts is fine because all kinds of transactions
+    //      require the account of the client to exist in order to succeed.
+    Outcome { currency, account: self.accounts[&client_id].clone() }
+  }
+  /// Whether the account of the client exists and is frozen.
+  fn is_frozen (&self, client_id: ClientId) -> bool
+  {
+    self.accounts.get(&client_id).is_some_and(|acc| acc.frozen)
+  }
+  /// The balance of the client in the given currency, which must exist.
+  fn balance (&self, client_id: ClientId, currency: Currency) -> Balance
+  {
+    // XXX: Indexing is fine because this is only used for transactions that we have found,
+    //      and because we create balances when we process deposits and withdrawals
+    //      that means that the balance exists for sure :)
+    self.accounts[&client_id].balances[&currency]
+  }
+  /// The balance of the client in the given currency, or an empty balance at
+  /// the given precision if the client has no balance in the currency yet.
+  fn balance_or_new (&self, client_id: ClientId, currency: Currency, precision: Precision) -> Balance
+  {
+    self.accounts.get(&client_id).and_then(|acc| acc.balance(currency)).copied().unwrap_or_else(|| Balance::new(precision))
+  }
+  /// Sets the balance of the client in the given currency, creating the account if needed.
+  fn set_balance (&mut self, client_id: ClientId, currency: Currency, balance: Balance)
+  {
+    let precision = self.policy.precision;
+    self.accounts.entry(client_id).or_insert_with(|| Account::new(precision)).balances.insert(currency, balance);
+  }
+  /// The currency of the deposit, withdrawal or transfer that is referenced by a dispute,
+  /// resolve or chargeback, if the transaction is known.
+  fn referenced_currency (&self, client_id: ClientId, transaction_id: TransactionId) -> std::io::Result<Option<Currency>>
+  {
+    let k = (client_id, transaction_id);
+    if let Some((currency, _)) = self.deposit_transactions.get(&k)? {
+      return Ok(Some(currency));
+    } else if let Some((currency, _)) = self.withdrawal_transactions.get(&k)? {
+      return Ok(Some(currency));
+    } else if let Some((_, currency, _)) = self.transfer_transactions.get(&k)? {
+      return Ok(Some(currency));
+    }
+    Ok(self.dispute_transactions.get(&k)?.map(|disputed_transaction| disputed_transaction.currency()))
+  }
+}
+
+impl Default for TransactionProcessor {
+  fn default () -> Self
+  {
+    Self::new()
+  }
+}
+
+impl From<TransactionProcessor> for Accounts {
+  /// Consumes the transaction processor and returns final account data for all accounts
+  /// for which valid transactions have been processed.
+  fn from (transaction_processor: TransactionProcessor) -> Accounts {
+    transaction_processor.accounts
+  }
+}
+
+/// Errors returned by [TransactionProcessor::process].
+#[derive(Error, Debug)]
+pub enum TransactionError {
+  #[error("Error during processing of deposit")]
+  Deposit(#[from] TransactionDepositError),
+  #[error("Error during processing of withdrawal")]
+  Withdrawal(#[from] TransactionWithdrawError),
+  #[error("Error during processing of transfer")]
+  Transfer(#[from] TransactionTransferError),
+  #[error("Error during processing of dispute")]
+  Dispute(#[from] TransactionDisputeError),
+  #[error("Error during processing of resolve")]
+  Resolve(#[from] TransactionResolveError),
+  #[error("Error during processing of chargeback")]
+  Chargeback(#[from] TransactionChargebackError),
+  #[error("Error during processing of lock")]
+  Lock(#[from] TransactionLockError),
+  #[error("Error during processing of unlock")]
+  Unlock(#[from] TransactionUnlockError),
+}
+
+impl TransactionError {
+  /// The storage error of the given kind of transaction, for I/O errors of a [store]
+  /// that happen while processing the transaction.
+  pub(crate) fn storage (kind: &TransactionKind, e: std::io::Error) -> Self
+  {
+    match kind {
+      TransactionKind::Deposit(_) => TransactionDepositError::Storage(e).into(),
+      TransactionKind::Withdrawal(_) => TransactionWithdrawError::Storage(e).into(),
+      TransactionKind::Transfer { .. } => TransactionTransferError::Storage(e).into(),
+      TransactionKind::Dispute => TransactionDisputeError::Storage(e).into(),
+      TransactionKind::Resolve => TransactionResolveError::Storage(e).into(),
+      TransactionKind::Chargeback => TransactionChargebackError::Storage(e).into(),
+      TransactionKind::Lock(_) => TransactionLockError::Storage(e).into(),
+      TransactionKind::Unlock(_) => TransactionUnlockError::Storage(e).into(),
+    }
+  }
+  /// Short, stable code that identifies the error, for use in machine-readable output.
+  ///
+  /// Errors that mean the same thing for different kinds of transactions have the same code.
+  pub fn code (&self) -> &'static str
+  {
+    match self {
+      Self::Deposit(e) => match e {
+        TransactionDepositError::CannotDepositANegativeAmount => "negative_amount",
+        TransactionDepositError::DuplicateTransactionId => "duplicate_transaction_id",
+        TransactionDepositError::CannotDepositToFrozenAccount => "frozen_account",
+        TransactionDepositError::AmountOverflow => "amount_overflow",
+        TransactionDepositError::PrecisionMismatch => "precision_mismatch",
+        TransactionDepositError::UnknownCurrency => "unknown_currency",
+        TransactionDepositError::Journal(_) => "journal_error",
+        TransactionDepositError::Storage(_) => "storage_error",
+      },
+      Self::Withdrawal(e) => match e {
+        TransactionWithdrawError::CannotWithdrawANegativeAmount => "negative_amount",
+        TransactionWithdrawError::CannotWithdrawFromFrozenAccount => "frozen_account",
+        TransactionWithdrawError::InsufficientAmountAvailableForWithdrawal => "insufficient_funds",
+        TransactionWithdrawError::DuplicateTransactionId => "duplicate_transaction_id",
+        TransactionWithdrawError::AmountOverflow => "amount_overflow",
+        TransactionWithdrawError::PrecisionMismatch => "precision_mismatch",
+        TransactionWithdrawError::UnknownCurrency => "unknown_currency",
+        TransactionWithdrawError::Journal(_) => "journal_error",
+        TransactionWithdrawError::Storage(_) => "storage_error",
+      },
+      Self::Transfer(e) => match e {
+        TransactionTransferError::CannotTransferANegativeAmount => "negative_amount",
+        TransactionTransferError::CannotTransferToSameClient => "same_client",
+        TransactionTransferError::CannotTransferFromFrozenAccount => "frozen_account",
+        TransactionTransferError::CannotTransferToFrozenAccount => "recipient_frozen_account",
+        TransactionTransferError::InsufficientAmountAvailableForTransfer => "insufficient_funds",
+        TransactionTransferError::DuplicateTransactionId => "duplicate_transaction_id",
+        TransactionTransferError::AmountOverflow => "amount_overflow",
+        TransactionTransferError::PrecisionMismatch => "precision_mismatch",
+        TransactionTransferError::UnknownCurrency => "unknown_currency",
+        TransactionTransferError::Journal(_) => "journal_error",
+        TransactionTransferError::Storage(_) => "storage_error",
+      },
+      Self::Dispute(e) => match e {
+        TransactionDisputeError::ReferencedTransactionNotFoundForSpecifiedClient => "transaction_not_found",
+        TransactionDisputeError::DisputeWindowExpired => "dispute_window_expired",
+        TransactionDisputeError::CannotDisputeOnFrozenAccount => "frozen_account",
+        TransactionDisputeError::AmountOverflow => "amount_overflow",
+        TransactionDisputeError::Journal(_) => "journal_error",
+        TransactionDisputeError::Storage(_) => "storage_error",
+      },
+      Self::Resolve(e) => match e {
+        TransactionResolveError::ReferencedTransactionNotUnderDisputeForSpecifiedClient => "transaction_not_under_dispute",
+        TransactionResolveError::CannotResolveOnFrozenAccount => "frozen_account",
+        TransactionResolveError::AmountOverflow => "amount_overflow",
+        TransactionResolveError::Journal(_) => "journal_error",
+        TransactionResolveError::Storage(_) => "storage_error",
+      },
+      Self::Chargeback(e) => match e {
+        TransactionChargebackError::ReferencedTransactionNotUnderDisputeForSpecifiedClient => "transaction_not_under_dispute",
+        TransactionChargebackError::CannotChargebackOnFrozenAccount => "frozen_account",
+        TransactionChargebackError::AmountOverflow => "amount_overflow",
+        TransactionChargebackError::Journal(_) => "journal_error",
+        TransactionChargebackError::Storage(_) => "storage_error",
+      },
+      Self::Lock(e) => match e {
+        TransactionLockError::AccountNotFound => "account_not_found",
+        TransactionLockError::AccountAlreadyFrozen => "already_frozen",
+        TransactionLockError::Journal(_) => "journal_error",
+        TransactionLockError::Storage(_) => "storage_error",
+      },
+      Self::Unlock(e) => match e {
+        TransactionUnlockError::AccountNotFound => "account_not_found",
+        TransactionUnlockError::AccountNotFrozen => "not_frozen",
+        TransactionUnlockError::Journal(_) => "journal_error",
+        TransactionUnlockError::Storage(_) => "storage_error",
+      },
+    }
+  }
+}
+
+/// Errors returned by [TransactionProcessor::deposit].
+#[derive(Error, Debug)]
+pub enum TransactionDepositError {
   #[error("Cannot deposit a negative amount")]
   CannotDepositANegativeAmount,
+  #[error("Transaction ID has already been used by another transaction")]
+  DuplicateTransactionId,
+  #[error("Cannot deposit to frozen account")]
+  CannotDepositToFrozenAccount,
+  #[error("Amount overflow")]
+  AmountOverflow,
+  #[error("Amount does not have the precision of the currency")]
+  PrecisionMismatch,
+  #[error("Currency is not accepted by the transaction processor")]
+  UnknownCurrency,
+  #[error("Failed to write transaction to journal")]
+  Journal(#[from] JournalError),
+  #[error("Failed to access the store of retained transactions")]
+  Storage(#[from] std::io::Error),
 }
 
 /// Errors returned by [TransactionProcessor::withdraw].
@@ -391,6 +2672,45 @@ pub enum TransactionWithdrawError {
   CannotWithdrawFromFrozenAccount,
   #[error("Insufficient amount available for withdrawal")]
   InsufficientAmountAvailableForWithdrawal,
+  #[error("Transaction ID has already been used by another transaction")]
+  DuplicateTransactionId,
+  #[error("Amount overflow")]
+  AmountOverflow,
+  #[error("Amount does not have the precision of the currency")]
+  PrecisionMismatch,
+  #[error("Currency is not accepted by the transaction processor")]
+  UnknownCurrency,
+  #[error("Failed to write transaction to journal")]
+  Journal(#[from] JournalError),
+  #[error("Failed to access the store of retained transactions")]
+  Storage(#[from] std::io::Error),
+}
+
+/// Errors returned by [TransactionProcessor::transfer].
+#[derive(Error, Debug)]
+pub enum TransactionTransferError {
+  #[error("Cannot transfer a negative amount")]
+  CannotTransferANegativeAmount,
+  #[error("Cannot transfer to the same client")]
+  CannotTransferToSameClient,
+  #[error("Cannot transfer from frozen account")]
+  CannotTransferFromFrozenAccount,
+  #[error("Cannot transfer to frozen account")]
+  CannotTransferToFrozenAccount,
+  #[error("Insufficient amount available for transfer")]
+  InsufficientAmountAvailableForTransfer,
+  #[error("Transaction ID has already been used by another transaction")]
+  DuplicateTransactionId,
+  #[error("Amount overflow")]
+  AmountOverflow,
+  #[error("Amount does not have the precision of the currency")]
+  PrecisionMismatch,
+  #[error("Currency is not accepted by the transaction processor")]
+  UnknownCurrency,
+  #[error("Failed to write transaction to journal")]
+  Journal(#[from] JournalError),
+  #[error("Failed to access the store of retained transactions")]
+  Storage(#[from] std::io::Error),
 }
 
 /// Errors returned by [TransactionProcessor::dispute].
@@ -398,6 +2718,16 @@ pub enum TransactionWithdrawError {
 pub enum TransactionDisputeError {
   #[error("Referenced transaction not found for specified client")]
   ReferencedTransactionNotFoundForSpecifiedClient,
+  #[error("Referenced transaction is outside of the dispute window")]
+  DisputeWindowExpired,
+  #[error("Cannot dispute transaction on frozen account")]
+  CannotDisputeOnFrozenAccount,
+  #[error("Amount overflow")]
+  AmountOverflow,
+  #[error("Failed to write transaction to journal")]
+  Journal(#[from] JournalError),
+  #[error("Failed to access the store of retained transactions")]
+  Storage(#[from] std::io::Error),
 }
 
 /// Errors returned by [TransactionProcessor::resolve].
@@ -405,6 +2735,14 @@ pub enum TransactionDisputeError {
 pub enum TransactionResolveError {
   #[error("Referenced transaction not under dispute for specified client")]
   ReferencedTransactionNotUnderDisputeForSpecifiedClient,
+  #[error("Cannot resolve dispute on frozen account")]
+  CannotResolveOnFrozenAccount,
+  #[error("Amount overflow")]
+  AmountOverflow,
+  #[error("Failed to write transaction to journal")]
+  Journal(#[from] JournalError),
+  #[error("Failed to access the store of retained transactions")]
+  Storage(#[from] std::io::Error),
 }
 
 /// Errors returned by [TransactionProcessor::chargeback].
@@ -412,4 +2750,38 @@ pub enum TransactionResolveError {
 pub enum TransactionChargebackError {
   #[error("Referenced transaction not under dispute for specified client")]
   ReferencedTransactionNotUnderDisputeForSpecifiedClient,
+  #[error("Cannot chargeback dispute on frozen account")]
+  CannotChargebackOnFrozenAccount,
+  #[error("Amount overflow")]
+  AmountOverflow,
+  #[error("Failed to write transaction to journal")]
+  Journal(#[from] JournalError),
+  #[error("Failed to access the store of retained transactions")]
+  Storage(#[from] std::io::Error),
+}
+
+/// Errors returned by [TransactionProcessor::lock].
+#[derive(Error, Debug)]
+pub enum TransactionLockError {
+  #[error("No account for specified client")]
+  AccountNotFound,
+  #[error("Account is already frozen")]
+  AccountAlreadyFrozen,
+  #[error("Failed to write transaction to journal")]
+  Journal(#[from] JournalError),
+  #[error("Failed to access the store of retained transactions")]
+  Storage(#[from] std::io::Error),
+}
+
+/// Errors returned by [TransactionProcessor::unlock].
+#[derive(Error, Debug)]
+pub enum TransactionUnlockError {
+  #[error("No account for specified client")]
+  AccountNotFound,
+  #[error("Account is not frozen")]
+  AccountNotFrozen,
+  #[error("Failed to write transaction to journal")]
+  Journal(#[from] JournalError),
+  #[error("Failed to access the store of retained transactions")]
+  Storage(#[from] std::io::Error),
 }
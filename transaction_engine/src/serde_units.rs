@@ -0,0 +1,59 @@
+//! Serialization of [FractionalAmount]s as the integer number of their smallest units.
+//!
+//! By default, amounts are serialized as decimal strings. For use with
+//! `#[serde(with = "transaction_engine::serde_units")]` on fields of type [FractionalAmount],
+//! this module serializes amounts as integers instead, which is more compact and
+//! does not require the amount to be parsed when it is read back.
+//!
+//! Since the size of the smallest unit depends on the [Precision] of the amount, the number
+//! of decimal places of the precision is serialized along with the number of units, as a pair
+//! of integers. Amounts are deserialized at the precision that they were serialized with,
+//! and a number of decimal places that is not a valid precision is rejected.
+//!
+//! ## Examples
+//!
+//! ```
+//! use serde::{Deserialize, Serialize};
+//! use transaction_engine::{FractionalAmount, Precision};
+//!
+//! #[derive(Serialize, Deserialize, Debug, PartialEq)]
+//! struct Record {
+//!   #[serde(with = "transaction_engine::serde_units")]
+//!   amount: FractionalAmount,
+//! }
+//!
+//! let record = Record { amount: "1.5".try_into().unwrap() };
+//! let json = serde_json::to_string(&record).unwrap();
+//! assert_eq!(json, r#"{"amount":[15000,4]}"#);
+//! assert_eq!(serde_json::from_str::<Record>(&json).unwrap(), record);
+//!
+//! // Amounts keep their precision.
+//! let record = Record { amount: FractionalAmount::new(12_345_678, Precision::new(8).unwrap()) };
+//! let json = serde_json::to_string(&record).unwrap();
+//! assert_eq!(json, r#"{"amount":[12345678,8]}"#);
+//! assert_eq!(serde_json::from_str::<Record>(&json).unwrap().amount.to_string(), "0.12345678");
+//!
+//! assert!(serde_json::from_str::<Record>(r#"{"amount":[15000,19]}"#).is_err());
+//! ```
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{Error, Unexpected};
+
+use crate::{FractionalAmount, Precision};
+
+/// Serializes an amount as the integer number of its smallest units,
+/// followed by the number of decimal places of its precision.
+pub fn serialize<S: Serializer> (amount: &FractionalAmount, serializer: S) -> Result<S::Ok, S::Error>
+{
+  (amount.units(), amount.precision().decimal_places()).serialize(serializer)
+}
+
+/// Deserializes an amount from the integer number of its smallest units,
+/// followed by the number of decimal places of its precision.
+pub fn deserialize<'de, D: Deserializer<'de>> (deserializer: D) -> Result<FractionalAmount, D::Error>
+{
+  let (units, decimal_places) = <(i64, u8)>::deserialize(deserializer)?;
+  let precision = Precision::new(decimal_places)
+    .ok_or_else(|| D::Error::invalid_value(Unexpected::Unsigned(decimal_places.into()), &"a valid number of decimal places"))?;
+  Ok(FractionalAmount::new(units, precision))
+}
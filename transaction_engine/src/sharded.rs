@@ -0,0 +1,691 @@
+//! Multi-threaded transaction processing, sharded by client id.
+//!
+//! As mentioned in the package level docs, almost all of the types of transactions that
+//! we are concerned about are per individual user. This means that we can split the accounts
+//! of the users across a number of [TransactionProcessor]s, each running on its own thread,
+//! and send each transaction to the processor that is responsible for the client of the
+//! transaction. There is then no synchronization needed between the threads that process
+//! these transactions.
+//!
+//! The exception is transfers between clients that belong to different workers, and the
+//! chargebacks of such transfers, which change the accounts of both clients. For these,
+//! the [ShardedProcessor] first asks the worker of one client to check whether its side of
+//! the transaction can be applied, then has the worker of the other client apply its side,
+//! and only if that succeeded, has the first worker apply its side as well. It waits for
+//! each worker to reply before going on, so the transaction is applied to both accounts
+//! or to neither, in the order in which it was submitted. Since each reply has to wait
+//! for the worker to process the transactions that are queued up before it, transactions
+//! between clients of different workers are considerably slower than other transactions.
+//! The [ShardedProcessor] keeps track of such transfers until the worker of the receiving
+//! client reports that they can no longer be charged back, because they fell outside of
+//! the dispute window.
+//!
+//! The one piece of state that is shared between all users is the set of transaction IDs
+//! that have been used, since transaction IDs are globally unique. This set is kept by
+//! the [ShardedProcessor] itself, which claims the ID of each deposit, withdrawal and transfer at
+//! the time that the transaction is sent to a worker. The worker reports back whether it accepted
+//! the transaction, and the ID of a rejected transaction is released again, so that it is not used
+//! up, the same as with a [TransactionProcessor]. When a transaction comes along with the ID of a
+//! transaction whose outcome has not been reported yet, the [ShardedProcessor] waits for the
+//! outcome before it decides on the new transaction. The same transactions are therefore accepted
+//! regardless of the number of workers.
+//!
+//! Each worker also keeps its own time, for a [crate::DisputeWindow::Seconds], which is advanced
+//! by the timestamps of the transactions of its clients. As long as the timestamps of the
+//! transactions are non-decreasing, a transaction falls outside of the dispute window at
+//! the same point as with a single [TransactionProcessor].
+//!
+//! ## Examples
+//!
+//! ```
+//! use std::num::NonZeroUsize;
+//! use transaction_engine::{ClientId, TransactionId, Transaction, TransactionKind, Accounts};
+//! use transaction_engine::sharded::ShardedProcessor;
+//!
+//! let num_threads = NonZeroUsize::new(4).unwrap();
+//! let mut sharded_processor = ShardedProcessor::new(num_threads, Default::default(), |transaction, res, _account| {
+//!   if let Err(e) = res {
+//!     eprintln!("{} tx {} for client {}: {:?}", e, transaction.tx, transaction.client, e);
+//!   }
+//! }).unwrap();
+//!
+//! for client in 1..=8u16 {
+//!   let tx = TransactionId::from(u32::from(client));
+//!   let amount = "1.5".try_into().unwrap();
+//!   sharded_processor.process(Transaction::new(ClientId::from(client), tx, TransactionKind::Deposit(amount)));
+//! }
+//! sharded_processor.process(Transaction::new(ClientId::from(1u16), TransactionId::from(1u32), TransactionKind::Dispute));
+//!
+//! // Clients 2 and 3 belong to different workers.
+//! let transfer = TransactionKind::Transfer { to: ClientId::from(3u16), amount: "0.5".try_into().unwrap() };
+//! sharded_processor.process(Transaction::new(ClientId::from(2u16), TransactionId::from(9u32), transfer));
+//!
+//! let accounts: Accounts = sharded_processor.into_processor().unwrap().into();
+//! assert_eq!(accounts.len(), 8);
+//! assert_eq!(accounts[&ClientId::from(1u16)].get_held().to_string(), "1.5000");
+//! assert_eq!(accounts[&ClientId::from(2u16)].get_available().to_string(), "1.0000");
+//! assert_eq!(accounts[&ClientId::from(3u16)].get_available().to_string(), "2.0000");
+//! ```
+//!
+//! The results are the same as with a single worker, including for transactions that reuse
+//! the ID of a transaction that was rejected.
+//!
+//! ```
+//! use std::num::NonZeroUsize;
+//! use transaction_engine::{AccountOrder, ClientId, TransactionId, Transaction, TransactionKind};
+//! use transaction_engine::sharded::ShardedProcessor;
+//!
+//! let transaction = |client: u16, tx: u32, kind| Transaction::new(ClientId::from(client), TransactionId::from(tx), kind);
+//! let amount = |amount: &str| amount.try_into().unwrap();
+//! let transactions = vec![
+//!   transaction(1, 1, TransactionKind::Deposit(amount("1.5"))),
+//!   // Rejected for insufficient funds, so the ID can be used by a client of another worker.
+//!   transaction(2, 2, TransactionKind::Withdrawal(amount("5"))),
+//!   transaction(1, 2, TransactionKind::Deposit(amount("1"))),
+//!   // Rejected for insufficient funds, after checking the client of the other worker.
+//!   transaction(2, 3, TransactionKind::Transfer { to: ClientId::from(1u16), amount: amount("1") }),
+//!   transaction(2, 3, TransactionKind::Deposit(amount("0.5"))),
+//!   // Rejected, as the ID has been used by an accepted transaction.
+//!   transaction(1, 3, TransactionKind::Deposit(amount("0.5"))),
+//! ];
+//! let balances = |num_threads| {
+//!   let mut sharded_processor = ShardedProcessor::new(NonZeroUsize::new(num_threads).unwrap(), Default::default(), |_, _, _| {}).unwrap();
+//!   for transaction in &transactions {
+//!     sharded_processor.process(transaction.clone());
+//!   }
+//!   sharded_processor.into_processor().unwrap().sorted_balances(AccountOrder::Client).into_iter()
+//!     .map(|b| (u16::from(b.client), b.balance.get_available().to_string()))
+//!     .collect::<Vec<_>>()
+//! };
+//! assert_eq!(balances(1), vec![(1, "2.5000".to_string()), (2, "0.5000".to_string())]);
+//! assert_eq!(balances(2), balances(1));
+//! ```
+
+use std::collections::HashMap;
+use std::io;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::thread::JoinHandle;
+
+use crate::{Account, ClientId, TransactionId, Currency, FractionalAmount, ProcessorPolicy, TransactionProcessor, Transaction, TransactionKind, Outcome, TransactionError};
+use crate::{DisputedTransaction, RetainedTransactions, TransactionDepositError, TransactionWithdrawError, TransactionTransferError, TransactionChargebackError};
+use crate::store::TransactionStore;
+
+/// Number of transactions that can be queued up for each worker before
+/// sending further transactions to the worker blocks.
+///
+/// This bounds the memory used for transactions that have been read from the input
+/// but not yet processed, in case the input is read faster than it can be processed.
+pub const CHANNEL_CAPACITY: usize = 4096;
+
+/// Callback that is used for reporting the results of individual transactions.
+type ResultCallback = Arc<dyn Fn(&Transaction, &Result<Outcome, TransactionError>, Option<&Account>) + Send + Sync>;
+
+/// Report sent from a worker to the [ShardedProcessor], when there is more than one worker.
+enum Report {
+  /// Whether a deposit, withdrawal or transfer whose ID was claimed by the [ShardedProcessor] was accepted.
+  Outcome(TransactionId, bool),
+  /// A transfer from a client of another worker, which can no longer be charged back
+  /// because it fell outside of the dispute window.
+  ForgottenTransfer(ClientId, TransactionId),
+}
+
+/// Message sent from the [ShardedProcessor] to a worker.
+enum ShardMessage {
+  /// A transaction for the worker to process.
+  Process(Transaction),
+  /// A transaction that was rejected before it was sent to the worker. The worker only reports
+  /// the result, so that it is reported in order with the other transactions of the client.
+  Reject(Transaction, TransactionError),
+  /// Checks whether the receiving client of a transfer from a client of another worker
+  /// can be credited, and replies with the result.
+  CheckTransferCredit { to: ClientId, currency: Currency, amount: FractionalAmount, reply: SyncSender<Result<(), TransactionTransferError>> },
+  /// A transfer to a client of another worker, for the worker to debit the sending client.
+  /// The worker replies whether the debit was applied.
+  DebitTransfer { transaction: Transaction, to: ClientId, amount: FractionalAmount, reply: SyncSender<bool> },
+  /// Credits the receiving client of a transfer whose sending client has been debited by another worker,
+  /// at the timestamp of the transfer, if any. The worker only reports the result of the transfer
+  /// if the credit fails with an I/O error of a [crate::store], as the worker of the sending client
+  /// has reported the result already.
+  CreditTransfer { from: ClientId, tx: TransactionId, to: ClientId, currency: Currency, amount: FractionalAmount, timestamp: Option<u64> },
+  /// Checks whether the amount of a transfer to a client of another worker can be credited
+  /// back to the sending client, and replies with the result.
+  CheckRefund { from: ClientId, currency: Currency, amount: FractionalAmount, reply: SyncSender<bool> },
+  /// A chargeback of a transfer from a client of another worker, which the worker applies without
+  /// crediting the amount back to the sending client. The worker replies whether the chargeback was applied.
+  ChargebackTransfer { transaction: Transaction, reply: SyncSender<bool> },
+  /// Credits the amount of a transfer that has been charged back by another worker back to the sending client.
+  Refund { from: ClientId, currency: Currency, amount: FractionalAmount },
+}
+
+/// Processes transactions on a number of worker threads, each of which
+/// owns a [TransactionProcessor] for a subset of the clients.
+///
+/// Transactions are routed to workers by [ClientId], so all transactions of a single client
+/// are processed by the same worker in the order in which they were submitted.
+///
+/// Transactions are processed asynchronously. The results of individual transactions are
+/// reported via the callback that is given when the sharded processor is created.
+/// The callback is called on the worker thread that is responsible for the client of the
+/// transaction, with the transaction, the result of processing the transaction, and the
+/// account of the client as it is after the transaction, whether the transaction was
+/// applied or not. For a given client, the results are reported in the order in which
+/// the transactions were submitted.
+///
+/// Transfers between clients of different workers, and the chargebacks of such transfers,
+/// involve both workers. See the [module level docs](self) for how these are processed.
+/// Their results are reported by the worker of the client of the transaction. In the unlikely
+/// case that the receiving client of a transfer cannot be credited because of an I/O error of
+/// a [crate::store], the worker of the receiving client reports that error for the transfer as well.
+pub struct ShardedProcessor {
+  senders: Vec<SyncSender<ShardMessage>>,
+  workers: Vec<JoinHandle<TransactionProcessor>>,
+  /// IDs of deposits, withdrawals and transfers that have been sent to workers and not rejected,
+  /// when there is more than one worker. With a single worker, the worker keeps track of the IDs itself.
+  ///
+  /// These are kept without the client that can dispute the transaction, which is recorded by the worker of that client.
+  transaction_ids: Option<Box<dyn TransactionStore<TransactionId, Option<ClientId>>>>,
+  /// IDs in [Self::transaction_ids] of transactions that the workers have not reported the outcome of yet,
+  /// along with the index of the worker.
+  pending_transaction_ids: HashMap<TransactionId, usize>,
+  /// For each worker, the reports of the worker, which include the outcomes of the transactions
+  /// with IDs in [Self::pending_transaction_ids].
+  reports: Vec<Receiver<Report>>,
+  /// Transfers between clients of different workers that can still be charged back, keyed by
+  /// the receiving client, along with the sending client, the currency and the amount.
+  /// A chargeback of one of these transfers involves the workers of both clients.
+  ///
+  /// Transfers that fall outside of the dispute window are reported by the worker of the receiving
+  /// client once they can no longer be charged back, and are then removed from here as well.
+  remote_transfers: RetainedTransactions<(ClientId, Currency, FractionalAmount)>,
+  /// Number of transactions that had been applied before the state was split up between the workers.
+  applied_transactions: u64,
+}
+
+impl ShardedProcessor {
+  /// Creates a sharded processor with the given number of worker threads,
+  /// each of which uses the given [ProcessorPolicy].
+  ///
+  /// Fails if the stores for retained transactions cannot be created. See [TransactionProcessor::with_policy].
+  pub fn new<F> (num_threads: NonZeroUsize, policy: ProcessorPolicy, on_result: F) -> io::Result<Self>
+  where
+    F: Fn(&Transaction, &Result<Outcome, TransactionError>, Option<&Account>) + Send + Sync + 'static,
+  {
+    Self::from_processor(TransactionProcessor::with_policy(policy)?, num_threads, on_result)
+  }
+  /// Creates a sharded processor with the given number of worker threads, continuing
+  /// from the state of an existing [TransactionProcessor].
+  ///
+  /// The state of the transaction processor is split up between the workers by client id.
+  ///
+  /// With a single worker, the transaction processor is used by the worker as is,
+  /// including its [crate::journal::Journal], if it has one.
+  ///
+  /// Fails if the stores of the workers cannot be created, or if the retained transactions
+  /// cannot be moved into them, because of an I/O error of a [crate::store].
+  ///
+  /// Also fails, with [io::ErrorKind::InvalidInput], if there is more than one worker and the transaction
+  /// processor has a [crate::journal::Journal] attached, since the workers cannot write their transactions
+  /// to a single journal in order. Detach the journal with [TransactionProcessor::detach_journal] first,
+  /// if that is intended.
+  ///
+  /// ```
+  /// use std::num::NonZeroUsize;
+  /// use transaction_engine::TransactionProcessor;
+  /// use transaction_engine::journal::{Journal, SyncPolicy};
+  /// use transaction_engine::sharded::ShardedProcessor;
+  ///
+  /// let path = std::env::temp_dir().join(format!("from_processor_{}.journal", std::process::id()));
+  /// let mut transaction_processor = TransactionProcessor::new();
+  /// transaction_processor.attach_journal(Journal::create(&path, SyncPolicy::Never).unwrap());
+  /// let res = ShardedProcessor::from_processor(transaction_processor, NonZeroUsize::new(2).unwrap(), |_, _, _| {});
+  /// assert_eq!(res.err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+  /// std::fs::remove_file(&path).unwrap();
+  /// ```
+  pub fn from_processor<F> (transaction_processor: TransactionProcessor, num_threads: NonZeroUsize, on_result: F) -> io::Result<Self>
+  where
+    F: Fn(&Transaction, &Result<Outcome, TransactionError>, Option<&Account>) + Send + Sync + 'static,
+  {
+    let num_threads = num_threads.get();
+    let on_result: ResultCallback = Arc::new(on_result);
+    let applied_transactions = transaction_processor.applied_transactions;
+    let mut remote_transfers = transaction_processor.policy.storage.create()?;
+    let (shards, transaction_ids) = if num_threads == 1 {
+      (vec![transaction_processor], None)
+    } else {
+      if transaction_processor.journal.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "transaction processors with a journal can only be used with a single worker"));
+      }
+      let mut shards = (0..num_threads)
+        .map(|_| Ok(TransactionProcessor { time: transaction_processor.time, ..TransactionProcessor::with_policy(transaction_processor.policy.clone())? }))
+        .collect::<io::Result<Vec<_>>>()?;
+      for (client_id, account) in transaction_processor.accounts {
+        shards[shard_index(client_id, num_threads)].accounts.insert(client_id, account);
+      }
+      for entry in transaction_processor.deposit_transactions.iter() {
+        let (k, amount) = entry?;
+        shards[shard_index(k.0, num_threads)].deposit_transactions.insert(k, amount)?;
+      }
+      for entry in transaction_processor.withdrawal_transactions.iter() {
+        let (k, amount) = entry?;
+        shards[shard_index(k.0, num_threads)].withdrawal_transactions.insert(k, amount)?;
+      }
+      for entry in transaction_processor.transfer_transactions.iter() {
+        let (k, transfer) = entry?;
+        if shard_index(transfer.0, num_threads) != shard_index(k.0, num_threads) {
+          remote_transfers.insert(k, transfer)?;
+        }
+        shards[shard_index(k.0, num_threads)].transfer_transactions.insert(k, transfer)?;
+      }
+      for entry in transaction_processor.dispute_transactions.iter() {
+        let (k, disputed_transaction) = entry?;
+        if let DisputedTransaction::Transfer(from, currency, amount) = disputed_transaction {
+          if shard_index(from, num_threads) != shard_index(k.0, num_threads) {
+            remote_transfers.insert(k, (from, currency, amount))?;
+          }
+        }
+        shards[shard_index(k.0, num_threads)].dispute_transactions.insert(k, disputed_transaction)?;
+      }
+      for (client_id, retained) in transaction_processor.retained_per_client {
+        shards[shard_index(client_id, num_threads)].retained_per_client.insert(client_id, retained);
+      }
+      for (time, client_id, transaction_id) in transaction_processor.retained_by_time {
+        shards[shard_index(client_id, num_threads)].retained_by_time.push_back((time, client_id, transaction_id));
+      }
+      for (time, client_id, transaction_id) in transaction_processor.pending_evictions {
+        shards[shard_index(client_id, num_threads)].pending_evictions.push_back((time, client_id, transaction_id));
+      }
+      for entry in transaction_processor.expired_transactions.iter() {
+        let (k, ()) = entry?;
+        shards[shard_index(k.0, num_threads)].expired_transactions.insert(k, ())?;
+      }
+      for entry in transaction_processor.transaction_ids.iter() {
+        if let (transaction_id, Some(client_id)) = entry? {
+          shards[shard_index(client_id, num_threads)].transaction_ids.insert(transaction_id, Some(client_id))?;
+        }
+      }
+      let mut transaction_ids = transaction_processor.transaction_ids;
+      for shard in &mut shards {
+        for entry in shard.transaction_ids.iter() {
+          transaction_ids.insert(entry?.0, None)?;
+        }
+        shard.forgotten_transfers = Some(Vec::new());
+      }
+      (shards, Some(transaction_ids))
+    };
+    let mut senders = Vec::with_capacity(num_threads);
+    let mut workers = Vec::with_capacity(num_threads);
+    let mut reports = Vec::with_capacity(num_threads);
+    for mut shard in shards {
+      let (sender, receiver) = sync_channel(CHANNEL_CAPACITY);
+      // XXX: The channel for reports is unbounded, so that reporting never blocks a worker.
+      //      The ShardedProcessor receives the reports every time it is given a transaction.
+      let (report_sender, report_receiver) = channel();
+      let report_sender = transaction_ids.is_some().then_some(report_sender);
+      let on_result = on_result.clone();
+      let worker = std::thread::spawn(move || {
+        for message in receiver {
+          // XXX: Transfers that were forgotten while handling the previous message are reported now,
+          //      which is soon enough, since forgotten transfers can no longer be charged back anyways.
+          if let (Some(report_sender), Some(forgotten_transfers)) = (&report_sender, shard.forgotten_transfers.as_mut()) {
+            for ((to, tx), from) in forgotten_transfers.drain(..) {
+              if shard_index(from, num_threads) != shard_index(to, num_threads) {
+                let _ = report_sender.send(Report::ForgottenTransfer(to, tx));
+              }
+            }
+          }
+          // XXX: Replies are sent on channels with room for the reply, so sending does not block,
+          //      and it only fails if the ShardedProcessor is gone, in which case nobody is waiting.
+          let (transaction, res) = match message {
+            ShardMessage::Process(transaction) => {
+              let res = shard.process(transaction.clone());
+              report_outcome(report_sender.as_ref(), &transaction, res.is_ok());
+              (transaction, res)
+            },
+            ShardMessage::Reject(transaction, e) => {
+              // XXX: An I/O error of a store while advancing the time is reported instead of the rejection.
+              let e = advance_time(&mut shard, &transaction).err().unwrap_or(e);
+              (transaction, Err(e))
+            },
+            ShardMessage::CheckTransferCredit { to, currency, amount, reply } => {
+              let _ = reply.send(shard.check_transfer_credit(to, currency, amount).map(drop));
+              continue;
+            },
+            ShardMessage::DebitTransfer { transaction, to, amount, reply } => {
+              let res = debit_transfer(&mut shard, &transaction, to, amount);
+              let _ = reply.send(res.is_ok());
+              (transaction, res)
+            },
+            ShardMessage::CreditTransfer { from, tx, to, currency, amount, timestamp } => {
+              let res = timestamp.map_or(Ok(()), |timestamp| shard.advance_time(timestamp))
+                .and_then(|()| shard.credit_transfer(from, tx, to, currency, amount));
+              let Err(e) = res else {
+                continue;
+              };
+              let transaction = Transaction { timestamp, ..Transaction::with_currency(from, tx, currency, TransactionKind::Transfer { to, amount }) };
+              (transaction, Err(TransactionTransferError::Storage(e).into()))
+            },
+            ShardMessage::CheckRefund { from, currency, amount, reply } => {
+              let _ = reply.send(shard.refunded_balance(from, currency, amount).is_some());
+              continue;
+            },
+            ShardMessage::ChargebackTransfer { transaction, reply } => {
+              let res = chargeback_transfer(&mut shard, &transaction);
+              let _ = reply.send(res.is_ok());
+              (transaction, res)
+            },
+            ShardMessage::Refund { from, currency, amount } => {
+              // XXX: The expect is fine because the refund was checked before the chargeback was
+              //      applied, and the account of the sending client has not changed since.
+              let balance = shard.refunded_balance(from, currency, amount).expect("refund was checked");
+              shard.set_balance(from, currency, balance);
+              continue;
+            },
+          };
+          on_result(&transaction, &res, shard.account(transaction.client));
+        }
+        shard
+      });
+      senders.push(sender);
+      workers.push(worker);
+      reports.push(report_receiver);
+    }
+    Ok(Self {
+      senders,
+      workers,
+      transaction_ids,
+      pending_transaction_ids: Default::default(),
+      reports,
+      remote_transfers,
+      applied_transactions,
+    })
+  }
+  /// Sends a transaction to the worker that is responsible for the client of the transaction.
+  /// See [TransactionProcessor::process].
+  ///
+  /// Blocks if the queue of transactions of the worker is full. Transactions that involve
+  /// clients of different workers block until both workers have processed them.
+  pub fn process (&mut self, transaction: Transaction)
+  {
+    self.receive_reports();
+    let shard = shard_index(transaction.client, self.senders.len());
+    let claimed = match transaction.kind {
+      TransactionKind::Deposit(_) | TransactionKind::Withdrawal(_) | TransactionKind::Transfer { .. } =>
+        self.claim_transaction_id(transaction.tx, shard),
+      _ => Ok(true),
+    };
+    let message = match (claimed, &transaction.kind) {
+      (Err(e), kind) => {
+        let e = TransactionError::storage(kind, e);
+        ShardMessage::Reject(transaction, e)
+      },
+      (Ok(false), TransactionKind::Deposit(_)) => ShardMessage::Reject(transaction, TransactionDepositError::DuplicateTransactionId.into()),
+      (Ok(false), TransactionKind::Withdrawal(_)) => ShardMessage::Reject(transaction, TransactionWithdrawError::DuplicateTransactionId.into()),
+      (Ok(false), _) => ShardMessage::Reject(transaction, TransactionTransferError::DuplicateTransactionId.into()),
+      (Ok(true), &TransactionKind::Transfer { to, amount }) if shard_index(to, self.senders.len()) != shard =>
+        return self.transfer_across_shards(transaction, to, amount),
+      (Ok(true), TransactionKind::Chargeback) => match self.remote_transfers.get(&(transaction.client, transaction.tx)) {
+        Ok(Some(transfer)) => return self.chargeback_across_shards(transaction, transfer),
+        Ok(None) => ShardMessage::Process(transaction),
+        Err(e) => ShardMessage::Reject(transaction, TransactionChargebackError::Storage(e).into()),
+      },
+      (Ok(true), _) => ShardMessage::Process(transaction),
+    };
+    self.send(shard, message);
+  }
+  /// Waits for the workers to finish processing all submitted transactions,
+  /// and merges the state of the workers into a single [TransactionProcessor].
+  ///
+  /// If any of the workers panicked, the panic is propagated to the caller. Fails if the retained
+  /// transactions of the workers cannot be merged because of an I/O error of a [crate::store].
+  pub fn into_processor (mut self) -> io::Result<TransactionProcessor>
+  {
+    // XXX: Dropping the senders closes the channels, which makes the workers
+    //      return once they have processed the remaining transactions.
+    self.senders.clear();
+    let shards: Vec<_> = std::mem::take(&mut self.workers).into_iter()
+      .map(|worker| worker.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+      .collect();
+    // XXX: The workers have finished, so all outcomes have been reported, and the IDs
+    //      of all rejected transactions are released before the IDs are merged.
+    self.receive_reports();
+    let mut shards = shards.into_iter();
+    // XXX: The unwrap is fine because there is always at least one worker.
+    let mut merged = shards.next().unwrap();
+    merged.forgotten_transfers = None;
+    let transaction_ids = match self.transaction_ids {
+      // With a single worker, the worker has used the transaction processor as is.
+      None => return Ok(merged),
+      Some(transaction_ids) => transaction_ids,
+    };
+    let mut retained_by_time = Vec::from(std::mem::take(&mut merged.retained_by_time));
+    for shard in shards {
+      merged.accounts.extend(shard.accounts);
+      merge(&mut *merged.deposit_transactions, &*shard.deposit_transactions)?;
+      merge(&mut *merged.withdrawal_transactions, &*shard.withdrawal_transactions)?;
+      merge(&mut *merged.transfer_transactions, &*shard.transfer_transactions)?;
+      merge(&mut *merged.dispute_transactions, &*shard.dispute_transactions)?;
+      merge_transaction_ids(&mut *merged.transaction_ids, &*shard.transaction_ids)?;
+      merged.retained_per_client.extend(shard.retained_per_client);
+      retained_by_time.extend(shard.retained_by_time);
+      merged.pending_evictions.extend(shard.pending_evictions);
+      merge(&mut *merged.expired_transactions, &*shard.expired_transactions)?;
+      merged.time = merged.time.max(shard.time);
+      merged.applied_transactions += shard.applied_transactions;
+    }
+    // XXX: The transactions retained by each worker are in order of time already,
+    //      so the stable sort keeps the order of transactions retained at the same time.
+    retained_by_time.sort_by_key(|&(time, _, _)| time);
+    merged.retained_by_time = retained_by_time.into();
+    // XXX: Workers whose clients had no recent transactions are behind on time, so transactions
+    //      that have fallen outside of the dispute window since then are evicted now.
+    merged.evict_expired()?;
+    merge_transaction_ids(&mut *merged.transaction_ids, &*transaction_ids)?;
+    merged.applied_transactions += self.applied_transactions;
+    Ok(merged)
+  }
+  /// Processes a transfer between clients of different workers.
+  ///
+  /// The worker of the receiving client checks that the credit can be applied, then the worker
+  /// of the sending client applies the debit, and only if that succeeded, the worker of the
+  /// receiving client applies the credit. No other transactions are sent to the workers in the
+  /// meantime, so the checked account cannot change before the credit is applied.
+  fn transfer_across_shards (&mut self, transaction: Transaction, to: ClientId, amount: FractionalAmount)
+  {
+    let num_shards = self.senders.len();
+    let (from, tx, currency, timestamp) = (transaction.client, transaction.tx, transaction.currency, transaction.timestamp);
+    let (reply, response) = sync_channel(1);
+    self.send(shard_index(to, num_shards), ShardMessage::CheckTransferCredit { to, currency, amount, reply });
+    match response.recv() {
+      Ok(Ok(())) => {},
+      Ok(Err(e)) => {
+        self.settle_transaction_id(tx, false);
+        return self.send(shard_index(from, num_shards), ShardMessage::Reject(transaction, e.into()));
+      },
+      // XXX: The worker has hung up, which only happens if it panicked. See Self::send.
+      Err(_) => return self.settle_transaction_id(tx, false),
+    }
+    // XXX: The transfer is kept track of before the sending client is debited, so that the transfer
+    //      is rejected rather than left without a way to charge it back if the store fails.
+    if let Err(e) = self.remote_transfers.insert((to, tx), (from, currency, amount)) {
+      self.settle_transaction_id(tx, false);
+      return self.send(shard_index(from, num_shards), ShardMessage::Reject(transaction, TransactionTransferError::Storage(e).into()));
+    }
+    let (reply, response) = sync_channel(1);
+    self.send(shard_index(from, num_shards), ShardMessage::DebitTransfer { transaction, to, amount, reply });
+    let debited = matches!(response.recv(), Ok(true));
+    self.settle_transaction_id(tx, debited);
+    if debited {
+      self.send(shard_index(to, num_shards), ShardMessage::CreditTransfer { from, tx, to, currency, amount, timestamp });
+    } else {
+      // XXX: Failing to remove the transfer is harmless, since a chargeback that references
+      //      a rejected transfer is rejected by the worker of the receiving client anyways.
+      let _ = self.remote_transfers.remove(&(to, tx));
+    }
+  }
+  /// Processes a chargeback of a transfer between clients of different workers.
+  ///
+  /// This works like [Self::transfer_across_shards], with the worker of the sending client
+  /// checking that the amount can be credited back to the sending client, and the worker
+  /// of the receiving client applying the chargeback itself.
+  fn chargeback_across_shards (&mut self, transaction: Transaction, (from, currency, amount): (ClientId, Currency, FractionalAmount))
+  {
+    let num_shards = self.senders.len();
+    let k = (transaction.client, transaction.tx);
+    let (reply, response) = sync_channel(1);
+    self.send(shard_index(from, num_shards), ShardMessage::CheckRefund { from, currency, amount, reply });
+    match response.recv() {
+      Ok(true) => {},
+      Ok(false) => return self.send(shard_index(k.0, num_shards), ShardMessage::Reject(transaction, TransactionChargebackError::AmountOverflow.into())),
+      // XXX: The worker has hung up, which only happens if it panicked. See Self::send.
+      Err(_) => return,
+    }
+    let (reply, response) = sync_channel(1);
+    self.send(shard_index(k.0, num_shards), ShardMessage::ChargebackTransfer { transaction, reply });
+    if let Ok(true) = response.recv() {
+      self.send(shard_index(from, num_shards), ShardMessage::Refund { from, currency, amount });
+      // XXX: Failing to remove the transfer is harmless, since a transfer that has been charged back
+      //      is no longer under dispute, so another chargeback of it is rejected by the worker anyways.
+      let _ = self.remote_transfers.remove(&k);
+    }
+  }
+  /// Sends a message to a worker.
+  fn send (&self, shard: usize, message: ShardMessage)
+  {
+    // XXX: Sending only fails if the worker has hung up, which only happens if it panicked.
+    //      The panic is propagated by Self::into_processor.
+    let _ = self.senders[shard].send(message);
+  }
+  /// Claims the ID of a deposit, withdrawal or transfer that is about to be sent to the worker
+  /// with the given index. Returns false if the ID has already been used.
+  ///
+  /// The ID is pending until the outcome of the transaction is known. If the ID is pending already,
+  /// this first waits for the outcome of the transaction that claimed it.
+  fn claim_transaction_id (&mut self, transaction_id: TransactionId, shard: usize) -> io::Result<bool>
+  {
+    let Some(transaction_ids) = self.transaction_ids.as_ref() else {
+      return Ok(true);
+    };
+    if transaction_ids.contains_key(&transaction_id)? {
+      self.wait_for_outcome(transaction_id);
+    }
+    // XXX: The unwrap is fine because we have checked that there are transaction IDs above.
+    let transaction_ids = self.transaction_ids.as_mut().unwrap();
+    if transaction_ids.insert(transaction_id, None)?.is_some() {
+      return Ok(false);
+    }
+    self.pending_transaction_ids.insert(transaction_id, shard);
+    Ok(true)
+  }
+  /// Records the outcome of a transaction whose ID is pending, releasing the ID if the transaction was rejected.
+  fn settle_transaction_id (&mut self, transaction_id: TransactionId, accepted: bool)
+  {
+    self.pending_transaction_ids.remove(&transaction_id);
+    if let (false, Some(transaction_ids)) = (accepted, self.transaction_ids.as_mut()) {
+      // XXX: If the ID cannot be released, it stays claimed, the same as when a worker panicked.
+      let _ = transaction_ids.remove(&transaction_id);
+    }
+  }
+  /// Handles the reports that the workers have sent so far, without waiting for any more.
+  fn receive_reports (&mut self)
+  {
+    for shard in 0..self.reports.len() {
+      while let Ok(report) = self.reports[shard].try_recv() {
+        self.handle_report(report);
+      }
+    }
+  }
+  /// Handles a report of a worker.
+  fn handle_report (&mut self, report: Report)
+  {
+    match report {
+      Report::Outcome(transaction_id, accepted) => self.settle_transaction_id(transaction_id, accepted),
+      // XXX: Failing to remove the transfer is harmless, since a chargeback of a transfer that has fallen
+      //      outside of the dispute window is rejected by the worker of the receiving client anyways.
+      Report::ForgottenTransfer(to, tx) => { let _ = self.remote_transfers.remove(&(to, tx)); },
+    }
+  }
+  /// Waits until the outcome of the transaction with the given ID is known, if the ID is pending.
+  fn wait_for_outcome (&mut self, transaction_id: TransactionId)
+  {
+    while let Some(&shard) = self.pending_transaction_ids.get(&transaction_id) {
+      match self.reports[shard].recv() {
+        Ok(report) => self.handle_report(report),
+        // XXX: The worker has hung up, which only happens if it panicked. See Self::send.
+        //      The ID then stays claimed.
+        Err(_) => { self.pending_transaction_ids.remove(&transaction_id); },
+      }
+    }
+  }
+}
+
+/// Reports the outcome of a deposit, withdrawal or transfer whose ID was claimed by the [ShardedProcessor],
+/// if the worker reports outcomes, which it does when there is more than one worker.
+fn report_outcome (report_sender: Option<&Sender<Report>>, transaction: &Transaction, accepted: bool)
+{
+  if let (Some(report_sender), TransactionKind::Deposit(_) | TransactionKind::Withdrawal(_) | TransactionKind::Transfer { .. }) = (report_sender, &transaction.kind) {
+    // XXX: Sending only fails if the ShardedProcessor is gone, in which case nobody is waiting.
+    let _ = report_sender.send(Report::Outcome(transaction.tx, accepted));
+  }
+}
+
+/// Copies all keys and their values from the store of a worker into the store of the merged transaction processor.
+fn merge<K, V> (into: &mut dyn TransactionStore<K, V>, from: &dyn TransactionStore<K, V>) -> io::Result<()>
+{
+  for entry in from.iter() {
+    let (k, v) = entry?;
+    into.insert(k, v)?;
+  }
+  Ok(())
+}
+
+/// Merges the IDs of transactions of a worker or the [ShardedProcessor] into those of another worker.
+///
+/// The client that can dispute a transaction is only recorded by the worker of that client,
+/// so it takes precedence over the same ID without a client.
+fn merge_transaction_ids (into: &mut dyn TransactionStore<TransactionId, Option<ClientId>>, from: &dyn TransactionStore<TransactionId, Option<ClientId>>) -> io::Result<()>
+{
+  for entry in from.iter() {
+    let (transaction_id, client_id) = entry?;
+    if client_id.is_some() || !into.contains_key(&transaction_id)? {
+      into.insert(transaction_id, client_id)?;
+    }
+  }
+  Ok(())
+}
+
+/// Advances the time of a worker to the timestamp of a transaction that is not processed
+/// with [TransactionProcessor::process], if the transaction has a timestamp.
+fn advance_time (shard: &mut TransactionProcessor, transaction: &Transaction) -> Result<(), TransactionError>
+{
+  if let Some(timestamp) = transaction.timestamp {
+    shard.advance_time(timestamp).map_err(|e| TransactionError::storage(&transaction.kind, e))?;
+  }
+  Ok(())
+}
+
+/// Debits the sending client of a transfer to a client of another worker.
+fn debit_transfer (shard: &mut TransactionProcessor, transaction: &Transaction, to: ClientId, amount: FractionalAmount) -> Result<Outcome, TransactionError>
+{
+  let (from, currency) = (transaction.client, transaction.currency);
+  advance_time(shard, transaction)?;
+  shard.debit_transfer(from, transaction.tx, to, currency, amount)?;
+  Ok(shard.outcome(from, currency))
+}
+
+/// Charges back a transfer from a client of another worker, without crediting the amount back to the sending client.
+fn chargeback_transfer (shard: &mut TransactionProcessor, transaction: &Transaction) -> Result<Outcome, TransactionError>
+{
+  let client = transaction.client;
+  advance_time(shard, transaction)?;
+  let currency = shard.referenced_currency(client, transaction.tx).map_err(TransactionChargebackError::Storage)?;
+  shard.chargeback_with_refund(client, transaction.tx, false)?;
+  Ok(shard.outcome(client, currency.unwrap_or(Currency::DEFAULT)))
+}
+
+/// Index of the worker that is responsible for the client.
+fn shard_index (client_id: ClientId, num_shards: usize) -> usize
+{
+  usize::from(u16::from(client_id)) % num_shards
+}
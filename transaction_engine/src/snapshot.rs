@@ -0,0 +1,434 @@
+//! Snapshots of the complete state of a [TransactionProcessor].
+//!
+//! Converting a transaction processor into [crate::Accounts] drops the deposits that
+//! are retained for future disputes, as well as the disputes that are currently open.
+//! A [ProcessorSnapshot] on the other hand contains all of the state of the transaction
+//! processor, so that processing can continue later on where it left off. For example,
+//! a dispute that appears in the input of tomorrow can then reference a deposit that
+//! appeared in the input of today.
+//!
+//! On disk, a snapshot consists of a short header with a magic number and the version
+//! of the snapshot format, followed by the snapshot data encoded with
+//! [bincode](https://crates.io/crates/bincode). Snapshots written with a different
+//! version of the format are rejected rather than misinterpreted.
+//!
+//! ## Examples
+//!
+//! ```
+//! use transaction_engine::{TransactionProcessor, ClientId, TransactionId, Accounts};
+//! use transaction_engine::snapshot::ProcessorSnapshot;
+//!
+//! let client_a = ClientId::from(1u16);
+//! let tx_1 = TransactionId::from(1u32);
+//!
+//! let mut transaction_processor = TransactionProcessor::new();
+//! transaction_processor.deposit(client_a, tx_1, "1.5".try_into().unwrap()).unwrap();
+//!
+//! let mut state = vec![];
+//! transaction_processor.snapshot().unwrap().write_to(&mut state).unwrap();
+//!
+//! let snapshot = ProcessorSnapshot::read_from(&state[..]).unwrap();
+//! let mut transaction_processor = TransactionProcessor::restore(snapshot, Default::default()).unwrap();
+//! transaction_processor.dispute(client_a, tx_1).unwrap();
+//!
+//! let accounts: Accounts = transaction_processor.into();
+//! assert_eq!(accounts[&client_a].get_held().to_string(), "1.5000");
+//! ```
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{Account, Balance, ClientId, TransactionId, Currency, FractionalAmount, ProcessorPolicy, TransactionProcessor, DisputedTransaction, RetainedTransactions};
+
+/// Magic number at the start of snapshot files.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"TTESNAP\0";
+
+/// Version of the snapshot format. This must be incremented whenever
+/// the structure of [ProcessorSnapshot] changes.
+pub const SNAPSHOT_VERSION: u32 = 7;
+
+/// The complete state of a [TransactionProcessor], except for its [ProcessorPolicy]
+/// and its [crate::journal::Journal].
+///
+/// The policy is not part of the state, and is given anew when the
+/// transaction processor is restored from the snapshot. The snapshot does
+/// record the [crate::Precision] of the amounts in each currency though, which
+/// must match the precision of the currency in the policy that it is restored with.
+///
+/// Entries are sorted, so that taking a snapshot of the same state
+/// always results in the same snapshot data, except for the order in which
+/// transactions were retained, which is kept as it is.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct ProcessorSnapshot {
+  /// The currencies of the amounts in the snapshot, along with the number of decimal
+  /// places of the amounts in each currency. Always includes the default currency.
+  currencies: Vec<CurrencyState>,
+  accounts: Vec<AccountState>,
+  deposit_transactions: Vec<RetainedTransaction>,
+  withdrawal_transactions: Vec<RetainedTransaction>,
+  transfer_transactions: Vec<RetainedTransfer>,
+  dispute_transactions: Vec<DisputeState>,
+  transaction_ids: Vec<TransactionIdState>,
+  /// The order in which transactions were retained, oldest first, for the [crate::DisputeWindow]
+  /// of the policy, if any. For [crate::DisputeWindow::Transactions], the transactions are grouped
+  /// by client and their time is zero.
+  retention: Vec<RetentionState>,
+  /// The transactions under dispute that have fallen outside of the dispute window.
+  expired_transactions: Vec<TransactionKey>,
+  /// See [TransactionProcessor::advance_time].
+  time: u64,
+  /// See [TransactionProcessor::applied_transactions].
+  applied_transactions: u64,
+}
+
+/// A currency in a [ProcessorSnapshot], along with the number of decimal places of its amounts.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+struct CurrencyState {
+  currency: [u8; 8],
+  precision: u8,
+}
+
+/// State of a single account in a [ProcessorSnapshot].
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+struct AccountState {
+  client: u16,
+  frozen: bool,
+  balances: Vec<BalanceState>,
+}
+
+/// State of the balance of an account in a single currency.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+struct BalanceState {
+  currency: [u8; 8],
+  available: i64,
+  held: i64,
+}
+
+/// A deposit or withdrawal that is retained in case it gets disputed.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+struct RetainedTransaction {
+  client: u16,
+  tx: u32,
+  currency: [u8; 8],
+  amount: i64,
+}
+
+/// A transfer that is retained in case it gets disputed, keyed by the receiving client.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+struct RetainedTransfer {
+  client: u16,
+  tx: u32,
+  /// The sending client.
+  from: u16,
+  currency: [u8; 8],
+  amount: i64,
+}
+
+/// A transaction in the order in which transactions were retained, along with the time it was retained at.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+struct RetentionState {
+  client: u16,
+  tx: u32,
+  time: u64,
+}
+
+/// A transaction that is referenced by the client it belongs to and its ID.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+struct TransactionKey {
+  client: u16,
+  tx: u32,
+}
+
+/// The ID of an accepted deposit, withdrawal or transfer, along with the client that can dispute it, if any.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+struct TransactionIdState {
+  tx: u32,
+  client: Option<u16>,
+}
+
+/// A transaction that is currently under dispute.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+struct DisputeState {
+  client: u16,
+  tx: u32,
+  kind: DisputedKind,
+  currency: [u8; 8],
+  amount: i64,
+}
+
+/// The type of transaction that is under dispute.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+enum DisputedKind {
+  Deposit,
+  Withdrawal,
+  /// A transfer, along with the sending client.
+  Transfer { from: u16 },
+}
+
+impl TransactionProcessor {
+  /// Takes a snapshot of the complete state of the transaction processor.
+  ///
+  /// The snapshot holds all of the state in memory, including the retained transactions and the IDs
+  /// of all transactions, even when the transaction processor keeps these in a [crate::store::DiskStore].
+  ///
+  /// Fails with [SnapshotError::Storage] if the retained transactions cannot be read from their store.
+  pub fn snapshot (&self) -> Result<ProcessorSnapshot, SnapshotError>
+  {
+    let retained = |transactions: &RetainedTransactions<(Currency, FractionalAmount)>| {
+      let mut retained = transactions.iter()
+        .map(|entry| entry.map(|((client_id, transaction_id), (currency, amount))| RetainedTransaction {
+          client: client_id.0,
+          tx: transaction_id.0,
+          currency: currency.to_bytes(),
+          amount: amount.units,
+        }))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(SnapshotError::Storage)?;
+      retained.sort();
+      Ok::<_, SnapshotError>(retained)
+    };
+    // XXX: Balances are the only place where amounts in a currency can come from,
+    //      so the precisions of the balances cover all amounts in the snapshot.
+    let mut currencies = BTreeMap::from([(Currency::DEFAULT, self.policy.precision)]);
+    let mut accounts: Vec<_> = self.accounts.iter()
+      .map(|(client_id, acc)| AccountState {
+        client: client_id.0,
+        frozen: acc.frozen,
+        balances: acc.balances().map(|(currency, balance)| {
+          currencies.insert(currency, balance.available_amount.precision);
+          BalanceState {
+            currency: currency.to_bytes(),
+            available: balance.available_amount.units,
+            held: balance.held_amount.units,
+          }
+        }).collect(),
+      })
+      .collect();
+    accounts.sort();
+    let mut dispute_transactions = self.dispute_transactions.iter()
+      .map(|entry| entry.map(|((client_id, transaction_id), disputed_transaction)| {
+        let (kind, currency, amount) = match disputed_transaction {
+          DisputedTransaction::Deposit(currency, amount) => (DisputedKind::Deposit, currency, amount),
+          DisputedTransaction::Withdrawal(currency, amount) => (DisputedKind::Withdrawal, currency, amount),
+          DisputedTransaction::Transfer(from, currency, amount) => (DisputedKind::Transfer { from: from.0 }, currency, amount),
+        };
+        DisputeState { client: client_id.0, tx: transaction_id.0, kind, currency: currency.to_bytes(), amount: amount.units }
+      }))
+      .collect::<Result<Vec<_>, _>>()
+      .map_err(SnapshotError::Storage)?;
+    dispute_transactions.sort();
+    let mut transfer_transactions = self.transfer_transactions.iter()
+      .map(|entry| entry.map(|((client_id, transaction_id), (from, currency, amount))| RetainedTransfer {
+        client: client_id.0,
+        tx: transaction_id.0,
+        from: from.0,
+        currency: currency.to_bytes(),
+        amount: amount.units,
+      }))
+      .collect::<Result<Vec<_>, _>>()
+      .map_err(SnapshotError::Storage)?;
+    transfer_transactions.sort();
+    let mut transaction_ids = self.transaction_ids.iter()
+      .map(|entry| entry.map(|(transaction_id, client_id)| TransactionIdState { tx: transaction_id.0, client: client_id.map(|c| c.0) }))
+      .collect::<Result<Vec<_>, _>>()
+      .map_err(SnapshotError::Storage)?;
+    transaction_ids.sort_unstable();
+    let mut retained_per_client: Vec<_> = self.retained_per_client.iter().collect();
+    retained_per_client.sort_unstable_by_key(|&(&client_id, _)| client_id);
+    // XXX: Transactions whose eviction is pending come first, as the oldest ones,
+    //      so that they are evicted when the snapshot is restored.
+    let retention = self.pending_evictions.iter().map(|&(time, client_id, transaction_id)| RetentionState { client: client_id.0, tx: transaction_id.0, time })
+      .chain(retained_per_client.into_iter()
+        .flat_map(|(client_id, retained)| retained.iter().map(|transaction_id| RetentionState { client: client_id.0, tx: transaction_id.0, time: 0 })))
+      .chain(self.retained_by_time.iter().map(|&(time, client_id, transaction_id)| RetentionState { client: client_id.0, tx: transaction_id.0, time }))
+      .collect();
+    let mut expired_transactions = self.expired_transactions.iter()
+      .map(|entry| entry.map(|((client_id, transaction_id), ())| TransactionKey { client: client_id.0, tx: transaction_id.0 }))
+      .collect::<Result<Vec<_>, _>>()
+      .map_err(SnapshotError::Storage)?;
+    expired_transactions.sort_unstable();
+    Ok(ProcessorSnapshot {
+      currencies: currencies.into_iter()
+        .map(|(currency, precision)| CurrencyState { currency: currency.to_bytes(), precision: precision.decimal_places() })
+        .collect(),
+      accounts,
+      deposit_transactions: retained(&self.deposit_transactions)?,
+      withdrawal_transactions: retained(&self.withdrawal_transactions)?,
+      transfer_transactions,
+      dispute_transactions,
+      transaction_ids,
+      retention,
+      expired_transactions,
+      time: self.time,
+      applied_transactions: self.applied_transactions,
+    })
+  }
+  /// Creates a transaction processor with the state from a snapshot,
+  /// using the given [ProcessorPolicy].
+  ///
+  /// The snapshot is checked for consistency, so that a damaged or hand-edited
+  /// snapshot cannot put the transaction processor in an invalid state.
+  ///
+  /// All currencies in the snapshot must be accepted by the policy, and the precision
+  /// of the amounts in each currency must be the same as that of the policy.
+  ///
+  /// The [crate::DisputeWindow] of the policy may differ from the one that the snapshot was taken
+  /// with. Transactions that are outside of the new dispute window are evicted right away,
+  /// and transactions that were retained without a dispute window count as the oldest ones.
+  ///
+  /// Fails with [SnapshotError::Storage] if the retained transactions cannot be written to their store.
+  pub fn restore (snapshot: ProcessorSnapshot, policy: ProcessorPolicy) -> Result<Self, SnapshotError>
+  {
+    let mut precisions = HashMap::new();
+    for c in snapshot.currencies {
+      let currency = Currency::from_bytes(c.currency).ok_or(SnapshotError::Inconsistent)?;
+      let precision = policy.precision_of(currency).ok_or(SnapshotError::UnknownCurrency(currency))?;
+      if c.precision != precision.decimal_places() {
+        return Err(SnapshotError::PrecisionMismatch { currency, snapshot: c.precision, policy: precision.decimal_places() });
+      }
+      precisions.insert(c.currency, (currency, precision));
+    }
+    let amount = |currency: [u8; 8], units| precisions.get(&currency)
+      .map(|&(currency, precision)| (currency, FractionalAmount::new(units, precision)))
+      .ok_or(SnapshotError::Inconsistent);
+    let mut transaction_processor = Self::with_policy(policy).map_err(SnapshotError::Storage)?;
+    for acc in snapshot.accounts {
+      let mut account = Account { frozen: acc.frozen, ..Account::new(transaction_processor.policy.precision) };
+      for b in acc.balances {
+        let (currency, available_amount) = amount(b.currency, b.available)?;
+        let (_, held_amount) = amount(b.currency, b.held)?;
+        if available_amount.checked_add(held_amount).is_none() {
+          return Err(SnapshotError::Inconsistent);
+        }
+        account.balances.insert(currency, Balance { available_amount, held_amount });
+      }
+      transaction_processor.accounts.insert(ClientId(acc.client), account);
+    }
+    let has_balance = |transaction_processor: &Self, client: u16, currency: Currency| transaction_processor.accounts.get(&ClientId(client))
+      .is_some_and(|acc| acc.balances.contains_key(&currency));
+    for t in snapshot.deposit_transactions {
+      let (currency, amount) = amount(t.currency, t.amount)?;
+      if t.amount < 0 || !has_balance(&transaction_processor, t.client, currency) {
+        return Err(SnapshotError::Inconsistent);
+      }
+      transaction_processor.deposit_transactions.insert((ClientId(t.client), TransactionId(t.tx)), (currency, amount)).map_err(SnapshotError::Storage)?;
+    }
+    for t in snapshot.withdrawal_transactions {
+      let (currency, amount) = amount(t.currency, t.amount)?;
+      if t.amount < 0 || !has_balance(&transaction_processor, t.client, currency) {
+        return Err(SnapshotError::Inconsistent);
+      }
+      transaction_processor.withdrawal_transactions.insert((ClientId(t.client), TransactionId(t.tx)), (currency, amount)).map_err(SnapshotError::Storage)?;
+    }
+    // XXX: The sending client of a transfer must have a balance in the currency of the transfer
+    //      as well, so that the amount can be credited back to them by a chargeback.
+    for t in snapshot.transfer_transactions {
+      let (currency, amount) = amount(t.currency, t.amount)?;
+      if t.amount < 0 || !has_balance(&transaction_processor, t.client, currency) || !has_balance(&transaction_processor, t.from, currency) {
+        return Err(SnapshotError::Inconsistent);
+      }
+      transaction_processor.transfer_transactions.insert((ClientId(t.client), TransactionId(t.tx)), (ClientId(t.from), currency, amount)).map_err(SnapshotError::Storage)?;
+    }
+    for d in snapshot.dispute_transactions {
+      let (currency, amount) = amount(d.currency, d.amount)?;
+      if d.amount < 0 || !has_balance(&transaction_processor, d.client, currency) {
+        return Err(SnapshotError::Inconsistent);
+      }
+      let disputed_transaction = match d.kind {
+        DisputedKind::Deposit => DisputedTransaction::Deposit(currency, amount),
+        DisputedKind::Withdrawal => DisputedTransaction::Withdrawal(currency, amount),
+        DisputedKind::Transfer { from } if has_balance(&transaction_processor, from, currency) =>
+          DisputedTransaction::Transfer(ClientId(from), currency, amount),
+        DisputedKind::Transfer { .. } => return Err(SnapshotError::Inconsistent),
+      };
+      transaction_processor.dispute_transactions.insert((ClientId(d.client), TransactionId(d.tx)), disputed_transaction).map_err(SnapshotError::Storage)?;
+    }
+    for t in snapshot.transaction_ids {
+      transaction_processor.transaction_ids.insert(TransactionId(t.tx), t.client.map(ClientId)).map_err(SnapshotError::Storage)?;
+    }
+    for t in snapshot.expired_transactions {
+      transaction_processor.expired_transactions.insert((ClientId(t.client), TransactionId(t.tx)), ()).map_err(SnapshotError::Storage)?;
+    }
+    transaction_processor.time = snapshot.time;
+    transaction_processor.applied_transactions = snapshot.applied_transactions;
+    transaction_processor.restore_retention(snapshot.retention).map_err(SnapshotError::Storage)?;
+    Ok(transaction_processor)
+  }
+  /// Restores the order in which transactions were retained, for the dispute window of the policy.
+  fn restore_retention (&mut self, retention: Vec<RetentionState>) -> std::io::Result<()>
+  {
+    if self.policy.dispute_window.is_none() {
+      return Ok(());
+    }
+    let listed: HashSet<_> = retention.iter().map(|r| (ClientId(r.client), TransactionId(r.tx))).collect();
+    let mut unlisted = self.deposit_transactions.iter().map(|entry| entry.map(|(k, _)| k))
+      .chain(self.withdrawal_transactions.iter().map(|entry| entry.map(|(k, _)| k)))
+      .chain(self.transfer_transactions.iter().map(|entry| entry.map(|(k, _)| k)))
+      .chain(self.dispute_transactions.iter().map(|entry| entry.map(|(k, _)| k)))
+      .filter(|k| !k.as_ref().is_ok_and(|k| listed.contains(k)))
+      .collect::<Result<Vec<_>, _>>()?;
+    unlisted.sort_unstable_by_key(|&(client_id, transaction_id)| (client_id, transaction_id.0));
+    // XXX: Times of transactions retained for a DisputeWindow::Transactions are all zero,
+    //      and they are otherwise in order already, so the stable sort keeps their order.
+    let mut retention: Vec<_> = unlisted.into_iter().map(|k| (0, k))
+      .chain(retention.into_iter().map(|r| (r.time, (ClientId(r.client), TransactionId(r.tx)))))
+      .collect();
+    retention.sort_by_key(|&(time, _)| time);
+    for (time, k) in retention {
+      self.retain_at(time, k);
+    }
+    self.evict_expired()
+  }
+}
+
+impl ProcessorSnapshot {
+  /// Writes the snapshot, including the header with the version of the snapshot format.
+  pub fn write_to<W: Write> (&self, mut writer: W) -> Result<(), SnapshotError>
+  {
+    writer.write_all(SNAPSHOT_MAGIC)?;
+    writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    bincode::serialize_into(&mut writer, self)?;
+    writer.flush()?;
+    Ok(())
+  }
+  /// Reads a snapshot that was written with [ProcessorSnapshot::write_to].
+  pub fn read_from<R: Read> (mut reader: R) -> Result<Self, SnapshotError>
+  {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != SNAPSHOT_MAGIC {
+      return Err(SnapshotError::NotASnapshot);
+    }
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version != SNAPSHOT_VERSION {
+      return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    Ok(bincode::deserialize_from(reader)?)
+  }
+}
+
+/// Errors which can occur when writing, reading or restoring a [ProcessorSnapshot].
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+  #[error("I/O error")]
+  Io(#[from] std::io::Error),
+  #[error("Failed to access the store of retained transactions")]
+  Storage(#[source] std::io::Error),
+  #[error("Failed to encode or decode snapshot data")]
+  Encoding(#[from] bincode::Error),
+  #[error("Data is not a snapshot")]
+  NotASnapshot,
+  #[error("Unsupported snapshot format version {0}")]
+  UnsupportedVersion(u32),
+  #[error("Snapshot data is inconsistent")]
+  Inconsistent,
+  #[error("Snapshot has amounts in currency \"{currency}\" with {snapshot} decimal places, but the policy has {policy}")]
+  PrecisionMismatch { currency: Currency, snapshot: u8, policy: u8 },
+  #[error("Snapshot has amounts in currency \"{0}\", which is not accepted by the policy")]
+  UnknownCurrency(Currency),
+}
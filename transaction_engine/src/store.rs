@@ -0,0 +1,570 @@
+//! Storage for the transactions that a [crate::TransactionProcessor] retains.
+//!
+//! Transaction IDs are u32 integers as per spec, so an input can have about four billion
+//! deposits, all of which might be disputed later on. Kept in memory, the deposits alone
+//! would take up far more memory than is usually available. The transaction processor
+//! therefore keeps retained transactions, disputes and transaction IDs in a [TransactionStore],
+//! which is chosen with the [Storage] of its [crate::ProcessorPolicy]:
+//!
+//! * With [Storage::Memory], which is the default, each store is a [HashMap].
+//! * With [Storage::Disk], each store is a [DiskStore], which is a hash table in a temporary file.
+//!   The memory used by the transaction processor is then bounded by the number of clients
+//!   rather than by the number of transactions, at the cost of reading from and writing to
+//!   the file for each transaction. The operating system keeps the parts of the file that
+//!   are used the most in its page cache, which can be evicted when memory is needed elsewhere.
+//!
+//! ## Examples
+//!
+//! ```
+//! use transaction_engine::{ClientId, TransactionId};
+//! use transaction_engine::store::{DiskStore, TransactionStore};
+//!
+//! let mut store: DiskStore<TransactionId, ClientId> = DiskStore::create(std::env::temp_dir()).unwrap();
+//! for tx in 0..10_000u32 {
+//!   store.insert(TransactionId::from(tx), ClientId::from((tx % 7) as u16)).unwrap();
+//! }
+//! store.remove(&TransactionId::from(3u32)).unwrap();
+//!
+//! assert_eq!(store.len(), 9_999);
+//! assert_eq!(store.get(&TransactionId::from(10u32)).unwrap(), Some(ClientId::from(3u16)));
+//! assert_eq!(store.get(&TransactionId::from(3u32)).unwrap(), None);
+//! let entries = store.iter().collect::<std::io::Result<Vec<_>>>().unwrap();
+//! assert_eq!(entries.iter().filter(|&&(_, client)| client == ClientId::from(0u16)).count(), 1_429);
+//! ```
+//!
+//! A transaction processor with disk storage processes transactions the same way as one that
+//! keeps everything in memory.
+//!
+//! ```
+//! use transaction_engine::{TransactionProcessor, ProcessorPolicy, ClientId, TransactionId};
+//! use transaction_engine::store::Storage;
+//!
+//! let policy = ProcessorPolicy { storage: Storage::Disk(std::env::temp_dir()), ..Default::default() };
+//! let mut transaction_processor = TransactionProcessor::with_policy(policy).unwrap();
+//!
+//! let client_a = ClientId::from(1u16);
+//! let amount = "1.5".try_into().unwrap();
+//! for tx in 1..=100u32 {
+//!   transaction_processor.deposit(client_a, TransactionId::from(tx), amount).unwrap();
+//! }
+//! transaction_processor.dispute(client_a, TransactionId::from(7u32)).unwrap();
+//! transaction_processor.chargeback(client_a, TransactionId::from(7u32)).unwrap();
+//!
+//! assert!(transaction_processor.deposit(client_a, TransactionId::from(7u32), amount).is_err());
+//! assert!(transaction_processor.dispute(client_a, TransactionId::from(7u32)).is_err());
+//! assert_eq!(transaction_processor.retained_transactions(), 99);
+//! ```
+//!
+//! Errors in reading from or writing to the files of disk stores are returned from the
+//! transaction processor as the storage error of the kind of transaction that was being processed.
+//!
+//! ```
+//! use transaction_engine::{TransactionProcessor, ProcessorPolicy};
+//! use transaction_engine::store::Storage;
+//!
+//! let policy = ProcessorPolicy { storage: Storage::Disk("/nonexistent/directory".into()), ..Default::default() };
+//! assert!(TransactionProcessor::with_policy(policy).is_err());
+//! ```
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::hash::Hash;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{ClientId, TransactionId, Currency, FractionalAmount, Precision, DisputedTransaction};
+
+/// Where a [crate::TransactionProcessor] keeps the transactions that it retains.
+/// See the [module level docs](self).
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub enum Storage {
+  /// In memory.
+  #[default]
+  Memory,
+  /// In temporary files in the given directory, which are removed when the transaction
+  /// processor is dropped.
+  Disk(PathBuf),
+}
+
+impl Storage {
+  /// Creates an empty store.
+  ///
+  /// For [Storage::Disk], this fails if the file for the store cannot be created.
+  pub(crate) fn create<K, V> (&self) -> io::Result<Box<dyn TransactionStore<K, V>>>
+  where
+    K: FixedLen + Hash + Eq + Send + 'static,
+    V: FixedLen + Send + 'static,
+  {
+    Ok(match self {
+      Storage::Memory => Box::new(HashMap::new()),
+      Storage::Disk(dir) => Box::new(DiskStore::create(dir)?),
+    })
+  }
+}
+
+/// A map from keys to values, such as from transactions to their amounts,
+/// that a [crate::TransactionProcessor] keeps its retained transactions in.
+///
+/// Operations that may have to read from or write to a file return I/O errors, which
+/// never happen for stores in memory.
+pub trait TransactionStore<K, V>: Send {
+  /// Returns the value for the key, if any.
+  fn get (&self, key: &K) -> io::Result<Option<V>>;
+  /// Inserts a value for the key, and returns the value that the key had before, if any.
+  fn insert (&mut self, key: K, value: V) -> io::Result<Option<V>>;
+  /// Removes the key, and returns the value that it had, if any.
+  fn remove (&mut self, key: &K) -> io::Result<Option<V>>;
+  /// Number of keys in the store.
+  fn len (&self) -> usize;
+  /// Returns all keys and their values, in no particular order.
+  fn iter (&self) -> Box<dyn Iterator<Item = io::Result<(K, V)>> + '_>;
+  /// Whether the store has a value for the key.
+  fn contains_key (&self, key: &K) -> io::Result<bool>
+  {
+    Ok(self.get(key)?.is_some())
+  }
+  /// Whether the store is empty.
+  fn is_empty (&self) -> bool
+  {
+    self.len() == 0
+  }
+}
+
+impl<K, V> TransactionStore<K, V> for HashMap<K, V>
+where
+  K: Copy + Hash + Eq + Send,
+  V: Copy + Send,
+{
+  fn get (&self, key: &K) -> io::Result<Option<V>>
+  {
+    Ok(HashMap::get(self, key).copied())
+  }
+  fn insert (&mut self, key: K, value: V) -> io::Result<Option<V>>
+  {
+    Ok(HashMap::insert(self, key, value))
+  }
+  fn remove (&mut self, key: &K) -> io::Result<Option<V>>
+  {
+    Ok(HashMap::remove(self, key))
+  }
+  fn len (&self) -> usize
+  {
+    HashMap::len(self)
+  }
+  fn iter (&self) -> Box<dyn Iterator<Item = io::Result<(K, V)>> + '_>
+  {
+    Box::new(HashMap::iter(self).map(|(&k, &v)| Ok((k, v))))
+  }
+}
+
+/// Keys and values that are encoded in a fixed number of bytes, so that they can be kept in a [DiskStore].
+pub trait FixedLen: Copy {
+  /// Number of bytes of the encoding.
+  const LEN: usize;
+  /// Encodes the value into a buffer of exactly [Self::LEN] bytes.
+  fn encode (&self, buf: &mut [u8]);
+  /// Decodes a value from a buffer of exactly [Self::LEN] bytes, as encoded by [Self::encode].
+  fn decode (buf: &[u8]) -> Self;
+}
+
+impl FixedLen for () {
+  const LEN: usize = 0;
+  fn encode (&self, _buf: &mut [u8]) {}
+  fn decode (_buf: &[u8]) -> Self {}
+}
+
+impl FixedLen for ClientId {
+  const LEN: usize = 2;
+  fn encode (&self, buf: &mut [u8])
+  {
+    buf.copy_from_slice(&self.0.to_le_bytes());
+  }
+  fn decode (buf: &[u8]) -> Self
+  {
+    // XXX: The unwrap is fine because the buffer is of the exact length of the array.
+    ClientId(u16::from_le_bytes(buf.try_into().unwrap()))
+  }
+}
+
+impl FixedLen for TransactionId {
+  const LEN: usize = 4;
+  fn encode (&self, buf: &mut [u8])
+  {
+    buf.copy_from_slice(&self.0.to_le_bytes());
+  }
+  fn decode (buf: &[u8]) -> Self
+  {
+    // XXX: The unwrap is fine because the buffer is of the exact length of the array.
+    TransactionId(u32::from_le_bytes(buf.try_into().unwrap()))
+  }
+}
+
+impl FixedLen for Currency {
+  const LEN: usize = 8;
+  fn encode (&self, buf: &mut [u8])
+  {
+    buf.copy_from_slice(&self.0);
+  }
+  fn decode (buf: &[u8]) -> Self
+  {
+    // XXX: The unwrap is fine because the buffer is of the exact length of the array.
+    Currency(buf.try_into().unwrap())
+  }
+}
+
+impl FixedLen for FractionalAmount {
+  const LEN: usize = 9;
+  fn encode (&self, buf: &mut [u8])
+  {
+    buf[0..8].copy_from_slice(&self.units.to_le_bytes());
+    buf[8] = self.precision.0;
+  }
+  fn decode (buf: &[u8]) -> Self
+  {
+    // XXX: The unwrap is fine because the slice is of the exact length of the array,
+    //      and the precision is valid because it was encoded from a valid precision.
+    FractionalAmount::new(i64::from_le_bytes(buf[0..8].try_into().unwrap()), Precision(buf[8]))
+  }
+}
+
+impl<A: FixedLen, B: FixedLen> FixedLen for (A, B) {
+  const LEN: usize = A::LEN + B::LEN;
+  fn encode (&self, buf: &mut [u8])
+  {
+    self.0.encode(&mut buf[..A::LEN]);
+    self.1.encode(&mut buf[A::LEN..]);
+  }
+  fn decode (buf: &[u8]) -> Self
+  {
+    (A::decode(&buf[..A::LEN]), B::decode(&buf[A::LEN..]))
+  }
+}
+
+impl<A: FixedLen, B: FixedLen, C: FixedLen> FixedLen for (A, B, C) {
+  const LEN: usize = A::LEN + B::LEN + C::LEN;
+  fn encode (&self, buf: &mut [u8])
+  {
+    self.0.encode(&mut buf[..A::LEN]);
+    (self.1, self.2).encode(&mut buf[A::LEN..]);
+  }
+  fn decode (buf: &[u8]) -> Self
+  {
+    let (b, c) = <(B, C)>::decode(&buf[A::LEN..]);
+    (A::decode(&buf[..A::LEN]), b, c)
+  }
+}
+
+/// Encoded as a byte that tells whether there is a value, followed by the value (zeros if there is none).
+impl<T: FixedLen> FixedLen for Option<T> {
+  const LEN: usize = 1 + T::LEN;
+  fn encode (&self, buf: &mut [u8])
+  {
+    match self {
+      Some(value) => {
+        buf[0] = 1;
+        value.encode(&mut buf[1..]);
+      },
+      None => buf.fill(0),
+    }
+  }
+  fn decode (buf: &[u8]) -> Self
+  {
+    (buf[0] != 0).then(|| T::decode(&buf[1..]))
+  }
+}
+
+/// Encoded as the type of the disputed transaction, followed by the sending client
+/// (zero for deposits and withdrawals), the currency and the amount.
+impl FixedLen for DisputedTransaction {
+  const LEN: usize = 1 + <(ClientId, Currency, FractionalAmount)>::LEN;
+  fn encode (&self, buf: &mut [u8])
+  {
+    let (kind, transfer) = match *self {
+      DisputedTransaction::Deposit(currency, amount) => (0, (ClientId(0), currency, amount)),
+      DisputedTransaction::Withdrawal(currency, amount) => (1, (ClientId(0), currency, amount)),
+      DisputedTransaction::Transfer(from, currency, amount) => (2, (from, currency, amount)),
+    };
+    buf[0] = kind;
+    transfer.encode(&mut buf[1..]);
+  }
+  fn decode (buf: &[u8]) -> Self
+  {
+    let (from, currency, amount) = <(ClientId, Currency, FractionalAmount)>::decode(&buf[1..]);
+    match buf[0] {
+      0 => DisputedTransaction::Deposit(currency, amount),
+      1 => DisputedTransaction::Withdrawal(currency, amount),
+      _ => DisputedTransaction::Transfer(from, currency, amount),
+    }
+  }
+}
+
+/// Number of slots that a new [DiskStore] starts out with.
+const INITIAL_SLOTS: u64 = 1 << 12;
+
+/// Number of bytes that are read at once when looking for a key in a [DiskStore].
+const PROBE_BUF_LEN: usize = 512;
+
+/// Number of bytes that are read at once when going through all slots of a [DiskStore].
+const SCAN_BUF_LEN: usize = 1 << 16;
+
+/// Counter for the names of the files of disk stores, which are unique within the process.
+static STORE_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// A hash table that is kept in a temporary file rather than in memory.
+///
+/// The file consists of slots of a fixed length, each of which is either empty or holds
+/// a key and its value. A key is kept in the first empty slot at or after the slot that
+/// its hash points to, which is known as linear probing. The file has at least twice as
+/// many slots as there are keys, and it is rebuilt with twice the number of slots when
+/// it fills up beyond that. Apart from a few counters, nothing is kept in memory.
+///
+/// The file is created in the given directory and removed when the store is dropped.
+///
+/// I/O errors of the file are returned from the operation that ran into them. Since an operation
+/// may have changed part of the file before the error, the contents of the store, and with them
+/// the state of the transaction processor that uses the store, can no longer be relied upon
+/// after an error in any operation that changes the store.
+pub struct DiskStore<K, V> {
+  file: File,
+  path: PathBuf,
+  /// Number of slots in the file, which is always a power of two.
+  slots: u64,
+  len: usize,
+  _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: FixedLen, V: FixedLen> DiskStore<K, V> {
+  /// Length of a slot; a byte that tells whether the slot is in use, followed by the key and the value.
+  const SLOT_LEN: usize = 1 + K::LEN + V::LEN;
+
+  /// Creates an empty store, in a new file in the given directory.
+  pub fn create<P: AsRef<Path>> (dir: P) -> io::Result<Self>
+  {
+    assert!(Self::SLOT_LEN <= PROBE_BUF_LEN, "keys and values of disk stores must fit in a probe buffer");
+    let (file, path) = create_file(dir.as_ref(), INITIAL_SLOTS * Self::SLOT_LEN as u64)?;
+    Ok(Self {
+      file,
+      path,
+      slots: INITIAL_SLOTS,
+      len: 0,
+      _types: PhantomData,
+    })
+  }
+  /// Finds the slot of the key. Returns the index of the slot along with the value for the key,
+  /// or the index of the empty slot where the key would go if the store does not have the key.
+  fn find (&self, key: &K) -> io::Result<(u64, Option<V>)>
+  {
+    let mut encoded = [0u8; PROBE_BUF_LEN];
+    key.encode(&mut encoded[..K::LEN]);
+    let encoded = &encoded[..K::LEN];
+    let mut buf = [0u8; PROBE_BUF_LEN];
+    let mut index = self.home(encoded);
+    // XXX: The loop ends because at least half of the slots are always empty.
+    loop {
+      let count = ((PROBE_BUF_LEN / Self::SLOT_LEN) as u64).min(self.slots - index);
+      let buf = &mut buf[..count as usize * Self::SLOT_LEN];
+      self.read_at(buf, index)?;
+      for (i, slot) in buf.chunks_exact(Self::SLOT_LEN).enumerate() {
+        if slot[0] == 0 {
+          return Ok((index + i as u64, None));
+        } else if &slot[1..1 + K::LEN] == encoded {
+          return Ok((index + i as u64, Some(V::decode(&slot[1 + K::LEN..]))));
+        }
+      }
+      index = (index + count) & (self.slots - 1);
+    }
+  }
+  /// Index of the slot that the hash of an encoded key points to.
+  fn home (&self, encoded: &[u8]) -> u64
+  {
+    // XXX: FNV-1a, followed by the finalizer of SplitMix64 to spread the bits of the short keys.
+    let mut hash = encoded.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, &b| (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3));
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (hash ^ (hash >> 31)) & (self.slots - 1)
+  }
+  /// Writes the key and the value into the slot with the given index.
+  fn write_slot (&self, index: u64, key: &K, value: &V) -> io::Result<()>
+  {
+    let mut buf = [0u8; PROBE_BUF_LEN];
+    let slot = &mut buf[..Self::SLOT_LEN];
+    slot[0] = 1;
+    key.encode(&mut slot[1..1 + K::LEN]);
+    value.encode(&mut slot[1 + K::LEN..]);
+    self.write_at(slot, index)
+  }
+  /// Doubles the number of slots, by moving all keys into a new file.
+  ///
+  /// The store is only replaced by the new file once all keys have been moved,
+  /// so that the store is left as it was if moving the keys fails.
+  fn grow (&mut self) -> io::Result<()>
+  {
+    let slots = self.slots * 2;
+    let dir = self.path.parent().unwrap_or(Path::new("."));
+    let (file, path) = create_file(dir, slots * Self::SLOT_LEN as u64)?;
+    let grown = Self { file, path, slots, len: self.len, _types: PhantomData };
+    for entry in self.iter() {
+      let (key, value) = entry?;
+      let (index, _) = grown.find(&key)?;
+      grown.write_slot(index, &key, &value)?;
+    }
+    *self = grown;
+    Ok(())
+  }
+  fn read_at (&self, buf: &mut [u8], index: u64) -> io::Result<()>
+  {
+    read_exact_at(&self.file, buf, index * Self::SLOT_LEN as u64)
+  }
+  fn write_at (&self, buf: &[u8], index: u64) -> io::Result<()>
+  {
+    write_all_at(&self.file, buf, index * Self::SLOT_LEN as u64)
+  }
+}
+
+impl<K: FixedLen, V: FixedLen> TransactionStore<K, V> for DiskStore<K, V> {
+  fn get (&self, key: &K) -> io::Result<Option<V>>
+  {
+    Ok(self.find(key)?.1)
+  }
+  fn insert (&mut self, key: K, value: V) -> io::Result<Option<V>>
+  {
+    let (mut index, previous) = self.find(&key)?;
+    if previous.is_none() && (self.len as u64 + 1) * 2 > self.slots {
+      self.grow()?;
+      index = self.find(&key)?.0;
+    }
+    self.write_slot(index, &key, &value)?;
+    if previous.is_none() {
+      self.len += 1;
+    }
+    Ok(previous)
+  }
+  fn remove (&mut self, key: &K) -> io::Result<Option<V>>
+  {
+    let (mut hole, value) = self.find(key)?;
+    if value.is_none() {
+      return Ok(None);
+    }
+    // XXX: Rather than marking the slot as deleted, the keys after it are moved back into
+    //      the hole for as long as that brings them closer to their home slot, so that
+    //      lookups never have to skip over deleted slots.
+    let mask = self.slots - 1;
+    let mut slot = [0u8; PROBE_BUF_LEN];
+    let slot = &mut slot[..Self::SLOT_LEN];
+    let mut index = hole;
+    loop {
+      index = (index + 1) & mask;
+      self.read_at(slot, index)?;
+      if slot[0] == 0 {
+        break;
+      }
+      let home = self.home(&slot[1..1 + K::LEN]);
+      if index.wrapping_sub(home) & mask >= index.wrapping_sub(hole) & mask {
+        self.write_at(slot, hole)?;
+        hole = index;
+      }
+    }
+    self.write_at(&[0u8; PROBE_BUF_LEN][..Self::SLOT_LEN], hole)?;
+    self.len -= 1;
+    Ok(value)
+  }
+  fn len (&self) -> usize
+  {
+    self.len
+  }
+  fn iter (&self) -> Box<dyn Iterator<Item = io::Result<(K, V)>> + '_>
+  {
+    let slots_per_read = (SCAN_BUF_LEN / Self::SLOT_LEN) as u64;
+    let mut buf = vec![0u8; slots_per_read as usize * Self::SLOT_LEN];
+    Box::new((0..self.slots).step_by(slots_per_read as usize).flat_map(move |index| {
+      let count = slots_per_read.min(self.slots - index);
+      let buf = &mut buf[..count as usize * Self::SLOT_LEN];
+      if let Err(e) = self.read_at(buf, index) {
+        return vec![Err(e)];
+      }
+      buf.chunks_exact(Self::SLOT_LEN)
+        .filter(|slot| slot[0] != 0)
+        .map(|slot| Ok((K::decode(&slot[1..1 + K::LEN]), V::decode(&slot[1 + K::LEN..]))))
+        .collect::<Vec<_>>()
+    }))
+  }
+}
+
+impl<K, V> Drop for DiskStore<K, V> {
+  fn drop (&mut self)
+  {
+    let _ = std::fs::remove_file(&self.path);
+  }
+}
+
+/// Creates a new file of the given length for a disk store in the given directory.
+fn create_file (dir: &Path, len: u64) -> io::Result<(File, PathBuf)>
+{
+  let path = dir.join(format!("transaction_engine_{}_{}.store", std::process::id(), STORE_FILES.fetch_add(1, Ordering::Relaxed)));
+  let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+  // XXX: The file is sparse, and the zeros that it reads as are empty slots.
+  file.set_len(len)?;
+  Ok((file, path))
+}
+
+#[cfg(unix)]
+fn read_exact_at (file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()>
+{
+  std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_all_at (file: &File, buf: &[u8], offset: u64) -> std::io::Result<()>
+{
+  std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at (file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()>
+{
+  while !buf.is_empty() {
+    match std::os::windows::fs::FileExt::seek_read(file, buf, offset)? {
+      0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+      n => {
+        buf = &mut buf[n..];
+        offset += n as u64;
+      },
+    }
+  }
+  Ok(())
+}
+
+#[cfg(windows)]
+fn write_all_at (file: &File, mut buf: &[u8], mut offset: u64) -> std::io::Result<()>
+{
+  while !buf.is_empty() {
+    match std::os::windows::fs::FileExt::seek_write(file, buf, offset)? {
+      0 => return Err(std::io::ErrorKind::WriteZero.into()),
+      n => {
+        buf = &buf[n..];
+        offset += n as u64;
+      },
+    }
+  }
+  Ok(())
+}
+
+// XXX: Seeking moves the position of the file, which is fine because a store is only ever used
+//      by one thread at a time, and nothing else relies on the position of the file.
+#[cfg(not(any(unix, windows)))]
+fn read_exact_at (mut file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()>
+{
+  use std::io::{Read, Seek, SeekFrom};
+  file.seek(SeekFrom::Start(offset))?;
+  file.read_exact(buf)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn write_all_at (mut file: &File, buf: &[u8], offset: u64) -> std::io::Result<()>
+{
+  use std::io::{Seek, SeekFrom, Write};
+  file.seek(SeekFrom::Start(offset))?;
+  file.write_all(buf)
+}
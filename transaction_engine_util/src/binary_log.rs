@@ -0,0 +1,284 @@
+//! Compact binary encoding of transactions, for input that is processed repeatedly.
+//!
+//! Parsing of text formats such as CSV takes up most of the time that it takes to process
+//! a large input. Converting the input to a binary transaction log once with [BinaryLogWriter]
+//! means that the text only needs to be parsed once, after which the binary transaction log
+//! can be read with [BinaryLogReader] as many times as needed.
+//!
+//! A binary transaction log starts with a header that consists of the magic number
+//! `TTETXLOG` followed by the version of the format, as a little-endian u32. Binary
+//! transaction logs written with a different version of the format are rejected.
+//!
+//! The header is followed by the records, one per transaction. Each record starts with
+//! a single byte that holds the length of the rest of the record. In the current version
+//! of the format, the rest of the record is 34 bytes, with the following fields in order,
+//! where integers are little-endian:
+//!
+//! * The client ID, as a u16.
+//! * The transaction ID, as a u32.
+//! * The kind of transaction, as a single byte. 0 is a deposit, 1 is a withdrawal,
+//!   2 is a dispute, 3 is a resolve, 4 is a chargeback, 5 is a lock, 6 is an unlock
+//!   and 7 is a transfer.
+//! * The amount, as an i64 number of the smallest units at the precision of the amount.
+//!   The amount is zero for disputes, resolves, chargebacks, locks and unlocks.
+//! * The precision of the amount, as a single byte that holds the number of decimal places.
+//! * The currency code, as 8 bytes of ASCII padded with zeros. The default currency
+//!   is all zeros.
+//! * The client ID of the receiving client of a transfer, as a u16. This is zero for
+//!   other kinds of transactions.
+//! * The timestamp of the transaction, as a u64 number of seconds since the Unix epoch.
+//!   This is zero for transactions without a timestamp.
+//!
+//! For locks and unlocks, these fields are followed by the reason, as UTF-8, which takes up
+//! the remaining bytes of the record. For other kinds of transactions, records that are longer
+//! than this are accepted, and the remaining bytes of the record are ignored, so that fields
+//! can be added to the end of records in the future.
+//!
+//! ## Examples
+//!
+//! ```
+//! use transaction_engine::{Transaction, TransactionKind, ClientId, TransactionId};
+//! use transaction_engine_util::binary_log::{BinaryLogReader, BinaryLogWriter};
+//!
+//! let client_a = ClientId::from(1u16);
+//! let deposit = Transaction::new(client_a, TransactionId::from(1u32), TransactionKind::Deposit("1.5".try_into().unwrap()));
+//! let dispute = Transaction::new(client_a, TransactionId::from(1u32), TransactionKind::Dispute);
+//! let lock = Transaction::new(client_a, TransactionId::from(2u32), TransactionKind::Lock("Suspected fraud".parse().unwrap()));
+//! let transfer = Transaction::new(client_a, TransactionId::from(3u32), TransactionKind::Transfer { to: ClientId::from(2u16), amount: "0.5".try_into().unwrap() })
+//!   .at(1_700_000_000);
+//!
+//! let mut wtr = BinaryLogWriter::from_writer(vec![]).unwrap();
+//! wtr.write(&deposit).unwrap();
+//! wtr.write(&dispute).unwrap();
+//! wtr.write(&lock).unwrap();
+//! wtr.write(&transfer).unwrap();
+//! let data = wtr.into_inner();
+//! assert_eq!(data.len(), 12 + 4 * 35 + "Suspected fraud".len());
+//!
+//! let transactions: Vec<_> = BinaryLogReader::from_reader(&data[..]).unwrap()
+//!   .collect::<Result<_, _>>().unwrap();
+//! assert_eq!(transactions, vec![deposit, dispute, lock, transfer]);
+//! ```
+
+use std::io::{ErrorKind, Read, Write};
+
+use thiserror::Error;
+
+use transaction_engine::{ClientId, TransactionId, Currency, FractionalAmount, Precision, Reason, Transaction, TransactionKind};
+
+/// Magic number at the start of binary transaction logs.
+const BINARY_LOG_MAGIC: &[u8; 8] = b"TTETXLOG";
+
+/// Version of the binary transaction log format. This must be incremented whenever
+/// the format changes in a way that readers of the current version cannot handle.
+pub const BINARY_LOG_VERSION: u32 = 5;
+
+/// Length of a record, not counting the byte that holds the length.
+const RECORD_LEN: usize = 34;
+
+/// Length of the header of a binary transaction log.
+const HEADER_LEN: u64 = 12;
+
+/// Writes transactions as a binary transaction log.
+///
+/// The writer is not buffered, so for writing to a file it should be wrapped in a [std::io::BufWriter].
+pub struct BinaryLogWriter<W: Write> {
+  wtr: W,
+}
+
+impl<W: Write> BinaryLogWriter<W> {
+  /// Creates a binary transaction log writer, and writes the header of the binary transaction log.
+  pub fn from_writer (mut wtr: W) -> std::io::Result<Self>
+  {
+    wtr.write_all(BINARY_LOG_MAGIC)?;
+    wtr.write_all(&BINARY_LOG_VERSION.to_le_bytes())?;
+    Ok(Self {
+      wtr,
+    })
+  }
+  /// Writes a transaction.
+  pub fn write (&mut self, transaction: &Transaction) -> std::io::Result<()>
+  {
+    let (kind, amount, to, reason) = match &transaction.kind {
+      TransactionKind::Deposit(amount) => (0u8, *amount, 0u16, ""),
+      TransactionKind::Withdrawal(amount) => (1, *amount, 0, ""),
+      TransactionKind::Dispute => (2, FractionalAmount::ZERO, 0, ""),
+      TransactionKind::Resolve => (3, FractionalAmount::ZERO, 0, ""),
+      TransactionKind::Chargeback => (4, FractionalAmount::ZERO, 0, ""),
+      TransactionKind::Lock(reason) => (5, FractionalAmount::ZERO, 0, reason.as_str()),
+      TransactionKind::Unlock(reason) => (6, FractionalAmount::ZERO, 0, reason.as_str()),
+      TransactionKind::Transfer { to, amount } => (7, *amount, u16::from(*to), ""),
+    };
+    let mut buf = [0u8; 1 + RECORD_LEN];
+    // XXX: The cast is fine because reasons are at most Reason::MAX_LEN bytes long,
+    //      which leaves room for the fields that come before the reason.
+    buf[0] = (RECORD_LEN + reason.len()) as u8;
+    buf[1..3].copy_from_slice(&u16::from(transaction.client).to_le_bytes());
+    buf[3..7].copy_from_slice(&u32::from(transaction.tx).to_le_bytes());
+    buf[7] = kind;
+    buf[8..16].copy_from_slice(&amount.units().to_le_bytes());
+    buf[16] = amount.precision().decimal_places();
+    buf[17..25].copy_from_slice(&transaction.currency.to_bytes());
+    buf[25..27].copy_from_slice(&to.to_le_bytes());
+    buf[27..35].copy_from_slice(&transaction.timestamp.unwrap_or(0).to_le_bytes());
+    self.wtr.write_all(&buf)?;
+    self.wtr.write_all(reason.as_bytes())
+  }
+  pub fn flush (&mut self) -> std::io::Result<()>
+  {
+    self.wtr.flush()
+  }
+  /// Returns the underlying writer.
+  pub fn into_inner (self) -> W
+  {
+    self.wtr
+  }
+}
+
+/// Reads transactions from a binary transaction log.
+///
+/// The reader is not buffered, so for reading from a file it should be wrapped in a [std::io::BufReader].
+pub struct BinaryLogReader<R: Read> {
+  rdr: R,
+  /// Byte offset in the binary transaction log of the next record.
+  offset: u64,
+  /// Buffer for the record that is being read, which is reused between records.
+  record: Vec<u8>,
+}
+
+impl<R: Read> BinaryLogReader<R> {
+  /// Creates a binary transaction log reader, and reads the header of the binary transaction log.
+  pub fn from_reader (mut rdr: R) -> Result<Self, BinaryLogError>
+  {
+    let mut magic = [0u8; 8];
+    rdr.read_exact(&mut magic)?;
+    if &magic != BINARY_LOG_MAGIC {
+      return Err(BinaryLogError::NotABinaryLog);
+    }
+    let mut version = [0u8; 4];
+    rdr.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version != BINARY_LOG_VERSION {
+      return Err(BinaryLogError::UnsupportedVersion(version));
+    }
+    Ok(Self {
+      rdr,
+      offset: HEADER_LEN,
+      record: Vec::with_capacity(RECORD_LEN),
+    })
+  }
+  /// Reads the next record into the record buffer. Returns false at the end of the binary transaction log.
+  fn read_record (&mut self, offset: u64) -> Result<bool, BinaryLogError>
+  {
+    let mut len = [0u8; 1];
+    match self.rdr.read_exact(&mut len) {
+      Ok(()) => {},
+      Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+      Err(e) => return Err(e.into()),
+    }
+    let len = usize::from(len[0]);
+    self.record.resize(len, 0);
+    match self.rdr.read_exact(&mut self.record) {
+      Ok(()) => {},
+      Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Err(BinaryLogError::TruncatedRecord { offset }),
+      Err(e) => return Err(e.into()),
+    }
+    self.offset += 1 + len as u64;
+    if len < RECORD_LEN {
+      return Err(BinaryLogError::RecordTooShort { offset });
+    }
+    Ok(true)
+  }
+  /// Decodes the record in the record buffer.
+  fn decode_record (&self, offset: u64) -> Result<Transaction, BinaryLogError>
+  {
+    let buf = &self.record;
+    // XXX: The unwraps are fine because the slices are of the exact lengths of the arrays.
+    let client = ClientId::from(u16::from_le_bytes(buf[0..2].try_into().unwrap()));
+    let tx = TransactionId::from(u32::from_le_bytes(buf[2..6].try_into().unwrap()));
+    let precision = Precision::new(buf[15]).ok_or(BinaryLogError::InvalidPrecision { offset, decimal_places: buf[15] })?;
+    let amount = FractionalAmount::new(i64::from_le_bytes(buf[7..15].try_into().unwrap()), precision);
+    let currency = Currency::from_bytes(buf[16..24].try_into().unwrap()).ok_or(BinaryLogError::InvalidCurrency { offset })?;
+    let to = u16::from_le_bytes(buf[24..26].try_into().unwrap());
+    let timestamp = u64::from_le_bytes(buf[26..34].try_into().unwrap());
+    let kind = match buf[6] {
+      0..=6 if to != 0 => return Err(BinaryLogError::UnexpectedRecipient { offset }),
+      0 => TransactionKind::Deposit(amount),
+      1 => TransactionKind::Withdrawal(amount),
+      2..=6 if amount != FractionalAmount::ZERO => return Err(BinaryLogError::UnexpectedAmount { offset }),
+      2 => TransactionKind::Dispute,
+      3 => TransactionKind::Resolve,
+      4 => TransactionKind::Chargeback,
+      5 => TransactionKind::Lock(self.decode_reason(offset)?),
+      6 => TransactionKind::Unlock(self.decode_reason(offset)?),
+      7 => TransactionKind::Transfer { to: ClientId::from(to), amount },
+      kind => return Err(BinaryLogError::UnknownKind { offset, kind }),
+    };
+    let transaction = Transaction::with_currency(client, tx, currency, kind);
+    Ok(match timestamp {
+      0 => transaction,
+      timestamp => transaction.at(timestamp),
+    })
+  }
+  /// Decodes the reason of a lock or unlock from the remaining bytes of the record in the record buffer.
+  fn decode_reason (&self, offset: u64) -> Result<Reason, BinaryLogError>
+  {
+    std::str::from_utf8(&self.record[RECORD_LEN..]).ok()
+      .and_then(|reason| reason.parse().ok())
+      .ok_or(BinaryLogError::InvalidReason { offset })
+  }
+}
+
+impl<R: Read> Iterator for BinaryLogReader<R> {
+  type Item = Result<Transaction, BinaryLogError>;
+  fn next (&mut self) -> Option<Self::Item>
+  {
+    let offset = self.offset;
+    match self.read_record(offset) {
+      Ok(true) => Some(self.decode_record(offset)),
+      Ok(false) => None,
+      Err(e) => Some(Err(e)),
+    }
+  }
+}
+
+/// Errors which can occur when reading a binary transaction log using [BinaryLogReader].
+///
+/// Errors for individual records include the byte offset of the record in the binary transaction log.
+#[derive(Error, Debug)]
+pub enum BinaryLogError {
+  #[error("I/O error")]
+  Io(#[from] std::io::Error),
+  #[error("Data is not a binary transaction log")]
+  NotABinaryLog,
+  #[error("Unsupported binary transaction log format version {0}")]
+  UnsupportedVersion(u32),
+  #[error("Record at byte offset {offset} is truncated")]
+  TruncatedRecord { offset: u64 },
+  #[error("Record at byte offset {offset} is too short")]
+  RecordTooShort { offset: u64 },
+  #[error("Record at byte offset {offset} has unknown transaction kind {kind}")]
+  UnknownKind { offset: u64, kind: u8 },
+  #[error("Record at byte offset {offset} specifies an amount for a transaction kind that has no amount")]
+  UnexpectedAmount { offset: u64 },
+  #[error("Record at byte offset {offset} specifies a receiving client for a transaction kind other than transfer")]
+  UnexpectedRecipient { offset: u64 },
+  #[error("Record at byte offset {offset} has an amount with unsupported precision of {decimal_places} decimal places")]
+  InvalidPrecision { offset: u64, decimal_places: u8 },
+  #[error("Record at byte offset {offset} has an invalid currency code")]
+  InvalidCurrency { offset: u64 },
+  #[error("Record at byte offset {offset} has a missing or invalid reason")]
+  InvalidReason { offset: u64 },
+}
+
+impl BinaryLogError {
+  /// Whether no further records can be read after the error, either because of
+  /// an I/O error or because the binary transaction log ends with a truncated record.
+  ///
+  /// Other errors only concern the record that was being read, and reading
+  /// can continue with the next record.
+  pub fn is_io_error (&self) -> bool
+  {
+    matches!(self, Self::Io(_) | Self::TruncatedRecord { .. })
+  }
+}
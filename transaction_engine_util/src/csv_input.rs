@@ -1,54 +1,108 @@
 //! Data structures and logic used for input of CSV data.
+//!
+//! CSV data can be read from a file, or from any other source that implements [std::io::Read],
+//! such as `stdin` or the output of a decompressor.
+//!
+//! Input that is compressed with gzip or zstd can be decompressed while it is being read,
+//! by wrapping the reader with [decompress]. The compression is detected from the first
+//! bytes of the data, so it does not matter what the name of the file is, or whether there
+//! is a file at all. Since decompression happens while reading, compressed input is never
+//! loaded into memory in full.
+//!
+//! ## Examples
+//!
+//! ```
+//! use std::collections::HashMap;
+//! use transaction_engine::{TransactionKind, ClientId, Currency, FractionalAmount, ParseOptions, Precision};
+//! use transaction_engine_util::csv_input::{CSVInputParser, CSVInputParserBuilder, decompress};
+//!
+//! let data = "type,client,tx,amount\ndeposit,1,1,1.5\ndispute,1,1,\n";
+//! let transactions: Vec<_> = CSVInputParser::from_reader(data.as_bytes()).unwrap()
+//!   .collect::<Result<_, _>>().unwrap();
+//! assert_eq!(transactions.len(), 2);
+//! assert_eq!(transactions[1].kind, TransactionKind::Dispute);
+//!
+//! // With flexible rows, the trailing empty amount of disputes, resolves
+//! // and chargebacks can be left out entirely.
+//! let data = "type;client;tx;amount\ndeposit;1;1;1.5\ndispute;1;1\n";
+//! let transactions: Vec<_> = CSVInputParserBuilder::new()
+//!   .delimiter(b';')
+//!   .flexible(true)
+//!   .from_reader(data.as_bytes()).unwrap()
+//!   .collect::<Result<_, _>>().unwrap();
+//! assert_eq!(transactions.len(), 2);
+//!
+//! // Amounts are parsed with the precision given in the parse options.
+//! let data = "type,client,tx,amount\ndeposit,1,1,0.12345678\n";
+//! let transactions: Vec<_> = CSVInputParserBuilder::new()
+//!   .parse_options(ParseOptions { precision: Precision::new(8).unwrap(), ..Default::default() })
+//!   .from_reader(data.as_bytes()).unwrap()
+//!   .collect::<Result<_, _>>().unwrap();
+//! assert_eq!(transactions[0].kind, TransactionKind::Deposit(FractionalAmount::new(12_345_678, Precision::new(8).unwrap())));
+//!
+//! // With an optional currency column, amounts in other currencies are parsed with
+//! // the precision of their currency. An empty currency is the default currency.
+//! let data = "type,client,tx,amount,currency\ndeposit,1,1,0.12345678,BTC\ndeposit,1,2,1.5,\n";
+//! let btc: Currency = "BTC".parse().unwrap();
+//! let transactions: Vec<_> = CSVInputParserBuilder::new()
+//!   .currencies(HashMap::from([(btc, Precision::new(8).unwrap())]))
+//!   .from_reader(data.as_bytes()).unwrap()
+//!   .collect::<Result<_, _>>().unwrap();
+//! assert_eq!(transactions[0].currency, btc);
+//! assert_eq!(transactions[0].kind, TransactionKind::Deposit(FractionalAmount::new(12_345_678, Precision::new(8).unwrap())));
+//! assert_eq!(transactions[1].currency, Currency::DEFAULT);
+//!
+//! // Locks and unlocks take a reason from an optional reason column.
+//! let data = "type,client,tx,amount,reason\nlock,1,100,,Suspected fraud\n";
+//! let transactions: Vec<_> = CSVInputParser::from_reader(data.as_bytes()).unwrap()
+//!   .collect::<Result<_, _>>().unwrap();
+//! assert_eq!(transactions[0].kind, TransactionKind::Lock("Suspected fraud".parse().unwrap()));
+//!
+//! // Transfers take the receiving client from an optional to column.
+//! let data = "type,client,tx,amount,to\ntransfer,1,101,0.5,2\n";
+//! let transactions: Vec<_> = CSVInputParser::from_reader(data.as_bytes()).unwrap()
+//!   .collect::<Result<_, _>>().unwrap();
+//! assert_eq!(transactions[0].kind, TransactionKind::Transfer { to: ClientId::from(2u16), amount: "0.5".try_into().unwrap() });
+//!
+//! // Transactions take a timestamp, in seconds since the Unix epoch, from an optional timestamp column.
+//! let data = "type,client,tx,amount,timestamp\ndeposit,1,1,1.5,1700000000\ndispute,1,1,,\n";
+//! let transactions: Vec<_> = CSVInputParser::from_reader(data.as_bytes()).unwrap()
+//!   .collect::<Result<_, _>>().unwrap();
+//! assert_eq!(transactions[0].timestamp, Some(1_700_000_000));
+//! assert_eq!(transactions[1].timestamp, None);
+//!
+//! // Errors carry the position and the contents of the record that could not be parsed.
+//! let data = "type,client,tx,amount\ndeposit,1,1,1.5\ndeposit,1,2,\n";
+//! let e = CSVInputParser::from_reader(data.as_bytes()).unwrap()
+//!   .collect::<Result<Vec<_>, _>>().unwrap_err();
+//! assert_eq!(e.position().unwrap().line(), 3);
+//! assert_eq!(e.record(), vec!["deposit", "1", "2", ""]);
+//! assert_eq!(e.to_string(), "Deposit must specify amount (line 3, byte 38, record 2)");
+//!
+//! // Compressed data is decompressed while reading.
+//! let data = "type,client,tx,amount\ndeposit,1,1,1.5\n";
+//! let compressed = zstd::encode_all(data.as_bytes(), 0).unwrap();
+//! let rdr = decompress(&compressed[..]).unwrap();
+//! let transactions: Vec<_> = CSVInputParser::from_reader(rdr).unwrap()
+//!   .collect::<Result<_, _>>().unwrap();
+//! assert_eq!(transactions.len(), 1);
+//! ```
 
-use serde::Deserialize;
-use thiserror::Error;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Cursor, ErrorKind, Read};
 
-use transaction_engine::{ClientId, TransactionId, FractionalAmount};
+use thiserror::Error;
 
-/// Transaction record as it appears in CSV inputs.
-///
-/// We use this struct in the initial stage of transaction processing
-/// where we are reading a transaction record from CSV data.
-///
-/// The data in this struct is further transformed into structs for the specific
-/// type of transaction that it describes before processing of the transaction itself
-/// takes place.
-#[derive(Deserialize)]
-pub(crate) struct TransactionCSVRecord<'a> {
-  #[serde(rename = "type")]
-  transaction_type: TransactionType,
-  #[serde(rename = "client")]
-  client_id: ClientId,
-  #[serde(rename = "tx")]
-  transaction_id: TransactionId,
-  /// The amount, where applicable, for the transaction.
-  ///
-  /// At the stage of reading the CSV record data we are not yet parsing
-  /// the amounts into our [transaction_engine::FractionalAmount] type.
-  ///
-  /// We borrow the string for this field from the CSV reader, as opposed
-  /// to using owned String, as the latter would cause additional allocation
-  /// for data that we only need for a short amount of time anyways.
-  amount: Option<&'a str>,
-}
+use transaction_engine::{Currency, ParseOptions, Precision, Transaction};
 
-/// The different transaction types that a [TransactionCSVRecord] entry can have.
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "snake_case")]
-enum TransactionType {
-  Deposit,
-  Withdrawal,
-  Dispute,
-  Resolve,
-  Chargeback,
-}
+use crate::transaction_record::{TransactionRecord, TransactionRecordError};
 
-/// Parses data from CSV file into corresponding [Transaction] variants.
+/// Parses data from CSV file into corresponding [Transaction]s.
 ///
 /// The purpose of our implementation of the CSV parsing is that we leverage
 /// the csv crate and serde for doing the deserialization for us as one would
 /// normally, while we also are able to further validate the data a little bit
-/// and convert the records into distinct [Transaction] variants, without getting
+/// and convert the records into distinct [transaction_engine::TransactionKind] variants, without getting
 /// nitty and gritty with serde.
 ///
 /// It is possible that deserializing the rows directly into transaction types
@@ -60,107 +114,294 @@ enum TransactionType {
 pub struct CSVInputParser<R: std::io::Read> {
   rdr: csv::Reader<R>,
   headers: csv::StringRecord,
+  /// The record that was read most recently. The buffers of the record are reused between records.
+  record: csv::StringRecord,
+  /// Options for parsing the amounts of transactions.
+  parse_options: ParseOptions,
+  /// The currencies other than the default currency, and the precision of their amounts.
+  currencies: HashMap<Currency, Precision>,
 }
 
 impl TryInto<CSVInputParser<std::fs::File>> for String {
   type Error = csv::Error;
   fn try_into (self) -> Result<CSVInputParser<std::fs::File>, Self::Error>
   {
-    let mut rdr = csv::ReaderBuilder::new()
-      .trim(csv::Trim::All)
-      .from_path(self)?;
+    CSVInputParserBuilder::new().from_path(self)
+  }
+}
+
+/// Builds a [CSVInputParser] with options for how the CSV data is read.
+///
+/// By default, whitespace around fields and headers is trimmed, fields are delimited by
+/// commas, all rows must have the same number of fields as the header row, amounts
+/// are parsed with the default [ParseOptions], and only the default currency is accepted.
+pub struct CSVInputParserBuilder {
+  builder: csv::ReaderBuilder,
+  parse_options: ParseOptions,
+  currencies: HashMap<Currency, Precision>,
+}
+
+impl CSVInputParserBuilder {
+  pub fn new () -> Self
+  {
+    let mut builder = csv::ReaderBuilder::new();
+    builder.trim(csv::Trim::All);
+    Self {
+      builder,
+      parse_options: Default::default(),
+      currencies: Default::default(),
+    }
+  }
+  /// The field delimiter to use. See [csv::ReaderBuilder::delimiter].
+  pub fn delimiter (&mut self, delimiter: u8) -> &mut Self
+  {
+    self.builder.delimiter(delimiter);
+    self
+  }
+  /// Whether whitespace is trimmed from fields and headers. See [csv::ReaderBuilder::trim].
+  pub fn trim (&mut self, trim: csv::Trim) -> &mut Self
+  {
+    self.builder.trim(trim);
+    self
+  }
+  /// Whether rows are allowed to have a different number of fields than the header row.
+  /// See [csv::ReaderBuilder::flexible].
+  ///
+  /// When enabled, the amount can be left out of rows that have no amount.
+  pub fn flexible (&mut self, flexible: bool) -> &mut Self
+  {
+    self.builder.flexible(flexible);
+    self
+  }
+  /// The options for parsing the amounts of transactions, such as their [transaction_engine::Precision].
+  /// See [transaction_engine::FractionalAmount::parse].
+  pub fn parse_options (&mut self, parse_options: ParseOptions) -> &mut Self
+  {
+    self.parse_options = parse_options;
+    self
+  }
+  /// The currencies other than the default currency that are accepted in the currency column,
+  /// along with the precision that amounts in each currency are parsed with. Amounts in the
+  /// default currency are parsed with the precision of the [ParseOptions].
+  pub fn currencies (&mut self, currencies: HashMap<Currency, Precision>) -> &mut Self
+  {
+    self.currencies = currencies;
+    self
+  }
+  /// Builds a parser that reads CSV data from the given reader.
+  ///
+  /// The header row is read immediately.
+  pub fn from_reader<R: std::io::Read> (&self, rdr: R) -> Result<CSVInputParser<R>, csv::Error>
+  {
+    CSVInputParser::with_reader(self.builder.from_reader(rdr), self.parse_options, self.currencies.clone())
+  }
+  /// Builds a parser that reads CSV data from the file at the given path.
+  ///
+  /// The header row is read immediately.
+  pub fn from_path<P: AsRef<std::path::Path>> (&self, path: P) -> Result<CSVInputParser<std::fs::File>, csv::Error>
+  {
+    CSVInputParser::with_reader(self.builder.from_path(path)?, self.parse_options, self.currencies.clone())
+  }
+}
+
+impl Default for CSVInputParserBuilder {
+  fn default () -> Self
+  {
+    Self::new()
+  }
+}
+
+impl<R: std::io::Read> CSVInputParser<R> {
+  /// Creates a parser that reads CSV data from the given reader, with the default
+  /// options of [CSVInputParserBuilder].
+  ///
+  /// The header row is read immediately.
+  pub fn from_reader (rdr: R) -> Result<Self, csv::Error>
+  {
+    CSVInputParserBuilder::new().from_reader(rdr)
+  }
+  fn with_reader (mut rdr: csv::Reader<R>, parse_options: ParseOptions, currencies: HashMap<Currency, Precision>) -> Result<Self, csv::Error>
+  {
     let headers = rdr.headers()?.clone();
-    Ok(CSVInputParser {
+    Ok(Self {
       rdr,
       headers,
+      record: csv::StringRecord::new(),
+      parse_options,
+      currencies,
     })
   }
-}
-
-impl<R: std::io::Read> CSVInputParser<R> {
+  /// The header row of the CSV data.
+  pub fn headers (&self) -> &csv::StringRecord
+  {
+    &self.headers
+  }
   /// Parses a raw CSV record into a transaction.
-  pub(crate) fn parse_raw_record(&self, raw_record: csv::StringRecord) -> Result<(ClientId, TransactionId, Transaction), CSVInputParserError> {
-    let record = raw_record.deserialize::<TransactionCSVRecord>(Some(&self.headers)).map_err(|e| CSVInputParserError::Csv(e))?;
-    let transaction = match record.transaction_type {
-      TransactionType::Deposit => {
-        let amount = record.amount
-          .ok_or(CSVInputParserError::DepositMustSpecifyAmount)
-          .and_then(|a| a.try_into().map_err(|e| CSVInputParserError::AmountParseError(e)))?;
-        Transaction::Deposit(amount)
-      },
-      TransactionType::Withdrawal => {
-        let amount = record.amount
-          .ok_or(CSVInputParserError::WithdrawalMustSpecifyAmount)
-          .and_then(|a| a.try_into().map_err(|e| CSVInputParserError::AmountParseError(e)))?;
-        Transaction::Withdrawal(amount)
-      },
-      TransactionType::Dispute => {
-        if record.amount.is_some() {
-          return Err(CSVInputParserError::DisputeCannotSpecifyAmount);
-        }
-        Transaction::Dispute
-      },
-      TransactionType::Resolve => {
-        if record.amount.is_some() {
-          return Err(CSVInputParserError::ResolveCannotSpecifyAmount);
-        }
-        Transaction::Resolve
-      },
-      TransactionType::Chargeback => {
-        if record.amount.is_some() {
-          return Err(CSVInputParserError::ChargebackCannotSpecifyAmount);
-        }
-        Transaction::Chargeback
-      },
-    };
-    Ok((record.client_id, record.transaction_id, transaction))
+  pub(crate) fn parse_raw_record(&self, raw_record: &csv::StringRecord) -> Result<Transaction, CSVInputParserErrorKind> {
+    let record = raw_record.deserialize::<TransactionRecord>(Some(&self.headers)).map_err(CSVInputParserErrorKind::Csv)?;
+    Ok(record.into_transaction(&self.parse_options, &self.currencies)?)
   }
 }
 
 impl<R: std::io::Read> Iterator for CSVInputParser<R> {
-  type Item = Result<(ClientId, TransactionId, Transaction), CSVInputParserError>;
+  type Item = Result<Transaction, CSVInputParserError>;
   fn next (&mut self) -> Option<Self::Item>
   {
-    let mut raw_record = csv::StringRecord::new();
-    let rec_read = self.rdr.read_record(&mut raw_record).map_err(|e| CSVInputParserError::Csv(e));
+    let rec_read = self.rdr.read_record(&mut self.record);
     match rec_read {
       Ok(did_read) => {
         if did_read {
-          Some(self.parse_raw_record(raw_record))
+          Some(self.parse_raw_record(&self.record).map_err(|kind| CSVInputParserError {
+            kind,
+            position: self.record.position().cloned(),
+            record: self.record.clone(),
+          }))
         } else {
           None
         }
       },
-      Err(e) => Some(Err(e)),
+      Err(e) => {
+        // XXX: When reading the record itself fails, the record buffer may still hold
+        //      a previous record, in which case we do not report it as the raw record.
+        let position = e.position().cloned();
+        let record = match (&position, self.record.position()) {
+          (Some(pos), Some(rec_pos)) if pos.byte() == rec_pos.byte() => self.record.clone(),
+          _ => csv::StringRecord::new(),
+        };
+        Some(Err(CSVInputParserError {
+          kind: CSVInputParserErrorKind::Csv(e),
+          position,
+          record,
+        }))
+      },
+    }
+  }
+}
+
+/// Magic number at the start of gzip data.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Magic number at the start of zstd data.
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compression of input data.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Compression {
+  None,
+  Gzip,
+  Zstd,
+}
+
+impl Compression {
+  /// Detects the compression of data from its first bytes.
+  pub fn detect (data: &[u8]) -> Self
+  {
+    if data.starts_with(GZIP_MAGIC) {
+      Self::Gzip
+    } else if data.starts_with(ZSTD_MAGIC) {
+      Self::Zstd
+    } else {
+      Self::None
+    }
+  }
+}
+
+/// Wraps a reader so that data that is compressed with gzip or zstd is decompressed while
+/// it is being read. Data that is not compressed is read as is. See [Compression::detect].
+///
+/// The returned reader is buffered, so the reader that is given does not need to be.
+///
+/// Gzip data with multiple members and zstd data with multiple frames,
+/// as created by concatenating compressed files, is read in full.
+pub fn decompress<'a, R: Read + 'a> (mut rdr: R) -> std::io::Result<Box<dyn BufRead + 'a>>
+{
+  // XXX: We read the first bytes ourselves rather than peeking into the buffer of a BufReader,
+  //      because a single read from a pipe can return fewer bytes than the magic numbers have.
+  let mut magic = [0u8; ZSTD_MAGIC.len()];
+  let mut len = 0;
+  while len < magic.len() {
+    match rdr.read(&mut magic[len..]) {
+      Ok(0) => break,
+      Ok(n) => len += n,
+      Err(e) if e.kind() == ErrorKind::Interrupted => {},
+      Err(e) => return Err(e),
     }
   }
+  let compression = Compression::detect(&magic[..len]);
+  let rdr = BufReader::new(Cursor::new(magic[..len].to_vec()).chain(rdr));
+  Ok(match compression {
+    Compression::None => Box::new(rdr),
+    Compression::Gzip => Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(rdr))),
+    Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::with_buffer(rdr)?)),
+  })
 }
 
-/// Transaction type and, in the case of deposits and withdrawals, the amount for the transaction.
+/// Error which can occur when parsing CSV raw records into [Transaction]s using [CSVInputParser],
+/// along with the position and the contents of the record for which the error occurred.
 #[derive(Debug)]
-pub enum Transaction {
-  Deposit(FractionalAmount),
-  Withdrawal(FractionalAmount),
-  Dispute,
-  Resolve,
-  Chargeback,
+pub struct CSVInputParserError {
+  kind: CSVInputParserErrorKind,
+  position: Option<csv::Position>,
+  record: csv::StringRecord,
+}
+
+impl CSVInputParserError {
+  /// The kind of error.
+  pub fn kind (&self) -> &CSVInputParserErrorKind
+  {
+    &self.kind
+  }
+  /// Consumes the error and returns the kind of error.
+  pub fn into_kind (self) -> CSVInputParserErrorKind
+  {
+    self.kind
+  }
+  /// The position in the CSV data of the record for which the error occurred, if known.
+  pub fn position (&self) -> Option<&csv::Position>
+  {
+    self.position.as_ref()
+  }
+  /// The raw record for which the error occurred, with surrounding whitespace of fields
+  /// trimmed as configured. The record is empty if it could not be read at all, for example
+  /// because it was not valid UTF-8.
+  pub fn record (&self) -> &csv::StringRecord
+  {
+    &self.record
+  }
+  /// Whether the error is an I/O error, after which no further records can be read.
+  ///
+  /// Other errors only concern the record that was being parsed, and reading
+  /// can continue with the next record.
+  pub fn is_io_error (&self) -> bool
+  {
+    matches!(&self.kind, CSVInputParserErrorKind::Csv(e) if matches!(e.kind(), csv::ErrorKind::Io(_)))
+  }
+}
+
+impl std::fmt::Display for CSVInputParserError {
+  fn fmt (&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+  {
+    match &self.position {
+      Some(pos) => write!(f, "{} (line {}, byte {}, record {})", self.kind, pos.line(), pos.byte(), pos.record()),
+      None => write!(f, "{}", self.kind),
+    }
+  }
+}
+
+impl std::error::Error for CSVInputParserError {
+  fn source (&self) -> Option<&(dyn std::error::Error + 'static)>
+  {
+    std::error::Error::source(&self.kind)
+  }
 }
 
-/// Errors which can occur when parsing CSV raw records into [Transaction]s using [CSVInputParser].
+/// The different kinds of errors which can occur when parsing CSV raw records
+/// into [Transaction]s using [CSVInputParser]. Errors in the contents of a record
+/// are the same as for other formats of input.
 #[derive(Error, Debug)]
-pub enum CSVInputParserError {
+pub enum CSVInputParserErrorKind {
   #[error("CSV error")]
   Csv(#[from] csv::Error),
-  #[error("Deposit must specify amount")]
-  DepositMustSpecifyAmount,
-  #[error("Failed to parse amount")]
-  AmountParseError(#[from] transaction_engine::FractionalAmountParseError),
-  #[error("Withdrawal must specify amount")]
-  WithdrawalMustSpecifyAmount,
-  #[error("Dispute cannot specify amount")]
-  DisputeCannotSpecifyAmount,
-  #[error("Resolve cannot specify amount")]
-  ResolveCannotSpecifyAmount,
-  #[error("Chargeback cannot specify amount")]
-  ChargebackCannotSpecifyAmount,
+  #[error(transparent)]
+  Record(#[from] TransactionRecordError),
 }
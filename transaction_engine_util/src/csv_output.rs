@@ -2,13 +2,24 @@
 
 use serde::Serialize;
 
+use transaction_engine::{Currency, FractionalAmount};
+
 /// Helper struct for serialization of account data to the CSV format
 /// specified in the spec.
+///
+/// The same struct is used for JSON Lines output with [crate::jsonl_output::JsonlWriter],
+/// so that the fields are the same in both formats.
+///
+/// There is one record per client and currency. The currency is left out entirely,
+/// rather than being left empty, when only the default currency is in use, so that
+/// the output is exactly as specified in the spec in that case.
 #[derive(Serialize, Debug)]
 pub struct AccountOutputCSVRecord {
   pub client: u16,
-  pub available: String,
-  pub held: String,
-  pub total: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub currency: Option<Currency>,
+  pub available: FractionalAmount,
+  pub held: FractionalAmount,
+  pub total: FractionalAmount,
   pub locked: bool,
 }
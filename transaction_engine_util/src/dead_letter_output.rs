@@ -0,0 +1,162 @@
+//! Output of rows of input that could not be parsed, for later repair.
+//!
+//! Each row of the dead-letter file consists of the line number of the row in the input,
+//! the reason that the row was rejected, and then the fields of the row itself, as they
+//! were read from the input. The header row of the dead-letter file is made up in the
+//! same way from the header row of the input. Once the rows have been repaired, the input
+//! can be recreated by removing the first two columns.
+//!
+//! The dead-letter file is a CSV file regardless of the format of the input. For JSON Lines
+//! input, the row consists of a single field with the line of input. For binary transaction
+//! logs, only the reason is written, which includes the byte offset of the record.
+//!
+//! ## Examples
+//!
+//! ```
+//! use transaction_engine_util::csv_input::CSVInputParser;
+//! use transaction_engine_util::dead_letter_output::DeadLetterWriter;
+//!
+//! let data = "type,client,tx,amount\ndeposit,1,1,1.5\ndeposit,1,2,\n";
+//! let mut csv_parser = CSVInputParser::from_reader(data.as_bytes()).unwrap();
+//! let mut dead_letters = DeadLetterWriter::from_writer(vec![], csv_parser.headers()).unwrap();
+//!
+//! for tx_result in &mut csv_parser {
+//!   if let Err(e) = tx_result {
+//!     dead_letters.write(&e).unwrap();
+//!   }
+//! }
+//!
+//! let data = String::from_utf8(dead_letters.into_inner().unwrap()).unwrap();
+//! assert_eq!(data, "\
+//! line,error,type,client,tx,amount
+//! 3,Deposit must specify amount,deposit,1,2,
+//! ");
+//! ```
+
+use std::error::Error;
+use std::io::Write;
+
+use crate::csv_input::CSVInputParserError;
+use crate::jsonl_input::JsonlInputParserError;
+use crate::binary_log::BinaryLogError;
+
+/// Errors of input parsers, for input that can be written to a dead-letter file.
+pub trait RejectedInput: Error {
+  /// The line number in the input of the row that was rejected, if known.
+  fn line (&self) -> Option<u64>;
+  /// The reason that the row was rejected, without the position in the input.
+  fn reason (&self) -> &(dyn Error + 'static);
+  /// The fields of the row that was rejected, as they were read from the input.
+  fn fields (&self) -> Vec<&str>;
+  /// Whether the error is an I/O error, after which no further rows can be read.
+  fn is_io_error (&self) -> bool;
+}
+
+impl RejectedInput for CSVInputParserError {
+  fn line (&self) -> Option<u64>
+  {
+    self.position().map(|pos| pos.line())
+  }
+  fn reason (&self) -> &(dyn Error + 'static)
+  {
+    self.kind()
+  }
+  fn fields (&self) -> Vec<&str>
+  {
+    self.record().iter().collect()
+  }
+  fn is_io_error (&self) -> bool
+  {
+    CSVInputParserError::is_io_error(self)
+  }
+}
+
+impl RejectedInput for JsonlInputParserError {
+  fn line (&self) -> Option<u64>
+  {
+    Some(JsonlInputParserError::line(self))
+  }
+  fn reason (&self) -> &(dyn Error + 'static)
+  {
+    self.kind()
+  }
+  fn fields (&self) -> Vec<&str>
+  {
+    vec![self.record()]
+  }
+  fn is_io_error (&self) -> bool
+  {
+    JsonlInputParserError::is_io_error(self)
+  }
+}
+
+impl RejectedInput for BinaryLogError {
+  /// Binary transaction logs do not have lines. The byte offset of
+  /// the record is part of the error itself instead.
+  fn line (&self) -> Option<u64>
+  {
+    None
+  }
+  fn reason (&self) -> &(dyn Error + 'static)
+  {
+    self
+  }
+  /// The records of binary transaction logs are not written to the dead-letter file.
+  fn fields (&self) -> Vec<&str>
+  {
+    vec![]
+  }
+  fn is_io_error (&self) -> bool
+  {
+    BinaryLogError::is_io_error(self)
+  }
+}
+
+/// Writes rows of CSV input that could not be parsed, along with the reason.
+pub struct DeadLetterWriter<W: Write> {
+  wtr: csv::Writer<W>,
+}
+
+impl<W: Write> DeadLetterWriter<W> {
+  /// Creates a dead-letter writer for input with the given header row,
+  /// and writes the header row of the dead-letter file.
+  ///
+  /// For input formats other than CSV, the header row is a single `record` field.
+  pub fn from_writer (writer: W, headers: &csv::StringRecord) -> Result<Self, csv::Error>
+  {
+    // XXX: Rows that could not be parsed may have a different number of fields than the header row.
+    let mut wtr = csv::WriterBuilder::new()
+      .flexible(true)
+      .from_writer(writer);
+    wtr.write_record(["line", "error"].into_iter().chain(headers.iter()))?;
+    Ok(Self {
+      wtr,
+    })
+  }
+  /// Writes the row that an error occurred for, with the line number of the row if known,
+  /// and the kind of error as the reason that the row was rejected.
+  ///
+  /// The reason that is written includes the sources of the error, if any.
+  pub fn write (&mut self, error: &dyn RejectedInput) -> Result<(), csv::Error>
+  {
+    let line = error.line().map(|line| line.to_string()).unwrap_or_default();
+    let mut reason = error.reason().to_string();
+    let mut source = error.reason().source();
+    while let Some(e) = source {
+      reason.push_str(": ");
+      reason.push_str(&e.to_string());
+      source = e.source();
+    }
+    self.wtr.write_record([line.as_str(), reason.as_str()].into_iter().chain(error.fields()))
+  }
+  /// Flushes the written rows.
+  pub fn flush (&mut self) -> Result<(), csv::Error>
+  {
+    Ok(self.wtr.flush()?)
+  }
+  /// Flushes the written rows and returns the underlying writer.
+  pub fn into_inner (self) -> Result<W, csv::Error>
+  {
+    self.wtr.into_inner().map_err(|e| std::io::Error::new(e.error().kind(), e.error().to_string()).into())
+  }
+}
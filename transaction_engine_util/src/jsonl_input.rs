@@ -0,0 +1,237 @@
+//! Data structures and logic used for input of JSON Lines data.
+//!
+//! Each line of the input is a JSON object with the same fields as the columns of CSV input,
+//! and results in the same [Transaction] as the corresponding row of CSV input would.
+//! The amount can be given either as a JSON number or as a string. Either way, the decimal
+//! representation of the amount is parsed exactly, without going through floating point.
+//! Empty lines are ignored.
+//!
+//! ## Examples
+//!
+//! ```
+//! use std::collections::HashMap;
+//! use transaction_engine::{TransactionKind, Currency, FractionalAmount, Precision};
+//! use transaction_engine_util::jsonl_input::JsonlInputParser;
+//!
+//! let data = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1.5}
+//! {"type": "withdrawal", "client": 1, "tx": 2, "amount": "0.25"}
+//!
+//! {"type": "dispute", "client": 1, "tx": 1}
+//! "#;
+//! let transactions: Vec<_> = JsonlInputParser::from_reader(data.as_bytes())
+//!   .collect::<Result<_, _>>().unwrap();
+//! assert_eq!(transactions.len(), 3);
+//! assert_eq!(transactions[1].kind, TransactionKind::Withdrawal("0.25".try_into().unwrap()));
+//! assert_eq!(transactions[2].kind, TransactionKind::Dispute);
+//!
+//! let data = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1.5, "currency": "BTC"}"#;
+//! let btc: Currency = "BTC".parse().unwrap();
+//! let transaction = JsonlInputParser::from_reader(data.as_bytes())
+//!   .currencies(HashMap::from([(btc, Precision::new(8).unwrap())]))
+//!   .next().unwrap().unwrap();
+//! assert_eq!(transaction.currency, btc);
+//! assert_eq!(transaction.kind, TransactionKind::Deposit(FractionalAmount::new(150_000_000, Precision::new(8).unwrap())));
+//!
+//! let data = r#"{"type": "deposit", "client": 1, "tx": 1}"#;
+//! let e = JsonlInputParser::from_reader(data.as_bytes()).next().unwrap().unwrap_err();
+//! assert_eq!(e.to_string(), "Deposit must specify amount (line 1)");
+//! ```
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use thiserror::Error;
+
+use transaction_engine::{ClientId, TransactionId, Currency, ParseOptions, Precision, Transaction};
+
+use crate::transaction_record::{TransactionRecord, TransactionRecordError, TransactionType};
+
+/// Transaction record as it appears in JSON Lines inputs.
+///
+/// This differs from the [TransactionRecord] of other formats of input only in how the amount
+/// is read, and we turn it into a [TransactionRecord] before transforming it into a [Transaction].
+#[derive(Deserialize)]
+struct TransactionJSONRecord<'a> {
+  #[serde(rename = "type")]
+  transaction_type: TransactionType,
+  #[serde(rename = "client")]
+  client_id: ClientId,
+  #[serde(rename = "tx")]
+  transaction_id: TransactionId,
+  /// The amount, where applicable, for the transaction.
+  ///
+  /// We keep the JSON text of the amount, so that numbers can be parsed
+  /// into our [transaction_engine::FractionalAmount] type without loss of precision.
+  #[serde(borrow, default)]
+  amount: Option<&'a RawValue>,
+  /// The currency of the amount, for deposits and withdrawals.
+  #[serde(borrow, default)]
+  currency: Option<Cow<'a, str>>,
+  /// The reason, for locks and unlocks.
+  #[serde(borrow, default)]
+  reason: Option<Cow<'a, str>>,
+  /// The receiving client, for transfers.
+  #[serde(default)]
+  to: Option<ClientId>,
+  /// The timestamp, in seconds since the Unix epoch.
+  #[serde(default)]
+  timestamp: Option<u64>,
+}
+
+/// Parses JSON Lines data into corresponding [Transaction]s.
+pub struct JsonlInputParser<R: BufRead> {
+  rdr: R,
+  /// Number of the line that was read most recently.
+  line_number: u64,
+  /// The line that was read most recently. The buffer is reused between lines.
+  line: String,
+  /// Options for parsing the amounts of transactions.
+  parse_options: ParseOptions,
+  /// The currencies other than the default currency, and the precision of their amounts.
+  currencies: HashMap<Currency, Precision>,
+}
+
+impl<R: BufRead> JsonlInputParser<R> {
+  /// Creates a parser that reads JSON Lines data from the given reader,
+  /// and parses amounts with the default [ParseOptions].
+  pub fn from_reader (rdr: R) -> Self
+  {
+    Self::with_parse_options(rdr, Default::default())
+  }
+  /// Creates a parser that reads JSON Lines data from the given reader,
+  /// and parses amounts with the given options. See [transaction_engine::FractionalAmount::parse].
+  pub fn with_parse_options (rdr: R, parse_options: ParseOptions) -> Self
+  {
+    Self {
+      rdr,
+      line_number: 0,
+      line: String::new(),
+      parse_options,
+      currencies: Default::default(),
+    }
+  }
+  /// Sets the currencies other than the default currency that are accepted, along with
+  /// the precision that amounts in each currency are parsed with. Amounts in the default
+  /// currency are parsed with the precision of the [ParseOptions].
+  pub fn currencies (mut self, currencies: HashMap<Currency, Precision>) -> Self
+  {
+    self.currencies = currencies;
+    self
+  }
+  /// Parses a line into a transaction.
+  fn parse_line (&self, line: &str) -> Result<Transaction, JsonlInputParserErrorKind>
+  {
+    let record: TransactionJSONRecord = serde_json::from_str(line)?;
+    let record = TransactionRecord {
+      transaction_type: record.transaction_type,
+      client_id: record.client_id,
+      transaction_id: record.transaction_id,
+      amount: record.amount.map(amount_text).transpose()?,
+      currency: record.currency,
+      reason: record.reason,
+      to: record.to,
+      timestamp: record.timestamp,
+    };
+    Ok(record.into_transaction(&self.parse_options, &self.currencies)?)
+  }
+}
+
+/// The text of an amount, which is either a JSON number or a JSON string.
+fn amount_text (amount: &RawValue) -> Result<Cow<'_, str>, serde_json::Error>
+{
+  let text = amount.get();
+  if text.starts_with('"') {
+    Ok(Cow::Owned(serde_json::from_str(text)?))
+  } else {
+    Ok(Cow::Borrowed(text))
+  }
+}
+
+impl<R: BufRead> Iterator for JsonlInputParser<R> {
+  type Item = Result<Transaction, JsonlInputParserError>;
+  fn next (&mut self) -> Option<Self::Item>
+  {
+    loop {
+      self.line.clear();
+      self.line_number += 1;
+      let res = match self.rdr.read_line(&mut self.line) {
+        Ok(0) => return None,
+        Ok(_) if self.line.trim().is_empty() => continue,
+        Ok(_) => self.parse_line(&self.line),
+        Err(e) => Err(JsonlInputParserErrorKind::Io(e)),
+      };
+      return Some(res.map_err(|kind| JsonlInputParserError {
+        kind,
+        line: self.line_number,
+        record: self.line.trim_end_matches(['\r', '\n']).to_string(),
+      }));
+    }
+  }
+}
+
+/// Error which can occur when parsing JSON Lines data into [Transaction]s using [JsonlInputParser],
+/// along with the line number and the contents of the line for which the error occurred.
+#[derive(Debug)]
+pub struct JsonlInputParserError {
+  kind: JsonlInputParserErrorKind,
+  line: u64,
+  record: String,
+}
+
+impl JsonlInputParserError {
+  /// The kind of error.
+  pub fn kind (&self) -> &JsonlInputParserErrorKind
+  {
+    &self.kind
+  }
+  /// Consumes the error and returns the kind of error.
+  pub fn into_kind (self) -> JsonlInputParserErrorKind
+  {
+    self.kind
+  }
+  /// The number of the line for which the error occurred, starting at 1.
+  pub fn line (&self) -> u64
+  {
+    self.line
+  }
+  /// The line for which the error occurred, without the line terminator.
+  pub fn record (&self) -> &str
+  {
+    &self.record
+  }
+  /// Whether the error is an I/O error, after which no further lines can be read.
+  pub fn is_io_error (&self) -> bool
+  {
+    matches!(self.kind, JsonlInputParserErrorKind::Io(_))
+  }
+}
+
+impl std::fmt::Display for JsonlInputParserError {
+  fn fmt (&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+  {
+    write!(f, "{} (line {})", self.kind, self.line)
+  }
+}
+
+impl std::error::Error for JsonlInputParserError {
+  fn source (&self) -> Option<&(dyn std::error::Error + 'static)>
+  {
+    std::error::Error::source(&self.kind)
+  }
+}
+
+/// The different kinds of errors which can occur when parsing JSON Lines data
+/// into [Transaction]s using [JsonlInputParser]. Errors in the contents of a line
+/// are the same as for other formats of input.
+#[derive(Error, Debug)]
+pub enum JsonlInputParserErrorKind {
+  #[error("I/O error")]
+  Io(#[from] std::io::Error),
+  #[error("JSON error")]
+  Json(#[from] serde_json::Error),
+  #[error(transparent)]
+  Record(#[from] TransactionRecordError),
+}
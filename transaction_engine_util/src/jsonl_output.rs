@@ -0,0 +1,59 @@
+//! Helper for outputting JSON Lines data.
+//!
+//! ## Examples
+//!
+//! ```
+//! use transaction_engine_util::csv_output::AccountOutputCSVRecord;
+//! use transaction_engine_util::jsonl_output::JsonlWriter;
+//!
+//! let mut wtr = JsonlWriter::from_writer(vec![]);
+//! wtr.serialize(&AccountOutputCSVRecord {
+//!   client: 1,
+//!   currency: None,
+//!   available: "1.5".try_into().unwrap(),
+//!   held: "0".try_into().unwrap(),
+//!   total: "1.5".try_into().unwrap(),
+//!   locked: false,
+//! }).unwrap();
+//!
+//! let data = String::from_utf8(wtr.into_inner()).unwrap();
+//! assert_eq!(data, r#"{"client":1,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false}
+//! "#);
+//! ```
+
+use std::io::Write;
+
+use serde::Serialize;
+
+/// Writes records as JSON Lines, with one JSON object per line.
+///
+/// Amounts in records such as [crate::csv_output::AccountOutputCSVRecord] are serialized
+/// as strings, so that consumers of the JSON data do not lose precision by reading amounts
+/// as floating point numbers.
+pub struct JsonlWriter<W: Write> {
+  wtr: W,
+}
+
+impl<W: Write> JsonlWriter<W> {
+  pub fn from_writer (wtr: W) -> Self
+  {
+    Self {
+      wtr,
+    }
+  }
+  /// Serializes a record as a single line of JSON.
+  pub fn serialize<T: Serialize> (&mut self, record: &T) -> Result<(), serde_json::Error>
+  {
+    serde_json::to_writer(&mut self.wtr, record)?;
+    self.wtr.write_all(b"\n").map_err(serde_json::Error::io)
+  }
+  pub fn flush (&mut self) -> std::io::Result<()>
+  {
+    self.wtr.flush()
+  }
+  /// Returns the underlying writer.
+  pub fn into_inner (self) -> W
+  {
+    self.wtr
+  }
+}
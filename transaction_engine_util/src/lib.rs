@@ -1,2 +1,8 @@
+pub mod binary_log;
 pub mod csv_input;
-pub mod csv_output;
\ No newline at end of file
+pub mod csv_output;
+pub mod dead_letter_output;
+pub mod jsonl_input;
+pub mod jsonl_output;
+pub mod outcome_output;
+pub mod transaction_record;
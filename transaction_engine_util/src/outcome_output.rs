@@ -0,0 +1,166 @@
+//! Output of the outcomes of individual transactions, as CSV data.
+//!
+//! The outcome log contains one row per transaction that was processed, with the client,
+//! the transaction ID, the type of transaction, whether it was applied or rejected,
+//! an error code for rejected transactions, the currency, and the balance of the account
+//! of the client in that currency after the transaction. For rejected transactions, the
+//! balance is that of the unchanged account, and it is left empty if the client has no
+//! balance in the currency.
+//!
+//! For disputes, resolves and chargebacks that were applied, the currency is that of the
+//! referenced transaction. The currency is left empty for the default currency.
+//!
+//! For transfers, the row is that of the sending client. The balance of the receiving
+//! client is not part of the outcome log.
+//!
+//! For locks and unlocks, the last column holds the reason that was given, so that the
+//! outcome log doubles as an audit trail of actions taken by support staff.
+//!
+//! ## Examples
+//!
+//! ```
+//! use transaction_engine::{TransactionProcessor, Transaction, TransactionKind, ClientId, TransactionId};
+//! use transaction_engine_util::outcome_output::OutcomeWriter;
+//!
+//! let mut transaction_processor = TransactionProcessor::new();
+//! let mut outcomes = OutcomeWriter::from_writer(vec![]);
+//!
+//! let client_a = ClientId::from(1u16);
+//! let deposit = Transaction::new(client_a, TransactionId::from(1u32), TransactionKind::Deposit("1.5".try_into().unwrap()));
+//! let withdrawal = Transaction::new(client_a, TransactionId::from(2u32), TransactionKind::Withdrawal("2".try_into().unwrap()));
+//! let lock = Transaction::new(client_a, TransactionId::from(3u32), TransactionKind::Lock("Suspected fraud".parse().unwrap()));
+//!
+//! for transaction in [deposit, withdrawal, lock] {
+//!   let res = transaction_processor.process(transaction.clone());
+//!   outcomes.write(&transaction, &res, transaction_processor.account(transaction.client));
+//! }
+//!
+//! let data = String::from_utf8(outcomes.finish().unwrap()).unwrap();
+//! assert_eq!(data, "\
+//! client,tx,type,status,error,currency,available,held,total,locked,reason
+//! 1,1,deposit,applied,,,1.5000,0.0000,1.5000,false,
+//! 1,2,withdrawal,rejected,insufficient_funds,,1.5000,0.0000,1.5000,false,
+//! 1,3,lock,applied,,,1.5000,0.0000,1.5000,true,Suspected fraud
+//! ");
+//! ```
+
+use std::io::Write;
+
+use serde::Serialize;
+
+use transaction_engine::{Account, Currency, FractionalAmount, Transaction, TransactionKind, Outcome, TransactionError};
+
+/// Helper struct for serialization of the outcome of a transaction to CSV.
+#[derive(Serialize, Debug)]
+pub struct OutcomeCSVRecord<'a> {
+  pub client: u16,
+  pub tx: u32,
+  #[serde(rename = "type")]
+  pub transaction_type: &'static str,
+  pub status: OutcomeStatus,
+  /// Error code, for rejected transactions. See [TransactionError::code].
+  pub error: Option<&'static str>,
+  pub currency: Currency,
+  pub available: Option<FractionalAmount>,
+  pub held: Option<FractionalAmount>,
+  pub total: Option<FractionalAmount>,
+  pub locked: Option<bool>,
+  /// The reason, for locks and unlocks.
+  pub reason: Option<&'a str>,
+}
+
+/// Whether a transaction was applied or rejected.
+#[derive(Serialize, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutcomeStatus {
+  Applied,
+  Rejected,
+}
+
+impl<'a> OutcomeCSVRecord<'a> {
+  /// Creates the record for a transaction, the result of processing it,
+  /// and the account of the client after the transaction, if any.
+  pub fn new (transaction: &'a Transaction, res: &Result<Outcome, TransactionError>, account: Option<&Account>) -> Self
+  {
+    let (status, error, currency) = match res {
+      Ok(outcome) => (OutcomeStatus::Applied, None, outcome.currency),
+      Err(e) => (OutcomeStatus::Rejected, Some(e.code()), transaction.currency),
+    };
+    let balance = account.and_then(|acc| acc.balance(currency));
+    let reason = match &transaction.kind {
+      TransactionKind::Lock(reason) | TransactionKind::Unlock(reason) => Some(reason.as_str()),
+      _ => None,
+    };
+    Self {
+      client: transaction.client.into(),
+      tx: transaction.tx.into(),
+      transaction_type: transaction_type(&transaction.kind),
+      status,
+      error,
+      currency,
+      available: balance.map(|b| b.get_available()),
+      held: balance.map(|b| b.get_held()),
+      total: balance.map(|b| b.get_total()),
+      locked: account.map(|acc| acc.is_frozen()),
+      reason,
+    }
+  }
+}
+
+/// Writes the outcomes of transactions as CSV data.
+///
+/// Outcomes are usually written from a callback that cannot return errors, such as the callback
+/// of a [transaction_engine::sharded::ShardedProcessor]. Therefore, [OutcomeWriter::write] does
+/// not return errors. Instead, the first error that occurs is kept, no further outcomes are
+/// written after it, and the error is returned by [OutcomeWriter::finish].
+pub struct OutcomeWriter<W: Write> {
+  wtr: csv::Writer<W>,
+  error: Option<csv::Error>,
+}
+
+impl<W: Write> OutcomeWriter<W> {
+  pub fn from_writer (writer: W) -> Self
+  {
+    Self {
+      wtr: csv::Writer::from_writer(writer),
+      error: None,
+    }
+  }
+  /// Writes the outcome of a transaction. See [OutcomeCSVRecord::new].
+  pub fn write (&mut self, transaction: &Transaction, res: &Result<Outcome, TransactionError>, account: Option<&Account>)
+  {
+    if self.error.is_none() {
+      if let Err(e) = self.wtr.serialize(OutcomeCSVRecord::new(transaction, res, account)) {
+        self.error = Some(e);
+      }
+    }
+  }
+  /// Flushes the written outcomes and returns the underlying writer,
+  /// or the first error that occurred while writing outcomes.
+  pub fn finish (self) -> Result<W, csv::Error>
+  {
+    if let Some(e) = self.error {
+      return Err(e);
+    }
+    let mut wtr = self.wtr;
+    wtr.flush()?;
+    // XXX: Getting the inner writer only fails if flushing fails, and we have just flushed.
+    //      Should it still fail, the I/O error is recreated since it cannot be moved out.
+    wtr.into_inner().map_err(|e| std::io::Error::new(e.error().kind(), e.error().to_string()).into())
+  }
+}
+
+/// Name of the type of transaction, as it appears in the `type` column of CSV data.
+fn transaction_type (kind: &TransactionKind) -> &'static str
+{
+  match kind {
+    TransactionKind::Deposit(_) => "deposit",
+    TransactionKind::Withdrawal(_) => "withdrawal",
+    TransactionKind::Transfer { .. } => "transfer",
+    TransactionKind::Dispute => "dispute",
+    TransactionKind::Resolve => "resolve",
+    TransactionKind::Chargeback => "chargeback",
+    TransactionKind::Lock(_) => "lock",
+    TransactionKind::Unlock(_) => "unlock",
+  }
+}
@@ -0,0 +1,224 @@
+//! Conversion of transaction records, as read from textual inputs, into [Transaction]s.
+//!
+//! CSV and JSON Lines inputs have the same fields, and the same rules for which fields
+//! each type of transaction must and must not specify. The input parsers read their records
+//! into a common transaction record, which is then checked and converted into a [Transaction] here,
+//! so that both formats of input accept the same transactions and reject the rest with the same errors.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use transaction_engine::{ClientId, TransactionId, Currency, FractionalAmount, ParseOptions, Precision, Reason, Transaction, TransactionKind};
+
+/// Transaction record as it appears in textual inputs.
+///
+/// We use this struct in the initial stage of transaction processing
+/// where we are reading a transaction record from the input.
+///
+/// The data in this struct is further transformed into a [Transaction]
+/// before processing of the transaction itself takes place.
+#[derive(Deserialize)]
+pub(crate) struct TransactionRecord<'a> {
+  #[serde(rename = "type")]
+  pub(crate) transaction_type: TransactionType,
+  #[serde(rename = "client")]
+  pub(crate) client_id: ClientId,
+  #[serde(rename = "tx")]
+  pub(crate) transaction_id: TransactionId,
+  /// The amount, where applicable, for the transaction.
+  ///
+  /// At the stage of reading the record we are not yet parsing
+  /// the amounts into our [transaction_engine::FractionalAmount] type.
+  ///
+  /// We borrow the string for this field from the reader where possible, as opposed
+  /// to using owned String, as the latter would cause additional allocation
+  /// for data that we only need for a short amount of time anyways.
+  ///
+  /// Even though [transaction_engine::FractionalAmount] implements Deserialize, we parse
+  /// the amount separately, so that errors in the amount are reported as
+  /// [TransactionRecordError::AmountParseError] rather than as generic errors of the input format.
+  #[serde(borrow)]
+  pub(crate) amount: Option<Cow<'a, str>>,
+  /// The currency of the amount, for deposits, withdrawals and transfers. The currency
+  /// field is optional, and an empty currency means the default currency.
+  #[serde(borrow, default)]
+  pub(crate) currency: Option<Cow<'a, str>>,
+  /// The reason, for locks and unlocks. The reason field is optional.
+  #[serde(borrow, default)]
+  pub(crate) reason: Option<Cow<'a, str>>,
+  /// The receiving client, for transfers. The to field is optional.
+  #[serde(default)]
+  pub(crate) to: Option<ClientId>,
+  /// The timestamp, in seconds since the Unix epoch. The timestamp field is optional.
+  #[serde(default)]
+  pub(crate) timestamp: Option<u64>,
+}
+
+/// The different transaction types that a [TransactionRecord] entry can have.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TransactionType {
+  Deposit,
+  Withdrawal,
+  Transfer,
+  Dispute,
+  Resolve,
+  Chargeback,
+  Lock,
+  Unlock,
+}
+
+impl TransactionRecord<'_> {
+  /// Converts the record into a transaction, parsing amounts in the default currency with the given options,
+  /// and amounts in the given other currencies with the precision of their currency.
+  pub(crate) fn into_transaction (self, parse_options: &ParseOptions, currencies: &HashMap<Currency, Precision>) -> Result<Transaction, TransactionRecordError>
+  {
+    let currency = match &self.currency {
+      Some(c) => c.parse().map_err(TransactionRecordError::CurrencyParseError)?,
+      None => Currency::DEFAULT,
+    };
+    let parse_amount = |a: Cow<str>| {
+      let parse_options = currency_parse_options(parse_options, currencies, currency)
+        .ok_or(TransactionRecordError::UnknownCurrency)?;
+      FractionalAmount::parse(&a, &parse_options).map_err(TransactionRecordError::AmountParseError)
+    };
+    if self.reason.is_some() && !matches!(self.transaction_type, TransactionType::Lock | TransactionType::Unlock) {
+      return Err(TransactionRecordError::OnlyLockAndUnlockCanSpecifyReason);
+    } else if self.to.is_some() && !matches!(self.transaction_type, TransactionType::Transfer) {
+      return Err(TransactionRecordError::OnlyTransferCanSpecifyRecipient);
+    }
+    let kind = match self.transaction_type {
+      TransactionType::Deposit => {
+        let amount = self.amount
+          .ok_or(TransactionRecordError::DepositMustSpecifyAmount)
+          .and_then(parse_amount)?;
+        TransactionKind::Deposit(amount)
+      },
+      TransactionType::Withdrawal => {
+        let amount = self.amount
+          .ok_or(TransactionRecordError::WithdrawalMustSpecifyAmount)
+          .and_then(parse_amount)?;
+        TransactionKind::Withdrawal(amount)
+      },
+      TransactionType::Transfer => {
+        let to = self.to.ok_or(TransactionRecordError::TransferMustSpecifyRecipient)?;
+        let amount = self.amount
+          .ok_or(TransactionRecordError::TransferMustSpecifyAmount)
+          .and_then(parse_amount)?;
+        TransactionKind::Transfer { to, amount }
+      },
+      TransactionType::Dispute => {
+        if self.amount.is_some() {
+          return Err(TransactionRecordError::DisputeCannotSpecifyAmount);
+        } else if self.currency.is_some() {
+          return Err(TransactionRecordError::DisputeCannotSpecifyCurrency);
+        }
+        TransactionKind::Dispute
+      },
+      TransactionType::Resolve => {
+        if self.amount.is_some() {
+          return Err(TransactionRecordError::ResolveCannotSpecifyAmount);
+        } else if self.currency.is_some() {
+          return Err(TransactionRecordError::ResolveCannotSpecifyCurrency);
+        }
+        TransactionKind::Resolve
+      },
+      TransactionType::Chargeback => {
+        if self.amount.is_some() {
+          return Err(TransactionRecordError::ChargebackCannotSpecifyAmount);
+        } else if self.currency.is_some() {
+          return Err(TransactionRecordError::ChargebackCannotSpecifyCurrency);
+        }
+        TransactionKind::Chargeback
+      },
+      TransactionType::Lock => {
+        if self.amount.is_some() {
+          return Err(TransactionRecordError::LockCannotSpecifyAmount);
+        } else if self.currency.is_some() {
+          return Err(TransactionRecordError::LockCannotSpecifyCurrency);
+        }
+        let reason = self.reason.as_deref()
+          .ok_or(TransactionRecordError::LockMustSpecifyReason)
+          .and_then(|r| r.parse::<Reason>().map_err(TransactionRecordError::ReasonParseError))?;
+        TransactionKind::Lock(reason)
+      },
+      TransactionType::Unlock => {
+        if self.amount.is_some() {
+          return Err(TransactionRecordError::UnlockCannotSpecifyAmount);
+        } else if self.currency.is_some() {
+          return Err(TransactionRecordError::UnlockCannotSpecifyCurrency);
+        }
+        let reason = self.reason.as_deref()
+          .ok_or(TransactionRecordError::UnlockMustSpecifyReason)
+          .and_then(|r| r.parse::<Reason>().map_err(TransactionRecordError::ReasonParseError))?;
+        TransactionKind::Unlock(reason)
+      },
+    };
+    Ok(Transaction { timestamp: self.timestamp, ..Transaction::with_currency(self.client_id, self.transaction_id, currency, kind) })
+  }
+}
+
+/// The options for parsing an amount in the given currency, which are the given options with
+/// the precision of the currency. Returns `None` if the currency is not one of the given currencies
+/// nor the default currency.
+pub(crate) fn currency_parse_options (parse_options: &ParseOptions, currencies: &HashMap<Currency, Precision>, currency: Currency) -> Option<ParseOptions>
+{
+  let precision = match currency {
+    Currency::DEFAULT => parse_options.precision,
+    currency => *currencies.get(&currency)?,
+  };
+  Some(ParseOptions { precision, ..*parse_options })
+}
+
+/// The different kinds of errors which can occur when converting transaction records
+/// into [Transaction]s, regardless of the format of the input.
+#[derive(Error, Debug)]
+pub enum TransactionRecordError {
+  #[error("Deposit must specify amount")]
+  DepositMustSpecifyAmount,
+  #[error("Failed to parse amount")]
+  AmountParseError(#[from] transaction_engine::FractionalAmountParseError),
+  #[error("Withdrawal must specify amount")]
+  WithdrawalMustSpecifyAmount,
+  #[error("Dispute cannot specify amount")]
+  DisputeCannotSpecifyAmount,
+  #[error("Resolve cannot specify amount")]
+  ResolveCannotSpecifyAmount,
+  #[error("Chargeback cannot specify amount")]
+  ChargebackCannotSpecifyAmount,
+  #[error("Failed to parse currency")]
+  CurrencyParseError(#[from] transaction_engine::ParseCurrencyError),
+  #[error("Unknown currency")]
+  UnknownCurrency,
+  #[error("Dispute cannot specify currency")]
+  DisputeCannotSpecifyCurrency,
+  #[error("Resolve cannot specify currency")]
+  ResolveCannotSpecifyCurrency,
+  #[error("Chargeback cannot specify currency")]
+  ChargebackCannotSpecifyCurrency,
+  #[error("Lock must specify reason")]
+  LockMustSpecifyReason,
+  #[error("Unlock must specify reason")]
+  UnlockMustSpecifyReason,
+  #[error("Failed to parse reason")]
+  ReasonParseError(#[from] transaction_engine::ParseReasonError),
+  #[error("Only lock and unlock can specify reason")]
+  OnlyLockAndUnlockCanSpecifyReason,
+  #[error("Lock cannot specify amount")]
+  LockCannotSpecifyAmount,
+  #[error("Unlock cannot specify amount")]
+  UnlockCannotSpecifyAmount,
+  #[error("Lock cannot specify currency")]
+  LockCannotSpecifyCurrency,
+  #[error("Unlock cannot specify currency")]
+  UnlockCannotSpecifyCurrency,
+  #[error("Transfer must specify amount")]
+  TransferMustSpecifyAmount,
+  #[error("Transfer must specify receiving client")]
+  TransferMustSpecifyRecipient,
+  #[error("Only transfer can specify receiving client")]
+  OnlyTransferCanSpecifyRecipient,
+}